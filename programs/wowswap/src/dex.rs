@@ -1,9 +1,13 @@
 use anchor_lang::prelude::*;
-use serum_dex::{instruction, matching, state::MarketState};
-use solana_program::{entrypoint::ProgramResult, program::invoke_signed};
+use serum_dex::{critbit::SlabView, instruction, matching, state::MarketState};
+use solana_program::program::invoke_signed;
 use std::num::NonZeroU64;
 
-use super::{math::TokenAmount, token};
+use super::{
+    error::{WowswapError, WowswapResult, WowswapResultEmpty},
+    math::{TokenAmount, Wad},
+    token,
+};
 
 declare_id!("9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin");
 
@@ -58,7 +62,7 @@ pub fn init_open_orders<'info>(
     owner: AccountInfo<'info>,
     market: AccountInfo<'info>,
     seeds: &[&[&[u8]]],
-) -> ProgramResult {
+) -> WowswapResultEmpty {
     invoke_signed(
         &instruction_patched::init_open_orders(
             dex_program.key,
@@ -66,10 +70,13 @@ pub fn init_open_orders<'info>(
             owner.key,
             market.key,
             None,
-        )?,
+        )
+        .map_err(|err| dex_error("init_open_orders", err))?,
         &[open_orders, owner, market],
         seeds,
     )
+    .map_err(|err| dex_error("init_open_orders", err))?;
+    Ok(())
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -82,7 +89,7 @@ pub fn buy<'info>(
     max_coin_qty: DexNonZeroTokenQty,
     max_native_pc_qty_including_fees: DexNonZeroTokenAmount,
     seeds: &[&[&[u8]]],
-) -> ProgramResult {
+) -> WowswapResultEmpty {
     make_swap(
         matching::Side::Bid,
         dex,
@@ -106,7 +113,7 @@ pub fn sell<'info>(
     max_coin_qty: DexNonZeroTokenQty,
     max_native_pc_qty_including_fees: DexNonZeroTokenAmount,
     seeds: &[&[&[u8]]],
-) -> ProgramResult {
+) -> WowswapResultEmpty {
     make_swap(
         matching::Side::Ask,
         dex,
@@ -131,7 +138,7 @@ pub fn make_swap<'info>(
     max_coin_qty: DexNonZeroTokenQty,
     max_native_pc_qty_including_fees: DexNonZeroTokenAmount,
     seeds: &[&[&[u8]]],
-) -> ProgramResult {
+) -> WowswapResultEmpty {
     let order_payer = match side {
         matching::Side::Bid => swap_pc_vault.clone(),
         matching::Side::Ask => swap_coin_vault.clone(),
@@ -161,7 +168,8 @@ pub fn make_swap<'info>(
             instruction::SelfTradeBehavior::AbortTransaction,
             u16::MAX, // limit
             max_native_pc_qty_including_fees.into_inner(),
-        )?,
+        )
+        .map_err(|err| dex_error("new_order", err))?,
         &[
             dex.market.clone(),
             dex.open_orders.clone(),
@@ -177,8 +185,19 @@ pub fn make_swap<'info>(
             // srm_account_referral
         ],
         seeds,
-    )?;
+    )
+    .map_err(|err| dex_error("new_order", err))?;
+
+    settle_funds(dex, swap_coin_vault, swap_pc_vault, swap_signer, seeds)
+}
 
+pub fn settle_funds<'info>(
+    dex: &DexAccounts<'info>,
+    swap_coin_vault: AccountInfo<'info>,
+    swap_pc_vault: AccountInfo<'info>,
+    swap_signer: AccountInfo<'info>,
+    seeds: &[&[&[u8]]],
+) -> WowswapResultEmpty {
     invoke_signed(
         &instruction::settle_funds(
             dex.dex_program.key,
@@ -192,21 +211,153 @@ pub fn make_swap<'info>(
             swap_pc_vault.key,
             None, // referrer_pc_wallet
             dex.vault_signer.key,
-        )?,
+        )
+        .map_err(|err| dex_error("settle_funds", err))?,
         &[
             dex.market.clone(),
             dex.open_orders.clone(),
             swap_signer,
             dex.coin_vault.clone(),
             dex.pc_vault.clone(),
-            swap_coin_vault.clone(),
-            swap_pc_vault.clone(),
+            swap_coin_vault,
+            swap_pc_vault,
             dex.vault_signer.clone(),
             // spl_token_program,
             // referrer_pc_wallet
         ],
         seeds,
     )
+    .map_err(|err| dex_error("settle_funds", err))?;
+    Ok(())
+}
+
+// Serum `OpenOrders` is stored after 5 bytes of head padding:
+// account_flags (8), market (32), owner (32), native_coin_free (8), native_coin_total (8),
+// native_pc_free (8), ...
+const OPEN_ORDERS_NATIVE_COIN_FREE_OFFSET: usize = 5 + 8 + 32 + 32;
+const OPEN_ORDERS_NATIVE_PC_FREE_OFFSET: usize = OPEN_ORDERS_NATIVE_COIN_FREE_OFFSET + 8 + 8;
+
+// Unsettled `(native_coin_free, native_pc_free)` of an open orders account
+pub fn open_orders_free(open_orders_data: &[u8]) -> WowswapResult<(u64, u64)> {
+    let read_u64 = |offset: usize| -> WowswapResult<u64> {
+        let bytes = open_orders_data
+            .get(offset..offset + 8)
+            .ok_or(WowswapError::InvalidArgument)?;
+        let mut value = [0u8; 8];
+        value.copy_from_slice(bytes);
+        Ok(u64::from_le_bytes(value))
+    };
+
+    Ok((
+        read_u64(OPEN_ORDERS_NATIVE_COIN_FREE_OFFSET)?,
+        read_u64(OPEN_ORDERS_NATIVE_PC_FREE_OFFSET)?,
+    ))
+}
+
+pub fn open_orders_native_pc_free(open_orders: &AccountInfo) -> WowswapResult<u64> {
+    let (_, native_pc_free) = open_orders_free(&open_orders.try_borrow_data()?)?;
+    Ok(native_pc_free)
+}
+
+// Serum event queue is stored between 5 bytes of head and 7 bytes of tail padding:
+// account_flags (8), head (8), count (8), seq_num (8), followed by the 88 byte events
+const EVENT_QUEUE_COUNT_OFFSET: usize = 5 + 8 + 8;
+const EVENT_QUEUE_HEADER_SIZE: usize = 5 + 8 + 8 + 8 + 8;
+const EVENT_QUEUE_TAIL_PADDING: usize = 7;
+const EVENT_SIZE: usize = 88;
+
+// Matching fails once the event queue is full, so this is how many more events an order may
+// produce before the dex rejects it
+pub fn event_queue_free_slots(event_queue: &AccountInfo) -> WowswapResult<u64> {
+    let data = event_queue.try_borrow_data()?;
+    let capacity = data
+        .len()
+        .checked_sub(EVENT_QUEUE_HEADER_SIZE + EVENT_QUEUE_TAIL_PADDING)
+        .ok_or(WowswapError::InvalidArgument)?
+        / EVENT_SIZE;
+    let bytes = data
+        .get(EVENT_QUEUE_COUNT_OFFSET..EVENT_QUEUE_COUNT_OFFSET + 8)
+        .ok_or(WowswapError::InvalidArgument)?;
+    let mut count = [0u8; 8];
+    count.copy_from_slice(bytes);
+    Ok((capacity as u64).saturating_sub(u64::from_le_bytes(count)))
+}
+
+// Serum `MarketState` is stored after 5 bytes of head padding:
+// account_flags (8), own_address (32), vault_signer_nonce (8), coin_mint (32), pc_mint (32),
+// coin_vault (32), coin_deposits_total (8), coin_fees_accrued (8), pc_vault (32),
+// pc_deposits_total (8), pc_fees_accrued (8), pc_dust_threshold (8), req_q (32), event_q (32),
+// bids (32), asks (32), ...
+const MARKET_OWN_ADDRESS_OFFSET: usize = 5 + 8;
+const MARKET_VAULT_SIGNER_NONCE_OFFSET: usize = MARKET_OWN_ADDRESS_OFFSET + 32;
+const MARKET_COIN_VAULT_OFFSET: usize = MARKET_VAULT_SIGNER_NONCE_OFFSET + 8 + 32 + 32;
+const MARKET_PC_VAULT_OFFSET: usize = MARKET_COIN_VAULT_OFFSET + 32 + 8 + 8;
+const MARKET_REQUEST_QUEUE_OFFSET: usize = MARKET_PC_VAULT_OFFSET + 32 + 8 + 8 + 8;
+const MARKET_EVENT_QUEUE_OFFSET: usize = MARKET_REQUEST_QUEUE_OFFSET + 32;
+const MARKET_BIDS_OFFSET: usize = MARKET_EVENT_QUEUE_OFFSET + 32;
+const MARKET_ASKS_OFFSET: usize = MARKET_BIDS_OFFSET + 32;
+
+// Market owned accounts a client needs to fill `DexAccounts`
+#[derive(Debug, Clone, Copy)]
+pub struct MarketAccounts {
+    pub market: Pubkey,
+    pub request_queue: Pubkey,
+    pub event_queue: Pubkey,
+    pub bids: Pubkey,
+    pub asks: Pubkey,
+    pub coin_vault: Pubkey,
+    pub pc_vault: Pubkey,
+    pub vault_signer: Pubkey,
+}
+
+// Parses raw market account data, so SDKs can build `DexAccounts` from the market alone
+pub fn market_accounts(market_data: &[u8]) -> WowswapResult<MarketAccounts> {
+    let read_pubkey = |offset: usize| {
+        market_data
+            .get(offset..offset + 32)
+            .map(Pubkey::new)
+            .ok_or(WowswapError::InvalidArgument)
+    };
+
+    let market = read_pubkey(MARKET_OWN_ADDRESS_OFFSET)?;
+    let mut nonce = [0u8; 8];
+    nonce.copy_from_slice(
+        market_data
+            .get(MARKET_VAULT_SIGNER_NONCE_OFFSET..MARKET_VAULT_SIGNER_NONCE_OFFSET + 8)
+            .ok_or(WowswapError::InvalidArgument)?,
+    );
+    let vault_signer = Pubkey::create_program_address(&[market.as_ref(), &nonce], &ID)
+        .map_err(|_| WowswapError::InvalidArgument)?;
+
+    Ok(MarketAccounts {
+        market,
+        request_queue: read_pubkey(MARKET_REQUEST_QUEUE_OFFSET)?,
+        event_queue: read_pubkey(MARKET_EVENT_QUEUE_OFFSET)?,
+        bids: read_pubkey(MARKET_BIDS_OFFSET)?,
+        asks: read_pubkey(MARKET_ASKS_OFFSET)?,
+        coin_vault: read_pubkey(MARKET_COIN_VAULT_OFFSET)?,
+        pc_vault: read_pubkey(MARKET_PC_VAULT_OFFSET)?,
+        vault_signer,
+    })
+}
+
+// Free pc left in the open orders account at or below the market dust threshold can't be
+// settled, it stays with the swap instead of reaching the trader or the reserve
+pub fn open_orders_pc_dust(
+    open_orders: &AccountInfo,
+    lot_sizes: MarketLotSizes,
+) -> WowswapResult<TokenAmount> {
+    let native_pc_free = open_orders_native_pc_free(open_orders)?;
+    if native_pc_free <= lot_sizes.pc_dust_threshold {
+        Ok(TokenAmount::new(native_pc_free))
+    } else {
+        Ok(TokenAmount::ZERO)
+    }
+}
+
+fn dex_error(operation: &str, err: impl Into<ProgramError>) -> WowswapError {
+    msg!("Serum DEX {} failed: {:?}", operation, err.into());
+    WowswapError::DexOperationFailed
 }
 
 // v0.4.0 start use dynamic sysvars but keys still need to be passed
@@ -249,16 +400,87 @@ mod instruction_patched {
 pub struct MarketLotSizes {
     pub coin: u64,
     pub pc: u64,
+    pub pc_dust_threshold: u64,
+}
+
+impl<'info> DexAccounts<'info> {
+    // Checks that `market` is `expected_market` and that the queues, book, vaults and vault
+    // signer are the ones it records, so mismatched accounts fail here with `InvalidArgument`
+    // rather than inside the dex. Returns the lot sizes of the market.
+    pub fn validate_and_load(&self, expected_market: &Pubkey) -> WowswapResult<MarketLotSizes> {
+        require!(
+            *self.market.key == *expected_market,
+            WowswapError::InvalidArgument
+        );
+        let lot_sizes = market_lot_sizes(self)?;
+
+        let market = market_accounts(&self.market.try_borrow_data()?)?;
+        require!(
+            market.market == *self.market.key
+                && market.request_queue == *self.request_queue.key
+                && market.event_queue == *self.event_queue.key
+                && market.bids == *self.bids.key
+                && market.asks == *self.asks.key
+                && market.coin_vault == *self.coin_vault.key
+                && market.pc_vault == *self.pc_vault.key
+                && market.vault_signer == *self.vault_signer.key,
+            WowswapError::InvalidArgument
+        );
+
+        Ok(lot_sizes)
+    }
 }
 
 pub fn market_lot_sizes(dex_accounts: &DexAccounts) -> Result<MarketLotSizes, ProgramError> {
-    let market = MarketState::load(&dex_accounts.market, dex_accounts.dex_program.key)?;
+    load_market_lot_sizes(&dex_accounts.market, dex_accounts.dex_program.key)
+}
+
+pub fn load_market_lot_sizes(
+    market: &AccountInfo,
+    dex_program_id: &Pubkey,
+) -> Result<MarketLotSizes, ProgramError> {
+    let market = MarketState::load(market, dex_program_id)?;
+    // Callers divide by the lot sizes, a malformed or uninitialized market would fault there
+    require!(
+        market.coin_lot_size != 0 && market.pc_lot_size != 0,
+        WowswapError::InvalidMarket
+    );
     Ok(MarketLotSizes {
         coin: market.coin_lot_size,
         pc: market.pc_lot_size,
+        pc_dust_threshold: market.pc_dust_threshold,
     })
 }
 
+// Highest bid on the market, `None` when the book is empty
+pub fn best_bid_price(
+    market: &AccountInfo,
+    bids: &AccountInfo,
+    dex_program_id: &Pubkey,
+) -> WowswapResult<Option<DexLimitPrice>> {
+    let market =
+        MarketState::load(market, dex_program_id).map_err(|err| dex_error("load_market", err))?;
+    let bids = market
+        .load_bids_mut(bids)
+        .map_err(|err| dex_error("load_bids", err))?;
+
+    Ok(bids
+        .find_max()
+        .and_then(|handle| bids.get(handle))
+        .and_then(|node| node.as_leaf())
+        .map(|leaf| DexLimitPrice(leaf.price())))
+}
+
+// Serum quotes prices in pc lots per coin lot. Returns the price in native pc units per native
+// coin unit, so that a native coin amount multiplied by it gives its value in native pc units.
+// All health and liquidation price math should go through this conversion.
+pub fn price_to_pc_per_coin(price: DexLimitPrice, lot_sizes: MarketLotSizes) -> Wad {
+    let pc_per_coin_lot = (price.into_inner().get() as u128)
+        .checked_mul(lot_sizes.pc as u128)
+        .expect("pc_per_coin_lot overflow");
+    Wad::new(pc_per_coin_lot).wad_div(Wad::new(lot_sizes.coin as u128))
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct DexLimitPrice(NonZeroU64);
 
@@ -284,6 +506,8 @@ impl borsh::BorshSerialize for DexLimitPrice {
 }
 
 impl DexLimitPrice {
+    // Instruction arguments are checked on deserialization. Anything else built from untrusted
+    // input should map `None` to `WowswapError::InvalidArgument` rather than unwrap it.
     pub const fn new(value: u64) -> Option<Self> {
         match NonZeroU64::new(value) {
             Some(value) => Some(Self(value)),
@@ -382,6 +606,10 @@ impl DexNonZeroTokenAmount {
         }
     }
 
+    pub const fn from_token_amount(value: TokenAmount) -> Option<Self> {
+        Self::new(value.into_inner())
+    }
+
     pub fn checked_mul_token_qty(self, other: DexTokenQty) -> Option<TokenAmount> {
         self.0.get().checked_mul(other.0).map(TokenAmount::new)
     }
@@ -404,3 +632,57 @@ impl DexNonZeroTokenAmount {
         TokenAmount::new(self.0.get())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lot_sizes(coin: u64, pc: u64) -> MarketLotSizes {
+        MarketLotSizes {
+            coin,
+            pc,
+            pc_dust_threshold: 0,
+        }
+    }
+
+    #[test]
+    fn prices_convert_from_lots_to_native_units() {
+        // SOL/USDC with 0.1 SOL coin lots and 0.0001 USDC pc lots, 150 USDC per SOL
+        let lots = lot_sizes(100_000_000, 100);
+        let price = price_to_pc_per_coin(DexLimitPrice::new(150_000).unwrap(), lots);
+        assert_eq!(price, Wad::new(150_000_000));
+        let two_sol = TokenAmount::new(2_000_000_000).into_wad().wad_mul(price);
+        assert_eq!(two_sol.as_token_amount(), TokenAmount::new(300_000_000));
+
+        // Same decimals and unit lots leave the price as is
+        let price = price_to_pc_per_coin(DexLimitPrice::new(7).unwrap(), lot_sizes(1, 1));
+        assert_eq!(price, Wad::new(7_000_000_000));
+
+        // Fractions are rounded to the nearest `Wad` unit
+        let price = price_to_pc_per_coin(DexLimitPrice::new(1).unwrap(), lot_sizes(3, 1));
+        assert_eq!(price, Wad::new(333_333_333));
+    }
+
+    #[test]
+    fn zero_prices_and_quantities_are_rejected() {
+        use borsh::{BorshDeserialize, BorshSerialize};
+
+        assert!(DexLimitPrice::new(0).is_none());
+        assert!(DexNonZeroTokenQty::from_token_amount(TokenAmount::ZERO).is_none());
+
+        let zero = 0u64.to_le_bytes();
+        assert!(DexLimitPrice::try_from_slice(&zero).is_err());
+        assert!(DexNonZeroTokenQty::try_from_slice(&zero).is_err());
+
+        let price = DexLimitPrice::new(150_000).unwrap().try_to_vec().unwrap();
+        let price = DexLimitPrice::try_from_slice(&price).unwrap();
+        assert_eq!(price.into_inner().get(), 150_000);
+
+        let qty = DexNonZeroTokenQty::from_token_amount(TokenAmount::new(42))
+            .unwrap()
+            .try_to_vec()
+            .unwrap();
+        let qty = DexNonZeroTokenQty::try_from_slice(&qty).unwrap();
+        assert_eq!(qty.into_inner().get(), 42);
+    }
+}
@@ -10,4 +10,26 @@ pub enum WowswapError {
     InvalidLeverageFactor,
     BorrowLimitExceeded,
     LiquidateHealthyPosition,
+    InsufficientLiquidity,
+    TokenOperationFailed,
+    DexOperationFailed,
+    InvalidNonce,
+    PositionCooldown,
+    FillPriceExceeded,
+    InconsistentPositionState,
+    BorrowerNotAllowed,
+    TraderNotPermitted,
+    MarketBlocked,
+    MarketNotBlocked,
+    TooManyPositions,
+    MarketCongested,
+    PositionNotExpired,
+    SharesBelowMinimum,
+    SnapshotTooEarly,
+    WithdrawalsPaused,
+    DuplicateRequest,
+    TooManyDependents,
+    InvalidMarket,
+    AccrualStale,
+    GovernanceUninitialized,
 }
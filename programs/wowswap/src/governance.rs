@@ -3,8 +3,8 @@ use std::ops::DerefMut;
 
 use super::{
     authority,
-    error::WowswapResultEmpty,
-    math::{Factor, Rate, Ray, TokenAmount},
+    error::{WowswapError, WowswapResultEmpty},
+    math::{self, Factor, Rate, Ray, TokenAmount, UnixTimestamp},
 };
 
 declare_id!("WowzN6f45eVb9nHMmKCuvq79mnGMRsd1TUWBjfyXF6T");
@@ -23,6 +23,31 @@ pub struct Governance {
     pub liquidation_margin: u128,
     pub liquidation_reward: u128,
     pub max_liquidation_reward: u128,
+    pub max_borrow_rate: u128,
+    pub lender_bonus_rate: u128,
+    pub position_cooldown: u128,
+    pub log_borrow_rate: bool,
+    pub min_debt_recovery: u128,
+    pub max_fill_slippage: u128,
+    pub reserve_liquidity_floor: u128,
+    pub liquidation_reserve_first: bool,
+    pub position_grace_period: u128,
+    pub liquidation_reward_redeemable: bool,
+    pub min_event_queue_free_slots: u128,
+    pub keeper_close_fee: u128,
+    pub close_fee: u128,
+    pub close_fee_to_treasury: bool,
+    pub utilization_alert_threshold: u128,
+    pub min_liquidation_reward: u128,
+    pub reserve_snapshot_interval: u128,
+    pub insurance_factor: u128,
+    pub compound_terms: u128,
+    pub max_accrual_age: u128,
+    pub repay_buffer: u128,
+    pub position_dust_threshold: u128,
+    pub liquidation_treasury_share: u128,
+    pub liquidation_insurance_share: u128,
+    pub market_fee_share: u128,
 }
 
 impl Governance {
@@ -36,6 +61,28 @@ impl Governance {
         }
     }
 
+    fn into_u64(value: u128, msg: &'static str) -> u64 {
+        match value {
+            v if v > u64::MAX as u128 => panic!("{}", msg),
+            v => v as u64,
+        }
+    }
+
+    // A zeroed account has no optimal utilization, the rate curve divides by it and by its
+    // complement, and no leverage. Checked on the parameters themselves rather than a stored flag,
+    // which would read as unset on the account written before it existed.
+    pub fn is_initialized(&self) -> bool {
+        let optimal_utilization = self.optimal_utilization();
+        !optimal_utilization.is_zero()
+            && optimal_utilization < Ray::ONE
+            && self.max_leverage_factor() >= Factor::ONE
+    }
+
+    pub fn check_initialized(&self) -> WowswapResultEmpty {
+        require!(self.is_initialized(), WowswapError::GovernanceUninitialized);
+        Ok(())
+    }
+
     pub fn pool_utilization_allowance(&self) -> Factor {
         Factor::new(Self::apply_accuracy(
             self.pool_utilization_allowance,
@@ -47,6 +94,30 @@ impl Governance {
         Rate::new(self.base_borrow_rate)
     }
 
+    pub const fn max_borrow_rate(&self) -> Rate {
+        Rate::new(self.max_borrow_rate)
+    }
+
+    pub const fn lender_bonus_rate(&self) -> Rate {
+        Rate::new(self.lender_bonus_rate)
+    }
+
+    // Binomial terms used to compound interest, clamped to a range the approximation handles.
+    // Zero keeps the default. Changing it changes the debt accrued since the last update.
+    pub fn compound_terms(&self) -> u8 {
+        match Self::into_u64(self.compound_terms, "Governance::compound_terms overflow") {
+            0 => math::interest::DEFAULT_COMPOUND_TERMS,
+            v => v
+                .max(math::interest::MIN_COMPOUND_TERMS as u64)
+                .min(math::interest::MAX_COMPOUND_TERMS as u64) as u8,
+        }
+    }
+
+    // Off by default to save compute
+    pub const fn log_borrow_rate(&self) -> bool {
+        self.log_borrow_rate
+    }
+
     pub fn excess_slope(&self) -> Ray {
         Ray::new(self.excess_slope)
     }
@@ -59,6 +130,11 @@ impl Governance {
         Ray::new(self.optimal_utilization)
     }
 
+    // Zero disables the utilization alert
+    pub fn utilization_alert_threshold(&self) -> Ray {
+        Ray::new(self.utilization_alert_threshold)
+    }
+
     pub fn treasure_factor(&self) -> Factor {
         Factor::new(Self::apply_accuracy(
             self.treasure_factor,
@@ -66,6 +142,24 @@ impl Governance {
         ))
     }
 
+    // Share of accrued interest set aside for the insurance fund, together with `treasure_factor`
+    // at most 100%. Lenders earn the rest.
+    pub fn insurance_factor(&self) -> Factor {
+        Factor::new(Self::apply_accuracy(
+            self.insurance_factor,
+            "Governance::insurance_factor overflow",
+        ))
+    }
+
+    // Added on top of the debt quoted for a full repay, covers interest accrued until the repay
+    // lands. Zero quotes the exact debt.
+    pub fn repay_buffer(&self) -> Factor {
+        Factor::new(Self::apply_accuracy(
+            self.repay_buffer,
+            "Governance::repay_buffer overflow",
+        ))
+    }
+
     pub fn max_leverage_factor(&self) -> Factor {
         Factor::new(Self::apply_accuracy(
             self.max_leverage_factor,
@@ -94,12 +188,153 @@ impl Governance {
         ))
     }
 
+    // Parts of the liquidation reward credited to the treasury and the insurance fund, together
+    // at most 100%. The liquidator is paid the rest.
+    pub fn liquidation_treasury_share(&self) -> Factor {
+        Factor::new(Self::apply_accuracy(
+            self.liquidation_treasury_share,
+            "Governance::liquidation_treasury_share overflow",
+        ))
+    }
+
+    pub fn liquidation_insurance_share(&self) -> Factor {
+        Factor::new(Self::apply_accuracy(
+            self.liquidation_insurance_share,
+            "Governance::liquidation_insurance_share overflow",
+        ))
+    }
+
+    // Seconds, zero disables the cooldown
+    pub fn position_cooldown(&self) -> UnixTimestamp {
+        UnixTimestamp::new(Self::into_u64(
+            self.position_cooldown,
+            "Governance::position_cooldown overflow",
+        ))
+    }
+
+    // Seconds from opening within which a full repay is interest-free, zero disables it
+    pub fn position_grace_period(&self) -> UnixTimestamp {
+        UnixTimestamp::new(Self::into_u64(
+            self.position_grace_period,
+            "Governance::position_grace_period overflow",
+        ))
+    }
+
+    // Seconds between two reserve history snapshots, zero only requires time to move forward
+    pub fn reserve_snapshot_interval(&self) -> UnixTimestamp {
+        UnixTimestamp::new(Self::into_u64(
+            self.reserve_snapshot_interval,
+            "Governance::reserve_snapshot_interval overflow",
+        ))
+    }
+
+    // Seconds since the last accrual after which opens and deposits are rejected until the reserve
+    // is caught up by `reserve_accrue`. Zero disables the limit.
+    pub fn max_accrual_age(&self) -> UnixTimestamp {
+        UnixTimestamp::new(Self::into_u64(
+            self.max_accrual_age,
+            "Governance::max_accrual_age overflow",
+        ))
+    }
+
+    // Opens are rejected while the dex event queue has fewer free slots, zero disables the check
+    pub fn min_event_queue_free_slots(&self) -> u64 {
+        Self::into_u64(
+            self.min_event_queue_free_slots,
+            "Governance::min_event_queue_free_slots overflow",
+        )
+    }
+
+    // Share of the current debt the reserve recovers from the liquidation output before the
+    // liquidator is rewarded
+    pub fn min_debt_recovery(&self) -> Factor {
+        Factor::new(Self::apply_accuracy(
+            self.min_debt_recovery,
+            "Governance::min_debt_recovery overflow",
+        ))
+    }
+
+    // Zero disables the fill price check on open
+    pub fn max_fill_slippage(&self) -> Factor {
+        Factor::new(Self::apply_accuracy(
+            self.max_fill_slippage,
+            "Governance::max_fill_slippage overflow",
+        ))
+    }
+
+    // Share of total liquidity that has to stay in the vault while there is outstanding debt,
+    // zero disables the floor
+    pub fn reserve_liquidity_floor(&self) -> Factor {
+        Factor::new(Self::apply_accuracy(
+            self.reserve_liquidity_floor,
+            "Governance::reserve_liquidity_floor overflow",
+        ))
+    }
+
+    // Repay the reserve in full before the liquidator is rewarded
+    pub const fn liquidation_reserve_first(&self) -> bool {
+        self.liquidation_reserve_first
+    }
+
+    // Pay liquidators in reserve redeemable tokens, the pc reward stays in the reserve
+    pub const fn liquidation_reward_redeemable(&self) -> bool {
+        self.liquidation_reward_redeemable
+    }
+
+    // Paid to whoever closes a position past its term, out of the trader's share of the output
+    pub fn keeper_close_fee(&self) -> Factor {
+        Factor::new(Self::apply_accuracy(
+            self.keeper_close_fee,
+            "Governance::keeper_close_fee overflow",
+        ))
+    }
+
+    // Taken from the trader's proceeds on close after the debt is repaid
+    pub fn close_fee(&self) -> Factor {
+        Factor::new(Self::apply_accuracy(
+            self.close_fee,
+            "Governance::close_fee overflow",
+        ))
+    }
+
+    // Credit the close fee to the treasury instead of lenders
+    pub const fn close_fee_to_treasury(&self) -> bool {
+        self.close_fee_to_treasury
+    }
+
+    // Share of the close fee kept by the market in its fee vault, the reserve gets the rest.
+    // Markets without a fee vault keep nothing.
+    pub fn market_fee_share(&self) -> Factor {
+        Factor::new(Self::apply_accuracy(
+            self.market_fee_share,
+            "Governance::market_fee_share overflow",
+        ))
+    }
+
+    // Debt left by a close that sold all the coins is written off to the reserve bad debt below
+    // this, zero keeps any remainder. Liquidations always settle the whole debt.
+    pub fn position_dust_threshold(&self) -> TokenAmount {
+        TokenAmount::new(Self::apply_accuracy(
+            self.position_dust_threshold,
+            "Governance::position_dust_threshold overflow",
+        ))
+    }
+
     pub fn max_liquidation_reward(&self) -> TokenAmount {
         TokenAmount::new(Self::apply_accuracy(
             self.max_liquidation_reward,
             "Governance::max_liquidation_reward overflow",
         ))
     }
+
+    // Paid even when the liquidation reward share is below it, still capped by
+    // `max_liquidation_reward` and the debt the reserve has to recover
+    pub fn min_liquidation_reward(&self) -> TokenAmount {
+        TokenAmount::new(Self::apply_accuracy(
+            self.min_liquidation_reward,
+            "Governance::min_liquidation_reward overflow",
+        ))
+    }
 }
 
 #[derive(Accounts)]
@@ -108,7 +343,7 @@ pub struct GovernanceInitialize<'info> {
         init,
         payer = payer,
         constraint = *(*governance).as_ref().key == ID,
-        space = 2048, // Current size is 184
+        space = 2048, // Current size is 524
     )]
     governance: Box<Account<'info, Governance>>,
 
@@ -121,6 +356,27 @@ pub struct GovernanceInitialize<'info> {
 
 impl<'info> GovernanceInitialize<'info> {
     pub fn handle(&mut self, governance: Governance) -> WowswapResultEmpty {
+        governance.check_initialized()?;
+        require!(
+            governance
+                .treasure_factor()
+                .checked_add(governance.insurance_factor())
+                .map_or(false, |v| v <= Factor::ONE),
+            WowswapError::InvalidArgument
+        );
+        require!(
+            governance
+                .liquidation_treasury_share()
+                .checked_add(governance.liquidation_insurance_share())
+                .map_or(false, |v| v <= Factor::ONE),
+            WowswapError::InvalidArgument
+        );
+        require!(
+            governance.market_fee_share() <= Factor::ONE
+                && governance.min_debt_recovery() <= Factor::ONE,
+            WowswapError::InvalidArgument
+        );
+
         *(*self.governance).deref_mut() = governance;
         Ok(())
     }
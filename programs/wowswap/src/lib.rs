@@ -11,7 +11,7 @@ pub mod token;
 use dex::{DexLimitPrice, DexNonZeroTokenQty};
 use error::WowswapResultEmpty;
 use governance::*;
-use math::{Factor, TokenAmount};
+use math::{Factor, TokenAmount, UnixTimestamp};
 use reserve::*;
 use swap::*;
 
@@ -41,6 +41,14 @@ pub mod wowswap {
     pub fn reserve_deposit(
         ctx: Context<ReserveDeposit>,
         amount: TokenAmount,
+        min_shares_out: TokenAmount,
+    ) -> WowswapResultEmpty {
+        ctx.accounts.handle(amount, min_shares_out)
+    }
+
+    pub fn reserve_preview_deposit(
+        ctx: Context<ReservePreviewDeposit>,
+        amount: TokenAmount,
     ) -> WowswapResultEmpty {
         ctx.accounts.handle(amount)
     }
@@ -52,7 +60,168 @@ pub mod wowswap {
         ctx.accounts.handle(amount)
     }
 
-    pub fn swap_initialize(ctx: Context<SwapInitialize>, nonce: u8) -> WowswapResultEmpty {
+    pub fn reserve_deploy(ctx: Context<ReserveDeploy>, amount: TokenAmount) -> WowswapResultEmpty {
+        ctx.accounts.handle(amount)
+    }
+
+    pub fn reserve_recall(ctx: Context<ReserveRecall>, amount: TokenAmount) -> WowswapResultEmpty {
+        ctx.accounts.handle(amount)
+    }
+
+    pub fn reserve_write_down_deployed(
+        ctx: Context<ReserveWriteDownDeployed>,
+        amount: TokenAmount,
+    ) -> WowswapResultEmpty {
+        ctx.accounts.handle(amount)
+    }
+
+    pub fn reserve_fund_bonus(
+        ctx: Context<ReserveFundBonus>,
+        amount: TokenAmount,
+    ) -> WowswapResultEmpty {
+        ctx.accounts.handle(amount)
+    }
+
+    pub fn reserve_reconcile(ctx: Context<ReserveReconcile>) -> WowswapResultEmpty {
+        ctx.accounts.handle()
+    }
+
+    pub fn reserve_accrue(ctx: Context<ReserveAccrue>) -> WowswapResultEmpty {
+        ctx.accounts.handle()
+    }
+
+    pub fn reserve_bad_debt_view(ctx: Context<ReserveBadDebtView>) -> WowswapResultEmpty {
+        ctx.accounts.handle()
+    }
+
+    pub fn reserve_stats(ctx: Context<ReserveStatsView>) -> WowswapResultEmpty {
+        ctx.accounts.handle()
+    }
+
+    pub fn reserve_dependents(ctx: Context<ReserveDependentsView>) -> WowswapResultEmpty {
+        ctx.accounts.handle()
+    }
+
+    pub fn reserve_swap_registry_initialize(
+        ctx: Context<ReserveSwapRegistryInitialize>,
+        nonce: u8,
+    ) -> WowswapResultEmpty {
+        ctx.accounts.handle(nonce)
+    }
+
+    pub fn reserve_register_swap(ctx: Context<ReserveRegisterSwap>) -> WowswapResultEmpty {
+        ctx.accounts.handle()
+    }
+
+    pub fn global_debt(ctx: Context<GlobalDebtView>) -> WowswapResultEmpty {
+        ctx.accounts.handle(ctx.program_id, ctx.remaining_accounts)
+    }
+
+    pub fn reserve_projected_exchange_rate(
+        ctx: Context<ReserveProjectedExchangeRateView>,
+        horizon: u64,
+    ) -> WowswapResultEmpty {
+        ctx.accounts.handle(horizon)
+    }
+
+    pub fn reserve_break_even_utilization(
+        ctx: Context<ReserveBreakEvenUtilizationView>,
+        target_lender_apr_bps: u64,
+    ) -> WowswapResultEmpty {
+        ctx.accounts.handle(target_lender_apr_bps)
+    }
+
+    pub fn reserve_simulate_curve(
+        ctx: Context<ReserveSimulateCurve>,
+        proposed: Governance,
+        utilizations: Vec<u128>,
+    ) -> WowswapResultEmpty {
+        ctx.accounts.handle(proposed, utilizations)
+    }
+
+    pub fn reserve_history_initialize(
+        ctx: Context<ReserveHistoryInitialize>,
+        nonce: u8,
+    ) -> WowswapResultEmpty {
+        ctx.accounts.handle(nonce)
+    }
+
+    pub fn reserve_snapshot(ctx: Context<ReserveTakeSnapshot>) -> WowswapResultEmpty {
+        ctx.accounts.handle()
+    }
+
+    pub fn reserve_cover_bad_debt(
+        ctx: Context<ReserveCoverBadDebt>,
+        amount: TokenAmount,
+    ) -> WowswapResultEmpty {
+        ctx.accounts.handle(amount)
+    }
+
+    pub fn reserve_set_allowlist(
+        ctx: Context<ReserveSetAllowlist>,
+        allowlist: Vec<Pubkey>,
+    ) -> WowswapResultEmpty {
+        ctx.accounts.handle(allowlist)
+    }
+
+    pub fn reserve_set_withdrawals_paused(
+        ctx: Context<ReserveSetWithdrawalsPaused>,
+        paused: bool,
+    ) -> WowswapResultEmpty {
+        ctx.accounts.handle(paused)
+    }
+
+    pub fn reserve_can_borrow(
+        ctx: Context<ReserveCanBorrow>,
+        amount: TokenAmount,
+    ) -> WowswapResultEmpty {
+        ctx.accounts.handle(amount)
+    }
+
+    pub fn swap_initialize(
+        ctx: Context<SwapInitialize>,
+        nonce: u8,
+        max_leverage_override: Factor,
+        trader_gate: Pubkey,
+    ) -> WowswapResultEmpty {
+        ctx.accounts
+            .handle(nonce, max_leverage_override, trader_gate)
+    }
+
+    pub fn swap_rotate_proxy_authority(
+        ctx: Context<SwapRotateProxyAuthority>,
+        new_authority: Pubkey,
+    ) -> WowswapResultEmpty {
+        ctx.accounts.handle(new_authority)
+    }
+
+    pub fn swap_set_blocked(ctx: Context<SwapSetBlocked>, blocked: bool) -> WowswapResultEmpty {
+        ctx.accounts.handle(blocked)
+    }
+
+    pub fn swap_set_max_proxy_supply(
+        ctx: Context<SwapSetMaxProxySupply>,
+        max_proxy_supply: TokenAmount,
+    ) -> WowswapResultEmpty {
+        ctx.accounts.handle(max_proxy_supply)
+    }
+
+    pub fn swap_set_fee_vault(ctx: Context<SwapSetFeeVault>) -> WowswapResultEmpty {
+        ctx.accounts.handle()
+    }
+
+    pub fn swap_collect_fees(ctx: Context<SwapCollectFees>) -> WowswapResultEmpty {
+        ctx.accounts.handle()
+    }
+
+    pub fn swap_open_interest(ctx: Context<SwapOpenInterestView>) -> WowswapResultEmpty {
+        ctx.accounts.handle()
+    }
+
+    pub fn trader_index_initialize(
+        ctx: Context<TraderIndexInitialize>,
+        nonce: u8,
+    ) -> WowswapResultEmpty {
         ctx.accounts.handle(nonce)
     }
 
@@ -60,16 +229,63 @@ pub mod wowswap {
         ctx: Context<SwapPositionInitialize>,
         nonce: u8,
     ) -> WowswapResultEmpty {
-        ctx.accounts.handle(nonce)
+        let mut accounts = ctx.remaining_accounts;
+        ctx.accounts.handle(&mut accounts, nonce)
+    }
+
+    pub fn swap_position_init_if_needed(
+        ctx: Context<SwapPositionInitIfNeeded>,
+        nonce: u8,
+    ) -> WowswapResultEmpty {
+        ctx.accounts
+            .handle(ctx.program_id, ctx.remaining_accounts, nonce)
     }
 
+    // A nonzero `client_nonce` must be above the last one passed for the position, so a
+    // resubmitted open fails with `DuplicateRequest`
     pub fn swap_position_open(
         ctx: Context<SwapPositionOpen>,
         limit_price: DexLimitPrice,
         coin_qty: DexNonZeroTokenQty,
         leverage_factor: Factor,
+        max_pc_with_fees: Option<TokenAmount>,
+        max_duration: UnixTimestamp,
+        client_nonce: u64,
     ) -> WowswapResultEmpty {
-        ctx.accounts.handle(limit_price, coin_qty, leverage_factor)
+        let mut accounts = ctx.remaining_accounts;
+        ctx.accounts.handle(
+            &mut accounts,
+            limit_price,
+            coin_qty,
+            leverage_factor,
+            max_pc_with_fees,
+            max_duration,
+            client_nonce,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn swap_position_open_with_setup(
+        ctx: Context<SwapPositionOpenWithSetup>,
+        nonce: u8,
+        limit_price: DexLimitPrice,
+        coin_qty: DexNonZeroTokenQty,
+        leverage_factor: Factor,
+        max_pc_with_fees: Option<TokenAmount>,
+        max_duration: UnixTimestamp,
+        client_nonce: u64,
+    ) -> WowswapResultEmpty {
+        ctx.accounts.handle(
+            ctx.program_id,
+            ctx.remaining_accounts,
+            nonce,
+            limit_price,
+            coin_qty,
+            leverage_factor,
+            max_pc_with_fees,
+            max_duration,
+            client_nonce,
+        )
     }
 
     pub fn swap_position_close(
@@ -77,10 +293,107 @@ pub mod wowswap {
         limit_price: DexLimitPrice,
         coin_qty: DexNonZeroTokenQty,
     ) -> WowswapResultEmpty {
-        ctx.accounts.handle(limit_price, coin_qty)
+        ctx.accounts.handle(
+            ctx.program_id,
+            ctx.remaining_accounts,
+            limit_price,
+            coin_qty,
+        )
     }
 
-    pub fn swap_position_liquidate(ctx: Context<SwapPositionLiquidate>) -> WowswapResultEmpty {
+    pub fn swap_position_roll(
+        ctx: Context<SwapPositionRoll>,
+        close_limit_price: DexLimitPrice,
+        open_limit_price: DexLimitPrice,
+        coin_qty: DexNonZeroTokenQty,
+        leverage_factor: Factor,
+        max_pc_with_fees: Option<TokenAmount>,
+    ) -> WowswapResultEmpty {
+        ctx.accounts.handle(
+            ctx.program_id,
+            ctx.remaining_accounts,
+            close_limit_price,
+            open_limit_price,
+            coin_qty,
+            leverage_factor,
+            max_pc_with_fees,
+        )
+    }
+
+    pub fn swap_position_close_and_route(
+        ctx: Context<SwapPositionCloseAndRoute>,
+        close_limit_price: DexLimitPrice,
+        coin_qty: DexNonZeroTokenQty,
+        route_limit_price: DexLimitPrice,
+        route_max_coin_qty: DexNonZeroTokenQty,
+        min_output: TokenAmount,
+    ) -> WowswapResultEmpty {
+        ctx.accounts.handle(
+            ctx.program_id,
+            ctx.remaining_accounts,
+            close_limit_price,
+            coin_qty,
+            route_limit_price,
+            route_max_coin_qty,
+            min_output,
+        )
+    }
+
+    pub fn swap_position_quote(
+        ctx: Context<SwapPositionQuote>,
+        limit_price: DexLimitPrice,
+        coin_qty: DexNonZeroTokenQty,
+        leverage_factor: Factor,
+        max_pc_with_fees: Option<TokenAmount>,
+    ) -> WowswapResultEmpty {
+        ctx.accounts
+            .handle(limit_price, coin_qty, leverage_factor, max_pc_with_fees)
+    }
+
+    pub fn swap_position_max_quote(
+        ctx: Context<SwapPositionMaxQuote>,
+        trader_pc: TokenAmount,
+        leverage_factor: Factor,
+        limit_price: DexLimitPrice,
+    ) -> WowswapResultEmpty {
+        ctx.accounts.handle(trader_pc, leverage_factor, limit_price)
+    }
+
+    pub fn swap_position_reclaim(ctx: Context<SwapPositionReclaim>) -> WowswapResultEmpty {
+        ctx.accounts.handle()
+    }
+
+    pub fn swap_position_exists(ctx: Context<SwapPositionExists>) -> WowswapResultEmpty {
+        ctx.accounts.handle()
+    }
+
+    pub fn swap_position_liquidation_distance(
+        ctx: Context<SwapPositionLiquidationDistanceView>,
+    ) -> WowswapResultEmpty {
         ctx.accounts.handle()
     }
+
+    pub fn swap_position_value(ctx: Context<SwapPositionValueView>) -> WowswapResultEmpty {
+        ctx.accounts.handle()
+    }
+
+    pub fn swap_position_repay_amount(
+        ctx: Context<SwapPositionRepayAmountView>,
+    ) -> WowswapResultEmpty {
+        ctx.accounts.handle()
+    }
+
+    pub fn swap_position_liquidate(ctx: Context<SwapPositionLiquidate>) -> WowswapResultEmpty {
+        ctx.accounts.handle(ctx.program_id, ctx.remaining_accounts)
+    }
+
+    // Same accounts as `swap_position_liquidate`, `liquidator` being the keeper
+    pub fn swap_position_keeper_close(ctx: Context<SwapPositionLiquidate>) -> WowswapResultEmpty {
+        ctx.accounts
+            .handle_expired(ctx.program_id, ctx.remaining_accounts)
+    }
+
+    pub fn swap_position_force_close(ctx: Context<SwapPositionForceClose>) -> WowswapResultEmpty {
+        ctx.accounts.handle(ctx.program_id, ctx.remaining_accounts)
+    }
 }
@@ -16,6 +16,10 @@ impl UnixTimestamp {
         Ok(Self(Clock::get()?.unix_timestamp as u64))
     }
 
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        self.0.checked_add(other.0).map(Self)
+    }
+
     pub fn checked_sub(self, other: Self) -> Option<Self> {
         self.0.checked_sub(other.0).map(Self)
     }
@@ -69,6 +73,12 @@ impl TokenAmount {
         self.0 == 0
     }
 
+    // Both conversions keep the raw base units, they don't scale by the mint decimals. The widest
+    // path, `into_wad().into_ray()`, multiplies by 1e9 once (checked in `Wad::into_ray`), so any
+    // `u64` amount stays below 2e28 and a `ray_mul` by factors up to about 1e10 fits in `u128`.
+    // Token decimals don't matter for overflow: an SPL amount is a `u64` of base units whatever
+    // the decimals, an 18 decimal token simply can't have more than about 18 tokens in one
+    // account. The binding limit is `Reserve::MAX_DECIMALS_SHIFT` for the redeemable mint.
     pub const fn into_wad(self) -> Wad {
         Wad::from_u64(self.0)
     }
@@ -77,26 +87,55 @@ impl TokenAmount {
         Ray::from_u64(self.0)
     }
 
+    // Checked `into_wad().into_ray()`, the amount scaled to RAY precision. Paths taking user
+    // amounts use it together with `Ray::checked_ray_mul` to fail instead of panicking.
+    pub fn checked_into_ray(self) -> Option<Ray> {
+        self.into_wad().checked_into_ray()
+    }
+
     pub const fn into_inner(self) -> u64 {
         self.0
     }
 }
 
-#[derive(Debug, Default, Clone, Copy, PartialEq, AnchorDeserialize, AnchorSerialize)]
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, PartialOrd, AnchorDeserialize, AnchorSerialize,
+)]
 pub struct Rate(u128);
 
 impl Rate {
     pub const ZERO: Self = Rate::new(0);
     pub const RAY_RATIO: u128 = 1_000_000_000;
 
+    pub const SECONDS_PER_YEAR: u128 = 365 * 24 * 60 * 60;
+
     pub const fn new(inner: u128) -> Self {
         Self(inner)
     }
 
+    // Per-second rate from an annual rate in basis points, for governance tooling. Interest is
+    // compounded every second, so the effective annual yield is slightly above the APR.
+    pub fn from_apr_bps(bps: u64) -> Self {
+        // Rate is a RAY scaled by `RAY_RATIO`, i.e. 1e+27 is 100% per second
+        let one = Ray::ONE
+            .into_inner()
+            .checked_mul(Self::RAY_RATIO)
+            .expect("Rate::from_apr_bps overflow");
+        let inner = (bps as u128)
+            .checked_mul(one / 10_000)
+            .map(|v| v / Self::SECONDS_PER_YEAR)
+            .expect("Rate::from_apr_bps overflow");
+        Self(inner)
+    }
+
     pub const fn into_ray(self) -> Ray {
         Ray::new(self.0.overflowing_div(Self::RAY_RATIO).0)
     }
 
+    pub const fn is_zero(&self) -> bool {
+        self.0 == 0
+    }
+
     pub const fn into_inner(self) -> u128 {
         self.0
     }
@@ -139,11 +178,38 @@ impl Factor {
             .expect("Factor::percentage_mul overflow")
     }
 
+    // Rounds down instead of half-up, for amounts taken from lenders so that rounding never
+    // charges them more than the exact share
+    pub fn percentage_mul_down(self, value: u128) -> u128 {
+        value
+            .checked_mul(self.0 as u128)
+            .and_then(|v| v.checked_div(Self::ONE.0 as u128))
+            .expect("Factor::percentage_mul_down overflow")
+    }
+
     pub fn invert(self) -> Self {
         Self::ONE
             .checked_sub(self)
             .expect("Factor::invert overflow")
     }
+
+    // Leverage for display, e.g. 2.5x is 250
+    pub const fn as_ratio_x100(self) -> u64 {
+        self.0 / 100
+    }
+
+    // Price drop (in bps) which wipes the collateral of a position with this leverage
+    pub fn max_drawdown_bps(self) -> u64 {
+        Self::ONE
+            .0
+            .checked_mul(Self::ONE.0)
+            .and_then(|v| v.checked_div(self.0))
+            .expect("Factor::max_drawdown_bps overflow")
+    }
+
+    pub const fn into_inner(self) -> u64 {
+        self.0
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
@@ -163,6 +229,10 @@ impl Wad {
         Self::new(value as u128)
     }
 
+    pub const fn into_inner(self) -> u128 {
+        self.0
+    }
+
     pub fn checked_add(self, other: Self) -> Option<Self> {
         self.0.checked_add(other.0).map(Self)
     }
@@ -181,35 +251,52 @@ impl Wad {
 
     // (a * b + HALF_WAD) / WAD
     pub fn wad_mul(self, other: Self) -> Self {
+        self.checked_wad_mul(other).expect("Wad::wad_mul overflow")
+    }
+
+    // Same as `wad_mul`, but returns `None` on overflow
+    pub fn checked_wad_mul(self, other: Self) -> Option<Self> {
         self.checked_mul(other)
             .and_then(|v| v.checked_add(Self::HALF))
             .and_then(|v| v.checked_div(Self::ONE))
-            .expect("Wad::wad_mul overflow")
     }
 
     // (a * WAD + b / 2) / b
     pub fn wad_div(self, other: Self) -> Self {
+        debug_assert!(!other.is_zero(), "Wad::wad_div division by zero");
+        self.checked_wad_div(other).expect("Wad::wad_div overflow")
+    }
+
+    // Same as `wad_div`, but returns `None` on overflow or when `other` is zero
+    pub fn checked_wad_div(self, other: Self) -> Option<Self> {
+        if other.is_zero() {
+            return None;
+        }
         self.checked_mul(Self::ONE)
-            .and_then(|v| {
-                let two = Wad::new(2);
-                v.checked_add(other.checked_div(two).expect("division by zero"))
-            })
+            .and_then(|v| v.checked_add(Wad::new(other.0 / 2)))
             .and_then(|v| v.checked_div(other))
-            .expect("Wad::wad_div overflow")
     }
 
     // a * 1e+9
     pub fn into_ray(self) -> Ray {
-        Ray::new(
-            (self.0 as u128)
-                .checked_mul(1_000_000_000)
-                .expect("Wad::into_ray overflow"),
-        )
+        self.checked_into_ray().expect("Wad::into_ray overflow")
+    }
+
+    pub fn checked_into_ray(self) -> Option<Ray> {
+        self.0.checked_mul(1_000_000_000).map(Ray::new)
     }
 
     pub fn as_token_amount(self) -> TokenAmount {
         TokenAmount::from_u128(self.0)
     }
+
+    pub fn checked_as_token_amount(self) -> Option<TokenAmount> {
+        if self.0 > u64::MAX as u128 {
+            None
+        } else {
+            Some(TokenAmount::new(self.0 as u64))
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
@@ -251,10 +338,14 @@ impl Ray {
 
     // (a * b + HALF_RAY) / RAY
     pub fn ray_mul(self, other: Self) -> Self {
+        self.checked_ray_mul(other).expect("Ray::ray_mul overflow")
+    }
+
+    // Same as `ray_mul`, but returns `None` on overflow
+    pub fn checked_ray_mul(self, other: Self) -> Option<Self> {
         self.checked_mul(other)
             .and_then(|v| v.checked_add(Self::HALF))
             .and_then(|v| v.checked_div(Self::ONE))
-            .expect("Ray::ray_mul overflow")
     }
 
     // (a * RAY + b / 2) / b
@@ -268,6 +359,16 @@ impl Ray {
             .expect("Ray::ray_div overflow")
     }
 
+    // Same as `ray_div`, but returns `None` on overflow or when `other` is zero
+    pub fn checked_ray_div(self, other: Self) -> Option<Self> {
+        if other.is_zero() {
+            return None;
+        }
+        self.checked_mul(Self::ONE)
+            .and_then(|v| v.checked_add(Ray::new(other.0 / 2)))
+            .and_then(|v| v.checked_div(other))
+    }
+
     pub fn invert(self) -> Self {
         Self::ONE.checked_sub(self).expect("Ray::invert overflow")
     }
@@ -292,17 +393,31 @@ impl Ray {
 pub mod liquidity {
     use super::{TokenAmount, Wad};
 
+    // Returns `None` when shares are outstanding but back no liquidity at all. Minting at 1:1 in
+    // that case would hand part of the new deposit to the existing holders. Also `None` when the
+    // shares don't fit in `u64`, which a wide `decimals_shift` makes reachable.
     pub fn mint_amount(
         amount: TokenAmount,
         total_supply: TokenAmount,
         total_liquidity: TokenAmount,
-    ) -> TokenAmount {
-        let index = if total_supply.is_zero() || total_liquidity.is_zero() {
-            Wad::ONE
+        decimals_shift: u8,
+    ) -> Option<TokenAmount> {
+        let index = if total_supply.is_zero() {
+            // Redeemable mint may have more decimals than the lendable one
+            10u128
+                .checked_pow(decimals_shift as u32)
+                .and_then(|v| Wad::ONE.checked_mul(Wad::new(v)))?
+        } else if total_liquidity.is_zero() {
+            return None;
         } else {
-            total_supply.into_wad().wad_div(total_liquidity.into_wad())
+            total_supply
+                .into_wad()
+                .checked_wad_div(total_liquidity.into_wad())?
         };
-        amount.into_wad().wad_mul(index).as_token_amount()
+        amount
+            .into_wad()
+            .checked_wad_mul(index)?
+            .checked_as_token_amount()
     }
 
     pub fn calculate_share(
@@ -322,20 +437,56 @@ pub mod liquidity {
 pub mod interest {
     use super::{Rate, Ray, TokenAmount, UnixTimestamp};
 
+    // Longest period compounded with the binomial approximation in one step
+    const COMPOUNDING_PERIOD: u64 = 24 * 60 * 60;
+
+    // Bounds of `Governance::compound_terms`, each term costs a few more multiplications
+    pub const MIN_COMPOUND_TERMS: u8 = 3;
+    pub const MAX_COMPOUND_TERMS: u8 = 8;
+    pub const DEFAULT_COMPOUND_TERMS: u8 = 6;
+
     // Calculate the interest using a compounded interest rate formula in RAY.
-    // To avoid expensive exponentiation, the calculation is performed using a binomial approximation:
-    // (1+x)^n = 1+n*x+[n/2*(n-1)]*x^2+[n/6*(n-1)*(n-2)*x^3...
+    // The truncated binomial approximation drifts from true compounding over long idle gaps, so
+    // the elapsed time is split into daily periods: the daily factor is raised to the number of
+    // full days by squaring and the remainder is compounded on top. `terms` is the number of
+    // binomial terms kept, the same value has to be used everywhere the same debt is accrued.
     pub fn calculate_compounded(
         rate: Rate,
         last_timestamp: UnixTimestamp,
         timestamp: UnixTimestamp,
+        terms: u8,
     ) -> Ray {
+        let exp = timestamp
+            .checked_sub(last_timestamp)
+            .expect("Invalid timestamps")
+            .into_inner();
+
+        let mut result =
+            compounded_approximation(rate, UnixTimestamp::new(exp % COMPOUNDING_PERIOD), terms);
+        let mut periods = exp / COMPOUNDING_PERIOD;
+        if periods > 0 {
+            let mut period_factor =
+                compounded_approximation(rate, UnixTimestamp::new(COMPOUNDING_PERIOD), terms);
+            loop {
+                if periods & 1 == 1 {
+                    result = result.ray_mul(period_factor);
+                }
+                periods >>= 1;
+                if periods == 0 {
+                    break;
+                }
+                period_factor = period_factor.ray_mul(period_factor);
+            }
+        }
+        result
+    }
+
+    // To avoid expensive exponentiation, the calculation is performed using a binomial approximation:
+    // (1+x)^n = 1+n*x+[n/2*(n-1)]*x^2+[n/6*(n-1)*(n-2)*x^3...
+    fn compounded_approximation(rate: Rate, exp: UnixTimestamp, terms: u8) -> Ray {
         let rate_ray = rate.into_ray();
         let mut result = Ray::ONE;
 
-        let exp = timestamp
-            .checked_sub(last_timestamp)
-            .expect("Invalid timestamps");
         if exp.is_zero() {
             return result;
         }
@@ -344,7 +495,8 @@ pub mod interest {
             .checked_mul(Ray::from_u64(exp.into_inner()))
             .expect("compounded overflow");
         result = result.checked_add(el).expect("compounded overflow");
-        for i in 1..5 {
+        // `1` and `n*x` are the first two terms
+        for i in 1..(terms.max(MIN_COMPOUND_TERMS) as u64 - 1) {
             let multiplier = match exp.checked_sub(UnixTimestamp::new(i)) {
                 None => break,
                 Some(exp) if exp == UnixTimestamp::ZERO => break,
@@ -365,7 +517,7 @@ pub mod interest {
     }
 
     // Calculate utilization rate based on current debt and available liquidity.
-    fn calculate_utilization(debt: TokenAmount, liquidity: TokenAmount) -> Ray {
+    pub fn calculate_utilization(debt: TokenAmount, liquidity: TokenAmount) -> Ray {
         debt.into_ray().ray_div(
             liquidity
                 .into_ray()
@@ -381,9 +533,28 @@ pub mod interest {
         excess_slope: Ray,
         optimal_slope: Ray,
         optimal_utilization: Ray,
+        max_borrow_rate: Rate,
+    ) -> Rate {
+        borrow_rate_at(
+            calculate_utilization(debt, liquidity),
+            base_borrow_rate,
+            excess_slope,
+            optimal_slope,
+            optimal_utilization,
+            max_borrow_rate,
+        )
+    }
+
+    // Same curve as `borrow_rate`, for a given utilization
+    pub fn borrow_rate_at(
+        utilization: Ray,
+        base_borrow_rate: Rate,
+        excess_slope: Ray,
+        optimal_slope: Ray,
+        optimal_utilization: Ray,
+        max_borrow_rate: Rate,
     ) -> Rate {
-        let utilization = calculate_utilization(debt, liquidity);
-        match utilization.checked_sub(optimal_utilization) {
+        let rate = match utilization.checked_sub(optimal_utilization) {
             // utilization >= optimal_utilization
             Some(diff) if !diff.is_zero() => {
                 // Utilization is too high, so calculate rate based on excess slope.
@@ -405,6 +576,255 @@ pub mod interest {
             }
         }
         .expect("borrow_rate overflow")
-        .as_rate()
+        .as_rate();
+
+        // Zero cap means the rate is not limited
+        if !max_borrow_rate.is_zero() && rate > max_borrow_rate {
+            max_borrow_rate
+        } else {
+            rate
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn as_f64(ray: Ray) -> f64 {
+        ray.into_inner() as f64 / Ray::ONE.into_inner() as f64
+    }
+
+    fn compounded(apr_bps: u64, seconds: u64, terms: u8) -> Ray {
+        interest::calculate_compounded(
+            Rate::from_apr_bps(apr_bps),
+            UnixTimestamp::ZERO,
+            UnixTimestamp::new(seconds),
+            terms,
+        )
+    }
+
+    const YEAR: u64 = Rate::SECONDS_PER_YEAR as u64;
+
+    #[test]
+    fn compounding_over_a_year_stays_close_to_exact() {
+        // Per second compounding of a 20% APR is e^0.2 to well within the bound
+        let exact = 0.2f64.exp();
+        for terms in interest::MIN_COMPOUND_TERMS..=interest::MAX_COMPOUND_TERMS {
+            let error = (as_f64(compounded(2_000, YEAR, terms)) - exact).abs() / exact;
+            assert!(error < 0.001, "terms: {}, error: {}", terms, error);
+        }
+    }
+
+    #[test]
+    fn compounding_splits_into_daily_periods() {
+        let terms = interest::DEFAULT_COMPOUND_TERMS;
+        assert_eq!(compounded(2_000, 0, terms), Ray::ONE);
+
+        // Ten days and an hour at once or period by period, up to rounding
+        let day = 24 * 60 * 60;
+        let at_once = compounded(2_000, 10 * day + 3_600, terms);
+        let mut stepped = compounded(2_000, 3_600, terms);
+        for _ in 0..10 {
+            stepped = stepped.ray_mul(compounded(2_000, day, terms));
+        }
+        let diff = at_once.into_inner().max(stepped.into_inner())
+            - at_once.into_inner().min(stepped.into_inner());
+        assert!(diff <= 10, "diff: {}", diff);
+    }
+
+    #[test]
+    fn more_compound_terms_are_never_less_accurate() {
+        let exact = 2.0f64.exp();
+        let error = |terms| (as_f64(compounded(20_000, YEAR, terms)) - exact).abs();
+        for terms in interest::MIN_COMPOUND_TERMS..interest::MAX_COMPOUND_TERMS {
+            assert!(error(terms + 1) <= error(terms), "terms: {}", terms);
+        }
+        // Fewer terms than the minimum are raised to it
+        assert_eq!(
+            compounded(20_000, YEAR, 0),
+            compounded(20_000, YEAR, interest::MIN_COMPOUND_TERMS)
+        );
+    }
+
+    #[test]
+    fn apr_converts_to_a_per_second_rate() {
+        // 100% APR is 1e+27 a year, less what truncating the per second rate drops
+        let rate = Rate::from_apr_bps(10_000).into_inner() * Rate::SECONDS_PER_YEAR;
+        let year = Ray::ONE.into_inner() * Rate::RAY_RATIO;
+        assert!(rate <= year && year - rate < Rate::SECONDS_PER_YEAR);
+
+        // 5% APR compounds to e^0.05 over a year
+        let grown = as_f64(compounded(500, YEAR, interest::DEFAULT_COMPOUND_TERMS));
+        assert!((grown - 0.05f64.exp()).abs() < 1e-6, "grown: {}", grown);
+
+        assert!(Rate::from_apr_bps(0).is_zero());
+    }
+
+    #[test]
+    fn borrow_rate_cap_engages_near_full_utilization() {
+        // 10% APR up to 80% utilization, then up to another 100% APR at full utilization
+        let slope = |apr_bps| Rate::from_apr_bps(apr_bps).into_ray();
+        let rate_at = |utilization_pct: u128, max_borrow_rate| {
+            interest::borrow_rate_at(
+                Ray::new(Ray::ONE.into_inner() * utilization_pct / 100),
+                Rate::ZERO,
+                slope(10_000),
+                slope(1_000),
+                Ray::new(Ray::ONE.into_inner() * 8 / 10),
+                max_borrow_rate,
+            )
+        };
+        let cap = Rate::from_apr_bps(10_800);
+
+        // 105% APR at 99% and 110% at 100%, up to rounding
+        let at_99 = rate_at(99, Rate::ZERO);
+        let at_100 = rate_at(100, Rate::ZERO);
+        assert!(at_99 > Rate::from_apr_bps(10_499) && at_99 < Rate::from_apr_bps(10_501));
+        assert!(at_100 > Rate::from_apr_bps(10_999) && at_100 < Rate::from_apr_bps(11_001));
+
+        // Only the rate above the cap is clamped
+        assert_eq!(rate_at(99, cap), at_99);
+        assert_eq!(rate_at(100, cap), cap);
+    }
+
+    #[test]
+    fn no_shares_are_minted_against_empty_liquidity() {
+        let mint = |amount, total_supply, total_liquidity, decimals_shift| {
+            liquidity::mint_amount(
+                TokenAmount::new(amount),
+                TokenAmount::new(total_supply),
+                TokenAmount::new(total_liquidity),
+                decimals_shift,
+            )
+            .map(TokenAmount::into_inner)
+        };
+
+        // Outstanding shares backed by nothing
+        assert_eq!(mint(100, 1_000, 0, 0), None);
+        assert_eq!(mint(100, 1, 0, 3), None);
+
+        // First deposit, scaled to the redeemable decimals
+        assert_eq!(mint(100, 0, 0, 0), Some(100));
+        assert_eq!(mint(100, 0, 0, 3), Some(100_000));
+        assert_eq!(mint(100, 2_000, 1_000, 0), Some(200));
+    }
+
+    #[test]
+    fn shares_of_an_18_decimal_redeemable_over_a_6_decimal_lendable() {
+        const SHIFT: u8 = 12;
+        let mint = |amount, total_supply, total_liquidity| {
+            liquidity::mint_amount(
+                TokenAmount::new(amount),
+                TokenAmount::new(total_supply),
+                TokenAmount::new(total_liquidity),
+                SHIFT,
+            )
+            .map(TokenAmount::into_inner)
+        };
+
+        // First deposit of 5 lendable tokens mints 5 redeemable ones
+        assert_eq!(mint(5_000_000, 0, 0), Some(5_000_000_000_000_000_000));
+        // A u64 holds about 18.4 redeemable tokens, larger deposits fail instead of overflowing
+        assert_eq!(mint(18_000_000, 0, 0), Some(18_000_000_000_000_000_000));
+        assert_eq!(mint(19_000_000, 0, 0), None);
+        assert_eq!(mint(u64::MAX, 0, 0), None);
+
+        // After 10% of interest, one more token mints 1 / 1.1 redeemable tokens
+        let total_supply = 5_000_000_000_000_000_000;
+        let minted = mint(1_000_000, total_supply, 5_500_000).unwrap();
+        let exact = 1_000_000u128 * total_supply as u128 / 5_500_000;
+        assert!((minted as u128).max(exact) - (minted as u128).min(exact) <= 1);
+        assert_eq!(mint(u64::MAX, total_supply, 5_500_000), None);
+        assert_eq!(mint(2, u64::MAX, 1), None);
+
+        // Redeeming pays the lendable share back, all of it for the whole supply
+        let redeem = |shares| {
+            liquidity::calculate_share(
+                TokenAmount::new(shares),
+                TokenAmount::new(total_supply),
+                TokenAmount::new(5_500_000),
+            )
+            .into_inner()
+        };
+        assert_eq!(redeem(total_supply), 5_500_000);
+        assert_eq!(redeem(total_supply / 2), 2_750_000);
+        assert_eq!(redeem(1_000_000_000_000), 1);
+        assert_eq!(
+            liquidity::calculate_share(
+                TokenAmount::new(u64::MAX),
+                TokenAmount::new(u64::MAX),
+                TokenAmount::new(u64::MAX),
+            ),
+            TokenAmount::new(u64::MAX)
+        );
+    }
+
+    #[test]
+    fn percentage_mul_rounding() {
+        // 7.5 rounds half up, or down for amounts charged to lenders
+        let half = Factor::new(5_000);
+        assert_eq!(half.percentage_mul(15), 8);
+        assert_eq!(half.percentage_mul_down(15), 7);
+
+        // Never above the exact share, and exact when there is no remainder
+        let third = Factor::new(3_333);
+        assert_eq!(third.percentage_mul(10), 3);
+        assert_eq!(third.percentage_mul_down(10), 3);
+        assert_eq!(third.percentage_mul_down(9_999), 3_332);
+        assert_eq!(third.percentage_mul(9_999), 3_333);
+        assert_eq!(half.percentage_mul_down(10_000), 5_000);
+    }
+
+    #[test]
+    fn leverage_display_and_drawdown() {
+        let leverage = |ratio_x100: u64| Factor::new(ratio_x100 * 100);
+
+        assert_eq!(Factor::ONE.as_ratio_x100(), 100);
+        assert_eq!(leverage(250).as_ratio_x100(), 250);
+        assert_eq!(Factor::new(25_099).as_ratio_x100(), 250);
+
+        // 1x only loses everything at a 100% drop, 2x at 50%, 5x at 20%
+        assert_eq!(Factor::ONE.max_drawdown_bps(), 10_000);
+        assert_eq!(leverage(200).max_drawdown_bps(), 5_000);
+        assert_eq!(leverage(500).max_drawdown_bps(), 2_000);
+        assert_eq!(leverage(300).max_drawdown_bps(), 3_333);
+    }
+
+    #[test]
+    fn every_token_amount_scales_to_a_ray() {
+        let max = TokenAmount::new(u64::MAX);
+        assert_eq!(
+            max.checked_into_ray(),
+            Some(Ray::new(u64::MAX as u128 * 1_000_000_000))
+        );
+        assert_eq!(max.checked_into_ray(), Some(max.into_wad().into_ray()));
+
+        let max_wad = u128::MAX / 1_000_000_000;
+        assert!(Wad::new(max_wad).checked_into_ray().is_some());
+        assert_eq!(Wad::new(max_wad + 1).checked_into_ray(), None);
+    }
+
+    #[test]
+    fn ray_mul_overflow_boundary_for_an_18_decimal_token() {
+        // The largest balance an 18 decimal mint can hold, about 18.4 tokens
+        let amount = TokenAmount::new(u64::MAX).checked_into_ray().unwrap();
+        let max_rate = (u128::MAX - Ray::HALF.into_inner()) / amount.into_inner();
+
+        assert!(amount.checked_ray_mul(Ray::new(max_rate)).is_some());
+        assert_eq!(amount.checked_ray_mul(Ray::new(max_rate + 1)), None);
+
+        // Which puts the limit between a 50% and a 100% APR
+        assert!(Rate::from_apr_bps(5_000).into_ray() < Ray::new(max_rate));
+        assert!(Rate::from_apr_bps(10_000).into_ray() > Ray::new(max_rate));
+    }
+
+    #[test]
+    fn checked_ray_div_matches_ray_div() {
+        let a = Ray::new(3 * Ray::ONE.into_inner());
+        let b = Ray::new(2 * Ray::ONE.into_inner());
+        assert_eq!(a.checked_ray_div(b), Some(a.ray_div(b)));
+        assert_eq!(a.checked_ray_div(Ray::new(0)), None);
+        assert_eq!(Ray::new(u128::MAX).checked_ray_div(b), None);
     }
 }
@@ -1,12 +1,12 @@
 use anchor_lang::prelude::*;
-use solana_program::{entrypoint::ProgramResult, program_option::COption};
+use solana_program::program_option::COption;
 
 use super::{
     authority,
-    error::{WowswapResult, WowswapResultEmpty},
+    error::{WowswapError, WowswapResult, WowswapResultEmpty},
     governance::{self, Governance},
-    math::{self, Factor, Rate, TokenAmount, UnixTimestamp},
-    swap::SwapPositionState,
+    math::{self, Factor, Rate, Ray, TokenAmount, UnixTimestamp, Wad},
+    swap::{Swap, SwapPositionState},
     token::{self, SplToken, TokenAccount, TokenMint},
 };
 
@@ -17,6 +17,16 @@ pub struct ReserveState {
     pub treasurer_update: UnixTimestamp,
 }
 
+// The reserve itself is not known where the rate is updated, it's identified by its signer
+#[event]
+pub struct HighUtilization {
+    pub reserve_signer: Pubkey,
+    // Scaled by 1e18
+    pub utilization: u128,
+    pub above: bool,
+    pub timestamp: UnixTimestamp,
+}
+
 #[derive(Debug, Default, Clone, Copy, PartialEq, AnchorSerialize, AnchorDeserialize)]
 pub struct ReserveDebt {
     pub average_rate: Rate,
@@ -25,13 +35,14 @@ pub struct ReserveDebt {
 }
 
 impl ReserveDebt {
-    pub fn get_total_debt(&self, timestamp: UnixTimestamp) -> TokenAmount {
+    pub fn get_total_debt(&self, timestamp: UnixTimestamp, compound_terms: u8) -> TokenAmount {
         self.total
             .into_ray()
             .ray_mul(math::interest::calculate_compounded(
                 self.average_rate,
                 self.last_update,
                 timestamp,
+                compound_terms,
             ))
             .as_token_amount()
     }
@@ -49,25 +60,201 @@ pub struct Reserve {
 
     pub state: ReserveState,
     pub debt: ReserveDebt,
+
+    pub deployed_liquidity: TokenAmount,
+
+    pub decimals_shift: u8,
+
+    // Swaps allowed to borrow, an empty list allows any swap
+    pub borrower_allowlist: [Pubkey; 7],
+
+    // Emergency stop for withdrawals during a run on the reserve, deposits and position closes
+    // keep working so debt can still be repaid into the vault
+    pub withdrawals_paused: bool,
+
+    // Lifetime sum of loans drawn from the reserve, repayments don't decrease it
+    pub cumulative_borrowed: u128,
+
+    // Funded by `reserve_fund_bonus` and held in the vault, but excluded from lender liquidity
+    // until it's paid out as the lender bonus
+    pub lender_bonus_pool: TokenAmount,
+    pub bad_debt: TokenAmount,
+
+    // Share of interest set aside for the insurance fund, excluded from lender liquidity like
+    // `state.treasure_accrued`
+    pub insurance_accrued: TokenAmount,
+
+    // Utilization was above `utilization_alert_threshold` on the last rate update
+    pub utilization_alert: bool,
 }
 
 impl Reserve {
+    // Redeemable shares are `u64` too, so an extra decimal costs a factor of ten in capacity. At
+    // the maximum, an 18 decimal redeemable over a 6 decimal lendable mint, the supply holds
+    // about 18 lendable tokens at the initial rate. Deposits past that fail, they don't overflow.
+    pub const MAX_DECIMALS_SHIFT: u8 = 12;
+
+    // Length of `borrower_allowlist`, bounded by the space left in accounts created at 489 bytes
+    pub const MAX_ALLOWED_BORROWERS: usize = 7;
+
+    pub fn find_signer_address(reserve: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[reserve.as_ref()], &crate::ID)
+    }
+
+    // Handlers only compare the signer against the stored key, so it's pinned to the canonical
+    // bump at initialization
+    pub fn check_signer_nonce(reserve: &Pubkey, nonce: u8) -> WowswapResultEmpty {
+        let (_, canonical_nonce) = Self::find_signer_address(reserve);
+        require!(nonce == canonical_nonce, WowswapError::InvalidNonce);
+        Ok(())
+    }
+
+    // Seeds of the signer for the reserve at `address`, as `find_signer_address` derives them
+    pub fn signer_seeds<'a>(&'a self, address: &'a Pubkey) -> [&'a [u8]; 2] {
+        [address.as_ref(), std::slice::from_ref(&self.nonce)]
+    }
+
+    pub fn is_borrower_allowed(&self, swap: &Pubkey) -> bool {
+        let default = Pubkey::default();
+        self.borrower_allowlist.iter().all(|key| *key == default)
+            || self.borrower_allowlist.contains(swap)
+    }
+
     pub fn update_state(
         &mut self,
         governance: &Governance,
         total_debt: TokenAmount,
+        liquidity: TokenAmount,
         timestamp: UnixTimestamp,
     ) {
-        self.state.treasure_accrued = self.get_liquidity_fee_accrued(governance, total_debt);
+        let bonus = self.get_lender_bonus(governance, total_debt, liquidity, timestamp);
+        self.lender_bonus_pool = self
+            .lender_bonus_pool
+            .checked_sub(bonus)
+            .expect("lender bonus overflow");
+        let (treasure_accrued, insurance_accrued) =
+            self.get_liquidity_fee_accrued(governance, total_debt);
+        self.state.treasure_accrued = treasure_accrued;
+        self.insurance_accrued = insurance_accrued;
+        self.checkpoint(timestamp, governance.compound_terms());
+
+        // Fees accrued on debt that was later written off may exceed what the reserve holds,
+        // cap them so total liquidity never goes negative. The bonus pool isn't theirs to take.
+        let gross_liquidity = self
+            .get_gross_liquidity(total_debt, liquidity)
+            .checked_sub(self.lender_bonus_pool)
+            .unwrap_or(TokenAmount::ZERO);
+        self.cap_fees_accrued(gross_liquidity);
+    }
+
+    // Same update as `update_state`, returning the treasury and insurance fees as accrued before
+    // the cap. Whatever they exceed the recorded fees by is drift the cap removed.
+    pub fn reconcile(
+        &mut self,
+        governance: &Governance,
+        total_debt: TokenAmount,
+        liquidity: TokenAmount,
+        timestamp: UnixTimestamp,
+    ) -> (TokenAmount, TokenAmount) {
+        let expected = self.get_liquidity_fee_accrued(governance, total_debt);
+        self.update_state(governance, total_debt, liquidity, timestamp);
+        expected
+    }
+
+    // Treasury first, the insurance fund gets what is left
+    fn cap_fees_accrued(&mut self, available: TokenAmount) {
+        if self.state.treasure_accrued > available {
+            self.state.treasure_accrued = available;
+        }
+        let insurance_available = available.safe_sub(self.state.treasure_accrued);
+        if self.insurance_accrued > insurance_available {
+            self.insurance_accrued = insurance_available;
+        }
+    }
+
+    // Fails when debt was last compounded more than `max_accrual_age` ago, so a late open or
+    // deposit doesn't price against a reserve that hasn't been caught up. Only checked where new
+    // exposure is taken, closes and withdrawals always go through. Reserves without debt have
+    // nothing to compound.
+    pub fn check_accrual_age(
+        &self,
+        governance: &Governance,
+        timestamp: UnixTimestamp,
+    ) -> WowswapResultEmpty {
+        let max_age = governance.max_accrual_age();
+        if max_age.is_zero() || self.debt.total.is_zero() {
+            return Ok(());
+        }
+
+        let age = timestamp
+            .checked_sub(self.debt.last_update)
+            .expect("Invalid timestamps");
+        require!(age <= max_age, WowswapError::AccrualStale);
+        Ok(())
+    }
+
+    // Moves both the debt and the treasury accrual windows to `timestamp`, so that the fee window
+    // of the next update starts exactly where debt was last compounded to
+    pub fn checkpoint(&mut self, timestamp: UnixTimestamp, compound_terms: u8) {
+        self.debt.total = self.debt.get_total_debt(timestamp, compound_terms);
+        self.debt.last_update = timestamp;
         self.state.treasurer_update = timestamp;
     }
 
+    // Lenders earn an extra bonus while utilization is above optimal. The bonus grows linearly
+    // from zero at optimal utilization to `lender_bonus_rate` at full utilization and is paid out
+    // of the bonus pool, so it stops once the pool is empty. It's computed on lender liquidity,
+    // which leaves out the pool itself.
+    fn get_lender_bonus(
+        &self,
+        governance: &Governance,
+        total_debt: TokenAmount,
+        liquidity: TokenAmount,
+        timestamp: UnixTimestamp,
+    ) -> TokenAmount {
+        let bonus_rate = governance.lender_bonus_rate();
+        if bonus_rate.is_zero() || total_debt.is_zero() || self.lender_bonus_pool.is_zero() {
+            return TokenAmount::ZERO;
+        }
+
+        let optimal_utilization = governance.optimal_utilization();
+        let utilization = math::interest::calculate_utilization(
+            total_debt,
+            liquidity
+                .checked_add(self.deployed_liquidity)
+                .expect("liquidity overflow"),
+        );
+        let excess_ratio = match utilization.checked_sub(optimal_utilization) {
+            Some(diff) if !diff.is_zero() => diff.ray_div(optimal_utilization.invert()),
+            Some(_) | None => return TokenAmount::ZERO,
+        };
+
+        let elapsed = timestamp
+            .checked_sub(self.state.treasurer_update)
+            .expect("Invalid timestamps");
+        let bonus = self
+            .get_total_liquidity(total_debt, liquidity)
+            .into_ray()
+            .ray_mul(
+                bonus_rate
+                    .into_ray()
+                    .ray_mul(excess_ratio)
+                    .checked_mul(Ray::from_u64(elapsed.into_inner()))
+                    .expect("lender bonus overflow"),
+            )
+            .as_token_amount();
+
+        std::cmp::min(bonus, self.lender_bonus_pool)
+    }
+
+    // Treasury and insurance fund totals with their shares of the interest accrued since the last
+    // update, lenders keep the rest
     fn get_liquidity_fee_accrued(
         &self,
         governance: &Governance,
         current_debt: TokenAmount,
-    ) -> TokenAmount {
-        let fee = {
+    ) -> (TokenAmount, TokenAmount) {
+        let debt_accrued = {
             if current_debt.is_zero() {
                 TokenAmount::ZERO
             } else {
@@ -79,25 +266,37 @@ impl Reserve {
                         self.debt.average_rate,
                         self.debt.last_update,
                         self.state.treasurer_update,
+                        governance.compound_terms(),
                     ))
                     .as_token_amount();
 
-                let debt_accrued = current_debt
+                current_debt
                     .checked_sub(previous_debt)
-                    .expect("invalid debt");
-
-                TokenAmount::from_u128(
-                    governance
-                        .treasure_factor()
-                        .percentage_mul(debt_accrued.into_inner() as u128),
-                )
+                    .expect("invalid debt")
             }
         };
 
-        self.state
-            .treasure_accrued
-            .checked_add(fee)
-            .expect("accured treasure overflow")
+        // Rounded down, the dust of every accrual stays with lenders
+        let fee = TokenAmount::from_u128(
+            governance
+                .treasure_factor()
+                .percentage_mul_down(debt_accrued.into_inner() as u128),
+        );
+        let insurance = TokenAmount::from_u128(
+            governance
+                .insurance_factor()
+                .percentage_mul_down(debt_accrued.into_inner() as u128),
+        );
+
+        (
+            self.state
+                .treasure_accrued
+                .checked_add(fee)
+                .expect("accured treasure overflow"),
+            self.insurance_accrued
+                .checked_add(insurance)
+                .expect("accured insurance overflow"),
+        )
     }
 
     pub fn get_total_liquidity(
@@ -105,12 +304,210 @@ impl Reserve {
         total_debt: TokenAmount,
         liquidity: TokenAmount,
     ) -> TokenAmount {
+        self.get_gross_liquidity(total_debt, liquidity)
+            .checked_sub(self.state.treasure_accrued)
+            .and_then(|v| v.checked_sub(self.insurance_accrued))
+            .and_then(|v| v.checked_sub(self.lender_bonus_pool))
+            .unwrap_or(TokenAmount::ZERO)
+    }
+
+    // Redeemable tokens minted for a deposit of `amount`, `total_liquidity` has to be accrued up to
+    // the deposit time for the result to match `reserve_deposit`
+    pub fn preview_deposit(
+        &self,
+        amount: TokenAmount,
+        total_supply: TokenAmount,
+        total_liquidity: TokenAmount,
+    ) -> Option<TokenAmount> {
+        math::liquidity::mint_amount(amount, total_supply, total_liquidity, self.decimals_shift)
+    }
+
+    // Share of total liquidity a single swap may have on loan
+    pub fn borrow_limit(
+        &self,
+        governance: &Governance,
+        total_debt: TokenAmount,
+        liquidity: TokenAmount,
+    ) -> TokenAmount {
+        let total_liquidity = self.get_total_liquidity(total_debt, liquidity);
+        TokenAmount::from_u128(
+            governance
+                .pool_utilization_allowance()
+                .percentage_mul(total_liquidity.into_inner() as u128),
+        )
+    }
+
+    // Largest new loan a swap with `total_loan` outstanding can take right now. The borrow limit
+    // is exclusive and the loan has to be covered by the vault.
+    pub fn borrow_headroom(
+        &self,
+        governance: &Governance,
+        total_debt: TokenAmount,
+        liquidity: TokenAmount,
+        total_loan: TokenAmount,
+    ) -> TokenAmount {
+        let limit_headroom = self
+            .borrow_limit(governance, total_debt, liquidity)
+            .checked_sub(total_loan)
+            .and_then(|v| v.checked_sub(TokenAmount::new(1)))
+            .unwrap_or(TokenAmount::ZERO);
+        std::cmp::min(limit_headroom, liquidity)
+    }
+
+    // Everything owed to lenders and the treasury, plus the bonus pool
+    fn get_gross_liquidity(&self, total_debt: TokenAmount, liquidity: TokenAmount) -> TokenAmount {
         total_debt
             .checked_add(liquidity)
-            .and_then(|v| v.checked_sub(self.state.treasure_accrued))
+            .and_then(|v| v.checked_add(self.deployed_liquidity))
             .expect("total_liquidity overflow")
     }
 
+    // Value held for lenders, consistent with `exchange_rate`. Bad debt is not subtracted here,
+    // it was already removed from the outstanding debt when it was written off.
+    pub fn tvl(
+        &self,
+        vault_balance: TokenAmount,
+        timestamp: UnixTimestamp,
+        compound_terms: u8,
+    ) -> TokenAmount {
+        self.get_total_liquidity(
+            self.debt.get_total_debt(timestamp, compound_terms),
+            vault_balance,
+        )
+    }
+
+    // For fees paid into the vault on behalf of the treasury
+    pub fn add_treasure(&mut self, amount: TokenAmount) {
+        self.state.treasure_accrued = self
+            .state
+            .treasure_accrued
+            .checked_add(amount)
+            .expect("accured treasure overflow");
+    }
+
+    pub fn add_insurance(&mut self, amount: TokenAmount) {
+        self.insurance_accrued = self
+            .insurance_accrued
+            .checked_add(amount)
+            .expect("accrued insurance overflow");
+    }
+
+    pub fn add_bad_debt(&mut self, amount: TokenAmount) {
+        self.bad_debt = self
+            .bad_debt
+            .checked_add(amount)
+            .expect("bad_debt overflow");
+    }
+
+    // Books `amount` coming back from the strategy. Anything above the deployed principal is
+    // yield, the treasury takes its `treasure_factor` share of it like it does of interest and
+    // lenders keep the rest. Returns the yield.
+    pub fn recall_deployed(&mut self, governance: &Governance, amount: TokenAmount) -> TokenAmount {
+        let principal = std::cmp::min(amount, self.deployed_liquidity);
+        let strategy_yield = amount.safe_sub(principal);
+        self.deployed_liquidity = self.deployed_liquidity.safe_sub(principal);
+
+        self.add_treasure(TokenAmount::from_u128(
+            governance
+                .treasure_factor()
+                .percentage_mul_down(strategy_yield.into_inner() as u128),
+        ));
+
+        strategy_yield
+    }
+
+    // Recognizes a strategy loss, lenders absorb it through the exchange rate
+    pub fn write_down_deployed(&mut self, amount: TokenAmount) -> WowswapResultEmpty {
+        self.deployed_liquidity = self
+            .deployed_liquidity
+            .checked_sub(amount)
+            .ok_or(WowswapError::InvalidArgument)?;
+        Ok(())
+    }
+
+    pub fn exchange_rate(
+        &self,
+        total_debt: TokenAmount,
+        liquidity: TokenAmount,
+        total_supply: TokenAmount,
+    ) -> Wad {
+        if total_supply.is_zero() {
+            Wad::ONE
+        } else {
+            self.get_total_liquidity(total_debt, liquidity)
+                .into_wad()
+                .wad_div(total_supply.into_wad())
+        }
+    }
+
+    // Exchange rate `seconds` from `timestamp` if nothing but interest changes until then. Debt is
+    // compounded at the current average rate and the treasury and lender bonus accrue as they
+    // would on the next update.
+    pub fn projected_exchange_rate(
+        &self,
+        governance: &Governance,
+        seconds: u64,
+        timestamp: UnixTimestamp,
+        vault_balance: TokenAmount,
+        total_supply: TokenAmount,
+    ) -> Wad {
+        let horizon = timestamp
+            .checked_add(UnixTimestamp::new(seconds))
+            .expect("horizon overflow");
+
+        let mut reserve = *self;
+        let total_debt = reserve
+            .debt
+            .get_total_debt(horizon, governance.compound_terms());
+        reserve.update_state(governance, total_debt, vault_balance, horizon);
+        reserve.exchange_rate(total_debt, vault_balance, total_supply)
+    }
+
+    // Lowest utilization at which lenders earn `target_lender_apr_bps`, or `None` if they don't
+    // even at full utilization. Lenders earn the borrow rate on the borrowed share of liquidity,
+    // less the treasury and insurance shares. The lender bonus is not included. The curve is
+    // monotonic, so it's inverted by bisection.
+    pub fn break_even_utilization(
+        governance: &Governance,
+        target_lender_apr_bps: u64,
+    ) -> Option<Ray> {
+        let target = Rate::from_apr_bps(target_lender_apr_bps).into_ray();
+        let lender_share = Factor::ONE
+            .checked_sub(governance.treasure_factor())
+            .and_then(|v| v.checked_sub(governance.insurance_factor()))
+            .unwrap_or(Factor::new(0));
+        let lender_rate = |utilization: Ray| {
+            let borrow_rate = math::interest::borrow_rate_at(
+                utilization,
+                governance.base_borrow_rate(),
+                governance.excess_slope(),
+                governance.optimal_slope(),
+                governance.optimal_utilization(),
+                governance.max_borrow_rate(),
+            );
+            Ray::new(
+                lender_share
+                    .percentage_mul(borrow_rate.into_ray().ray_mul(utilization).into_inner()),
+            )
+        };
+
+        if lender_rate(Ray::ONE) < target {
+            return None;
+        }
+
+        let mut low = Ray::new(0);
+        let mut high = Ray::ONE;
+        while high.checked_sub(low).map_or(false, |v| v > Ray::new(1)) {
+            let mid = Ray::new((low.into_inner() + high.into_inner()) / 2);
+            if lender_rate(mid) < target {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        Some(high)
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub fn update_borrow_rate(
         &mut self,
@@ -127,8 +524,10 @@ impl Reserve {
             .and_then(|v| v.checked_sub(debt_removed))
             .expect("debt overflow");
 
+        // Deployed funds still belong to lenders, so they count as available liquidity
         let liquidity = liquidity
-            .checked_add(liquidity_added)
+            .checked_add(self.deployed_liquidity)
+            .and_then(|v| v.checked_add(liquidity_added))
             .and_then(|v| v.checked_sub(liquidity_removed))
             .expect("liquidity overflow");
 
@@ -139,7 +538,36 @@ impl Reserve {
             governance.excess_slope(),
             governance.optimal_slope(),
             governance.optimal_utilization(),
+            governance.max_borrow_rate(),
         );
+
+        let alert_threshold = governance.utilization_alert_threshold();
+        if !alert_threshold.is_zero() {
+            let utilization = math::interest::calculate_utilization(debt, liquidity);
+            let above = utilization > alert_threshold;
+            // Only crossings are reported, so monitoring is not flooded while it stays above
+            if above != self.utilization_alert {
+                self.utilization_alert = above;
+                emit!(HighUtilization {
+                    reserve_signer: self.signer,
+                    utilization: utilization.into_inner(),
+                    above,
+                    // Callers accrue the reserve up to now before updating the rate
+                    timestamp: self.debt.last_update,
+                });
+            }
+        }
+
+        if governance.log_borrow_rate() {
+            msg!(
+                "Borrow rate update: debt={}, liquidity={}, base_borrow_rate={}, optimal_utilization={}, borrow_rate={}",
+                debt.into_inner(),
+                liquidity.into_inner(),
+                governance.base_borrow_rate().into_inner(),
+                governance.optimal_utilization().into_inner(),
+                self.state.borrow_rate.into_inner(),
+            );
+        }
     }
 
     pub fn increase_debt(
@@ -149,117 +577,51 @@ impl Reserve {
         previous_total: TokenAmount,
         amount: TokenAmount,
         rate_multiplier: Factor,
-    ) {
-        let rate = Rate::new(rate_multiplier.percentage_mul(self.state.borrow_rate.into_inner()));
-        let amount_ray_rate = amount.into_wad().into_ray().ray_mul(rate.into_ray());
-
-        let (current_debt, debt_increase) = position.calculate_debt_increase(timestamp);
-        let next_total = previous_total
-            .checked_add(amount)
-            .expect("total debt overflow");
-        self.debt.total = next_total;
-
-        // Update user debt
-        position.amount = position
-            .amount
-            .checked_add(amount)
-            .and_then(|v| v.checked_add(debt_increase))
-            .expect("amount overflow");
-        position.rate = position
-            .rate
-            .into_ray()
-            .ray_mul(current_debt.into_wad().into_ray())
-            .checked_add(amount_ray_rate)
-            .map(|v| {
-                let debt = current_debt.checked_add(amount).expect("debt overflow");
-                v.ray_div(debt.into_wad().into_ray())
-            })
-            .expect("rate overflow")
-            .as_rate();
-        position.timestamp = timestamp;
+        compound_terms: u8,
+    ) -> WowswapResultEmpty {
+        sim::increase_debt(
+            &mut self.debt,
+            self.state.borrow_rate,
+            position,
+            timestamp,
+            previous_total,
+            amount,
+            rate_multiplier,
+            compound_terms,
+        )?;
 
-        // Recalculate an average borrow rate
-        self.debt.average_rate = self
-            .debt
-            .average_rate
-            .into_ray()
-            .ray_mul(previous_total.into_wad().into_ray())
-            .checked_add(amount_ray_rate)
-            .map(|v| v.ray_div(next_total.into_wad().into_ray()))
-            .expect("rate overflow")
-            .as_rate();
-        self.debt.last_update = timestamp;
+        self.cumulative_borrowed = self
+            .cumulative_borrowed
+            .checked_add(amount.into_inner() as u128)
+            .expect("cumulative_borrowed overflow");
+        Ok(())
     }
 
     pub fn decrease_debt(
         &mut self,
         position: &mut SwapPositionState,
         timestamp: UnixTimestamp,
+        grace: UnixTimestamp,
         reserve_total_debt: TokenAmount,
         debt_change: TokenAmount,
+        compound_terms: u8,
     ) {
-        let (current_debt, debt_increase) = position.calculate_debt_increase(timestamp);
-
-        // Since the total debt and each individual user's debts are accrued separately, due to an
-        // accumulation error the last borrower to repay loan may try to repay more than the total
-        // debt outstanding.
-        // In this case when the last borrower repays the debt, we simply set the total outstanding
-        // debt and the average stable rate to 0.
-        if reserve_total_debt <= debt_change {
-            self.debt.average_rate = Rate::ZERO;
-            self.debt.total = TokenAmount::ZERO;
-        } else {
-            let next_total = reserve_total_debt
-                .checked_sub(debt_change)
-                .expect("total debt overflow");
-            self.debt.total = next_total;
-
-            // For the reason described above, when the last user repays the debt, it might happen
-            // that user's rate * user's balance > avg rate * total debt. In that case, we simply
-            // set the avg rate to 0
-            let first_term = self
-                .debt
-                .average_rate
-                .into_ray()
-                .ray_mul(reserve_total_debt.into_wad().into_ray());
-            let second_term = position
-                .rate
-                .into_ray()
-                .ray_mul(debt_change.into_wad().into_ray());
-
-            if second_term >= first_term {
-                self.debt.average_rate = Rate::ZERO;
-                self.debt.total = TokenAmount::ZERO;
-            } else {
-                self.debt.average_rate = first_term
-                    .checked_sub(second_term)
-                    .expect("rate overflow")
-                    .ray_div(next_total.into_wad().into_ray())
-                    .as_rate();
-            }
-        }
-
-        if debt_change == current_debt {
-            position.rate = Rate::ZERO;
-            position.amount = TokenAmount::ZERO;
-            position.timestamp = UnixTimestamp::ZERO;
-        } else {
-            position.amount = position
-                .amount
-                .checked_add(debt_increase)
-                .and_then(|v| v.checked_sub(debt_change))
-                .expect("amount overflow");
-            position.timestamp = timestamp;
-        }
-
-        self.debt.last_update = timestamp;
+        sim::decrease_debt(
+            &mut self.debt,
+            position,
+            timestamp,
+            grace,
+            reserve_total_debt,
+            debt_change,
+            compound_terms,
+        );
     }
 }
 
 #[derive(Accounts)]
 #[instruction(nonce: u8)]
 pub struct ReserveInitialize<'info> {
-    #[account(init, payer = payer, space = 489)] // Current size is 169
+    #[account(init, payer = payer, space = 489)] // Current size is 476
     reserve: Box<Account<'info, Reserve>>,
     #[account(seeds = [(*reserve).as_ref().key.as_ref()], bump = nonce)]
     signer: AccountInfo<'info>,
@@ -276,8 +638,10 @@ pub struct ReserveInitialize<'info> {
     lendable_vault: Box<Account<'info, TokenAccount>>,
     #[account(
         constraint = redeemable_mint.mint_authority == COption::Some(*signer.key),
+        constraint = redeemable_mint.freeze_authority.is_none(),
         constraint = redeemable_mint.supply == 0,
-        constraint = redeemable_mint.decimals == lendable_mint.decimals,
+        constraint = redeemable_mint.decimals >= lendable_mint.decimals,
+        constraint = redeemable_mint.decimals - lendable_mint.decimals <= Reserve::MAX_DECIMALS_SHIFT,
     )]
     redeemable_mint: Box<Account<'info, TokenMint>>,
 
@@ -290,6 +654,8 @@ pub struct ReserveInitialize<'info> {
 
 impl<'info> ReserveInitialize<'info> {
     pub fn handle(&mut self, nonce: u8) -> WowswapResultEmpty {
+        Reserve::check_signer_nonce((*self.reserve).as_ref().key, nonce)?;
+
         let reserve = &mut self.reserve;
 
         reserve.signer = *self.signer.key;
@@ -298,6 +664,7 @@ impl<'info> ReserveInitialize<'info> {
         reserve.lendable_mint = *(*self.lendable_mint).as_ref().key;
         reserve.lendable_vault = *(*self.lendable_vault).as_ref().key;
         reserve.redeemable_mint = *(*self.redeemable_mint).as_ref().key;
+        reserve.decimals_shift = self.redeemable_mint.decimals - self.lendable_mint.decimals;
 
         Ok(())
     }
@@ -332,8 +699,18 @@ pub struct ReserveDeposit<'info> {
 }
 
 impl<'info> ReserveDeposit<'info> {
-    pub fn handle(&mut self, amount: TokenAmount) -> WowswapResultEmpty {
+    pub fn handle(
+        &mut self,
+        amount: TokenAmount,
+        min_shares_out: TokenAmount,
+    ) -> WowswapResultEmpty {
+        self.governance.check_initialized()?;
+
         let mint_amount = self.reserve_update_state(amount)?;
+        require!(
+            mint_amount >= min_shares_out,
+            WowswapError::SharesBelowMinimum
+        );
         self.take_investor_funds(amount)?;
         self.mint_redeemable(mint_amount)?;
         Ok(())
@@ -342,30 +719,19 @@ impl<'info> ReserveDeposit<'info> {
     fn reserve_update_state(&mut self, amount: TokenAmount) -> WowswapResult<TokenAmount> {
         let timestamp = UnixTimestamp::now()?;
 
-        let reserve = &mut self.reserve;
-        let governance = &self.governance;
-        let total_debt = reserve.debt.get_total_debt(timestamp);
-        reserve.update_state(governance, total_debt, timestamp);
-
-        let liquidity = TokenAmount::new(self.reserve_lendable_vault.amount);
-        reserve.update_borrow_rate(
-            governance,
-            liquidity,
+        let mint_amount = sim::deposit(
+            &mut self.reserve,
+            &self.governance,
+            TokenAmount::new(self.reserve_lendable_vault.amount),
+            TokenAmount::new(self.reserve_redeemable_mint.supply),
             amount,
-            TokenAmount::ZERO,
-            total_debt,
-            TokenAmount::ZERO,
-            TokenAmount::ZERO,
-        );
-
-        let total_supply = TokenAmount::new(self.reserve_redeemable_mint.supply);
-        let total_liquidity = reserve.get_total_liquidity(total_debt, liquidity);
-        let mint_amount = math::liquidity::mint_amount(amount, total_supply, total_liquidity);
+            timestamp,
+        )?;
 
         Ok(mint_amount)
     }
 
-    fn take_investor_funds(&self, amount: TokenAmount) -> ProgramResult {
+    fn take_investor_funds(&self, amount: TokenAmount) -> WowswapResultEmpty {
         token::transfer(
             self.investor_lendable_vault.to_account_info(),
             self.reserve_lendable_vault.to_account_info(),
@@ -375,17 +741,69 @@ impl<'info> ReserveDeposit<'info> {
         )
     }
 
-    fn mint_redeemable(&self, amount: TokenAmount) -> ProgramResult {
+    fn mint_redeemable(&self, amount: TokenAmount) -> WowswapResultEmpty {
         token::mint_to(
             self.reserve_redeemable_mint.to_account_info(),
             self.investor_redeemable_vault.to_account_info(),
             self.reserve_signer.to_account_info(),
             amount,
-            &[&[(*self.reserve).as_ref().key.as_ref(), &[self.reserve.nonce]]],
+            &[&self.reserve.signer_seeds((*self.reserve).as_ref().key)],
         )
     }
 }
 
+#[event]
+pub struct ReserveDepositPreview {
+    pub reserve: Pubkey,
+    pub amount: TokenAmount,
+    pub shares: TokenAmount,
+}
+
+// Read-only, reports through the `ReserveDepositPreview` event how many redeemable tokens
+// `reserve_deposit` would mint for `amount` right now
+#[derive(Accounts)]
+pub struct ReservePreviewDeposit<'info> {
+    #[account(
+        constraint = *(*reserve_lendable_vault).as_ref().key == reserve.lendable_vault,
+        constraint = *(*reserve_redeemable_mint).as_ref().key == reserve.redeemable_mint,
+    )]
+    reserve: Box<Account<'info, Reserve>>,
+    reserve_lendable_vault: Box<Account<'info, TokenAccount>>,
+    reserve_redeemable_mint: Box<Account<'info, TokenMint>>,
+
+    #[account(constraint = *(*governance).as_ref().key == governance::ID)]
+    governance: Box<Account<'info, Governance>>,
+}
+
+impl<'info> ReservePreviewDeposit<'info> {
+    pub fn handle(&self, amount: TokenAmount) -> WowswapResultEmpty {
+        let timestamp = UnixTimestamp::now()?;
+
+        // Accrue a copy the same way the deposit would. The rate update is skipped, it doesn't
+        // change the mint amount and would report utilization alerts for a state never stored.
+        let liquidity = TokenAmount::new(self.reserve_lendable_vault.amount);
+        let mut reserve = **self.reserve;
+        let total_debt = reserve
+            .debt
+            .get_total_debt(timestamp, self.governance.compound_terms());
+        reserve.update_state(&self.governance, total_debt, liquidity, timestamp);
+        let shares = reserve
+            .preview_deposit(
+                amount,
+                TokenAmount::new(self.reserve_redeemable_mint.supply),
+                reserve.get_total_liquidity(total_debt, liquidity),
+            )
+            .ok_or(WowswapError::InvalidArgument)?;
+
+        emit!(ReserveDepositPreview {
+            reserve: *(*self.reserve).as_ref().key,
+            amount,
+            shares,
+        });
+        Ok(())
+    }
+}
+
 #[derive(Accounts)]
 pub struct ReserveWithdraw<'info> {
     #[account(
@@ -416,6 +834,12 @@ pub struct ReserveWithdraw<'info> {
 
 impl<'info> ReserveWithdraw<'info> {
     pub fn handle(&mut self, amount: TokenAmount) -> WowswapResultEmpty {
+        require!(
+            !self.reserve.withdrawals_paused,
+            WowswapError::WithdrawalsPaused
+        );
+        self.governance.check_initialized()?;
+
         let (burn_amount, withdraw_amount) = self.reserve_update_state(amount)?;
         self.burn_redeemable(burn_amount)?;
         self.payout_investor_funds(withdraw_amount)?;
@@ -428,57 +852,2152 @@ impl<'info> ReserveWithdraw<'info> {
     ) -> WowswapResult<(TokenAmount, TokenAmount)> {
         let timestamp = UnixTimestamp::now()?;
 
-        let reserve = &mut self.reserve;
+        sim::withdraw(
+            &mut self.reserve,
+            &self.governance,
+            TokenAmount::new(self.reserve_lendable_vault.amount),
+            TokenAmount::new(self.reserve_redeemable_mint.supply),
+            amount,
+            timestamp,
+        )
+    }
+
+    fn burn_redeemable(&self, amount: TokenAmount) -> WowswapResultEmpty {
+        token::burn(
+            self.reserve_redeemable_mint.to_account_info(),
+            self.investor_redeemable_vault.to_account_info(),
+            self.investor.to_account_info(),
+            amount,
+            &[],
+        )
+    }
+
+    fn payout_investor_funds(&self, amount: TokenAmount) -> WowswapResultEmpty {
+        token::transfer(
+            self.reserve_lendable_vault.to_account_info(),
+            self.investor_lendable_vault.to_account_info(),
+            self.reserve_signer.clone(),
+            amount,
+            &[&self.reserve.signer_seeds((*self.reserve).as_ref().key)],
+        )
+    }
+}
+
+// Deploy, recall and write-down accrue the reserve first, so the fee and bonus windows up to now
+// are computed against the liquidity the reserve had over them
+#[derive(Accounts)]
+pub struct ReserveDeploy<'info> {
+    #[account(
+        mut,
+        constraint = reserve.signer == *reserve_signer.key,
+        constraint = *(*reserve_lendable_vault).as_ref().key == reserve.lendable_vault,
+    )]
+    reserve: Box<Account<'info, Reserve>>,
+    reserve_signer: AccountInfo<'info>,
+
+    #[account(mut)]
+    reserve_lendable_vault: Box<Account<'info, TokenAccount>>,
+    #[account(
+        mut,
+        constraint = strategy_vault.mint == reserve.lendable_mint,
+        constraint = *(*strategy_vault).as_ref().key != reserve.lendable_vault,
+    )]
+    strategy_vault: Box<Account<'info, TokenAccount>>,
+
+    #[account(constraint = *(*governance).as_ref().key == governance::ID)]
+    governance: Box<Account<'info, Governance>>,
+
+    #[account(constraint = *authority.as_ref().key == authority::ID)]
+    authority: Signer<'info>,
+
+    spl_token_program: Program<'info, SplToken>,
+}
+
+impl<'info> ReserveDeploy<'info> {
+    pub fn handle(&mut self, amount: TokenAmount) -> WowswapResultEmpty {
+        self.governance.check_initialized()?;
+        let timestamp = UnixTimestamp::now()?;
+
+        require!(
+            amount.into_inner() <= self.reserve_lendable_vault.amount,
+            WowswapError::InsufficientLiquidity
+        );
 
         let liquidity = TokenAmount::new(self.reserve_lendable_vault.amount);
-        let total_supply = TokenAmount::new(self.reserve_redeemable_mint.supply);
-        let total_debt = reserve.debt.get_total_debt(timestamp);
-        let total_liquidity = reserve.get_total_liquidity(total_debt, liquidity);
-        let mut amount_to_withdraw =
-            math::liquidity::calculate_share(amount, total_supply, total_liquidity);
+        let total_debt = self
+            .reserve
+            .debt
+            .get_total_debt(timestamp, self.governance.compound_terms());
+        self.reserve
+            .update_state(&self.governance, total_debt, liquidity, timestamp);
 
-        let burn_amount = if amount_to_withdraw > liquidity {
-            let portion = liquidity.into_wad().wad_div(amount_to_withdraw.into_wad());
-            let portion_amount = amount.into_wad().wad_mul(portion);
-            amount_to_withdraw = liquidity;
-            portion_amount.as_token_amount()
-        } else {
-            amount
-        };
+        // Deployed funds still count as liquidity, so the borrow rate doesn't change
+        self.reserve.deployed_liquidity = self
+            .reserve
+            .deployed_liquidity
+            .checked_add(amount)
+            .expect("deployed_liquidity overflow");
 
-        let governance = &self.governance;
-        reserve.update_state(governance, total_debt, timestamp);
+        self.transfer_to_strategy(amount)?;
 
-        reserve.update_borrow_rate(
-            governance,
+        Ok(())
+    }
+
+    fn transfer_to_strategy(&self, amount: TokenAmount) -> WowswapResultEmpty {
+        token::transfer(
+            self.reserve_lendable_vault.to_account_info(),
+            self.strategy_vault.to_account_info(),
+            self.reserve_signer.clone(),
+            amount,
+            &[&self.reserve.signer_seeds((*self.reserve).as_ref().key)],
+        )
+    }
+}
+
+#[derive(Accounts)]
+pub struct ReserveRecall<'info> {
+    #[account(
+        mut,
+        constraint = *(*reserve_lendable_vault).as_ref().key == reserve.lendable_vault,
+    )]
+    reserve: Box<Account<'info, Reserve>>,
+
+    #[account(mut)]
+    reserve_lendable_vault: Box<Account<'info, TokenAccount>>,
+    #[account(
+        mut,
+        constraint = strategy_vault.mint == reserve.lendable_mint,
+        constraint = strategy_vault.owner == *strategy_authority.key,
+    )]
+    strategy_vault: Box<Account<'info, TokenAccount>>,
+    strategy_authority: Signer<'info>,
+
+    #[account(constraint = *(*governance).as_ref().key == governance::ID)]
+    governance: Box<Account<'info, Governance>>,
+
+    #[account(constraint = *authority.as_ref().key == authority::ID)]
+    authority: Signer<'info>,
+
+    spl_token_program: Program<'info, SplToken>,
+}
+
+impl<'info> ReserveRecall<'info> {
+    pub fn handle(&mut self, amount: TokenAmount) -> WowswapResultEmpty {
+        self.governance.check_initialized()?;
+        let timestamp = UnixTimestamp::now()?;
+
+        let liquidity = TokenAmount::new(self.reserve_lendable_vault.amount);
+        let total_debt = self
+            .reserve
+            .debt
+            .get_total_debt(timestamp, self.governance.compound_terms());
+        self.reserve
+            .update_state(&self.governance, total_debt, liquidity, timestamp);
+
+        let strategy_yield = self.reserve.recall_deployed(&self.governance, amount);
+
+        // Only the yield is new liquidity, the principal was already counted as deployed
+        self.reserve.update_borrow_rate(
+            &self.governance,
             liquidity,
+            amount,
             TokenAmount::ZERO,
-            amount_to_withdraw,
             total_debt,
             TokenAmount::ZERO,
             TokenAmount::ZERO,
         );
 
-        Ok((burn_amount, amount_to_withdraw))
+        self.transfer_from_strategy(amount)?;
+
+        emit!(ReserveRecalled {
+            reserve: *(*self.reserve).as_ref().key,
+            amount,
+            strategy_yield,
+            deployed_liquidity: self.reserve.deployed_liquidity,
+        });
+
+        Ok(())
     }
 
-    fn burn_redeemable(&self, amount: TokenAmount) -> ProgramResult {
-        token::burn(
-            self.reserve_redeemable_mint.to_account_info(),
-            self.investor_redeemable_vault.to_account_info(),
-            self.investor.to_account_info(),
+    fn transfer_from_strategy(&self, amount: TokenAmount) -> WowswapResultEmpty {
+        token::transfer(
+            self.strategy_vault.to_account_info(),
+            self.reserve_lendable_vault.to_account_info(),
+            self.strategy_authority.to_account_info(),
             amount,
             &[],
         )
     }
+}
+
+#[event]
+pub struct ReserveRecalled {
+    pub reserve: Pubkey,
+    pub amount: TokenAmount,
+    pub strategy_yield: TokenAmount,
+    pub deployed_liquidity: TokenAmount,
+}
+
+#[event]
+pub struct ReserveDeployedWrittenDown {
+    pub reserve: Pubkey,
+    pub amount: TokenAmount,
+    pub deployed_liquidity: TokenAmount,
+}
+
+// Marks a strategy loss to market. `deployed_liquidity` only shrinks on recall otherwise, so
+// without it a lost deployment would be counted as lender liquidity forever.
+#[derive(Accounts)]
+pub struct ReserveWriteDownDeployed<'info> {
+    #[account(
+        mut,
+        constraint = *(*reserve_lendable_vault).as_ref().key == reserve.lendable_vault,
+    )]
+    reserve: Box<Account<'info, Reserve>>,
+    reserve_lendable_vault: Box<Account<'info, TokenAccount>>,
+
+    #[account(constraint = *(*governance).as_ref().key == governance::ID)]
+    governance: Box<Account<'info, Governance>>,
+
+    #[account(constraint = *authority.as_ref().key == authority::ID)]
+    authority: Signer<'info>,
+}
+
+impl<'info> ReserveWriteDownDeployed<'info> {
+    pub fn handle(&mut self, amount: TokenAmount) -> WowswapResultEmpty {
+        self.governance.check_initialized()?;
+        let timestamp = UnixTimestamp::now()?;
+
+        let liquidity = TokenAmount::new(self.reserve_lendable_vault.amount);
+        let total_debt = self
+            .reserve
+            .debt
+            .get_total_debt(timestamp, self.governance.compound_terms());
+        self.reserve
+            .update_state(&self.governance, total_debt, liquidity, timestamp);
+
+        self.reserve.write_down_deployed(amount)?;
+        self.reserve.update_borrow_rate(
+            &self.governance,
+            liquidity,
+            TokenAmount::ZERO,
+            TokenAmount::ZERO,
+            total_debt,
+            TokenAmount::ZERO,
+            TokenAmount::ZERO,
+        );
+
+        emit!(ReserveDeployedWrittenDown {
+            reserve: *(*self.reserve).as_ref().key,
+            amount,
+            deployed_liquidity: self.reserve.deployed_liquidity,
+        });
+
+        Ok(())
+    }
+}
+
+// Tops up the pool the lender bonus is paid from. Funds sit in the vault but only reach lenders
+// as the bonus accrues.
+#[derive(Accounts)]
+pub struct ReserveFundBonus<'info> {
+    #[account(
+        mut,
+        constraint = *(*reserve_lendable_vault).as_ref().key == reserve.lendable_vault,
+    )]
+    reserve: Box<Account<'info, Reserve>>,
+    #[account(mut)]
+    reserve_lendable_vault: Box<Account<'info, TokenAccount>>,
+
+    #[account(constraint = *(*governance).as_ref().key == governance::ID)]
+    governance: Box<Account<'info, Governance>>,
+
+    funder: Signer<'info>,
+    #[account(mut, constraint = bonus_pool.owner == *funder.key)]
+    bonus_pool: Box<Account<'info, TokenAccount>>,
+
+    spl_token_program: Program<'info, SplToken>,
+}
+
+impl<'info> ReserveFundBonus<'info> {
+    pub fn handle(&mut self, amount: TokenAmount) -> WowswapResultEmpty {
+        self.governance.check_initialized()?;
+        let timestamp = UnixTimestamp::now()?;
+
+        // Settle the bonus owed so far against the old pool, so new funds aren't paid out for
+        // time that has already passed
+        let liquidity = TokenAmount::new(self.reserve_lendable_vault.amount);
+        let total_debt = self
+            .reserve
+            .debt
+            .get_total_debt(timestamp, self.governance.compound_terms());
+        self.reserve
+            .update_state(&self.governance, total_debt, liquidity, timestamp);
+
+        self.reserve.lender_bonus_pool = self
+            .reserve
+            .lender_bonus_pool
+            .checked_add(amount)
+            .expect("lender bonus overflow");
+
+        self.take_bonus_funds(amount)?;
+
+        Ok(())
+    }
 
-    fn payout_investor_funds(&self, amount: TokenAmount) -> ProgramResult {
+    fn take_bonus_funds(&self, amount: TokenAmount) -> WowswapResultEmpty {
         token::transfer(
+            self.bonus_pool.to_account_info(),
             self.reserve_lendable_vault.to_account_info(),
-            self.investor_lendable_vault.to_account_info(),
-            self.reserve_signer.clone(),
+            self.funder.to_account_info(),
             amount,
-            &[&[(*self.reserve).as_ref().key.as_ref(), &[self.reserve.nonce]]],
+            &[],
         )
     }
 }
+
+// Recorded figures next to the ones recomputed from the debt, the difference is the drift
+#[event]
+pub struct ReserveReconciled {
+    pub reserve: Pubkey,
+    // Stored total and the same compounded up to `timestamp`
+    pub debt_recorded: TokenAmount,
+    pub debt_expected: TokenAmount,
+    pub treasure_expected: TokenAmount,
+    pub treasure_accrued: TokenAmount,
+    pub insurance_expected: TokenAmount,
+    pub insurance_accrued: TokenAmount,
+    pub total_liquidity: TokenAmount,
+    pub total_supply: TokenAmount,
+    pub timestamp: UnixTimestamp,
+}
+
+// Permissionless, accrues the reserve as `reserve_accrue` does and reports the drift. Fees above
+// the gross liquidity, debt included, are capped by the accrual, fees above the vault balance
+// alone are normal at high utilization and are kept.
+#[derive(Accounts)]
+pub struct ReserveReconcile<'info> {
+    #[account(
+        mut,
+        constraint = *(*reserve_lendable_vault).as_ref().key == reserve.lendable_vault,
+        constraint = *(*reserve_redeemable_mint).as_ref().key == reserve.redeemable_mint,
+    )]
+    reserve: Box<Account<'info, Reserve>>,
+
+    #[account(constraint = *(*governance).as_ref().key == governance::ID)]
+    governance: Box<Account<'info, Governance>>,
+
+    reserve_lendable_vault: Box<Account<'info, TokenAccount>>,
+    reserve_redeemable_mint: Box<Account<'info, TokenMint>>,
+}
+
+impl<'info> ReserveReconcile<'info> {
+    pub fn handle(&mut self) -> WowswapResultEmpty {
+        self.governance.check_initialized()?;
+        let timestamp = UnixTimestamp::now()?;
+
+        let reserve = &mut self.reserve;
+        let liquidity = TokenAmount::new(self.reserve_lendable_vault.amount);
+        let total_debt = reserve
+            .debt
+            .get_total_debt(timestamp, self.governance.compound_terms());
+
+        let debt_recorded = reserve.debt.total;
+        let (treasure_expected, insurance_expected) =
+            reserve.reconcile(&self.governance, total_debt, liquidity, timestamp);
+
+        emit!(ReserveReconciled {
+            reserve: *(**reserve).as_ref().key,
+            debt_recorded,
+            debt_expected: total_debt,
+            treasure_expected,
+            treasure_accrued: reserve.state.treasure_accrued,
+            insurance_expected,
+            insurance_accrued: reserve.insurance_accrued,
+            total_liquidity: reserve.get_total_liquidity(total_debt, liquidity),
+            total_supply: TokenAmount::new(self.reserve_redeemable_mint.supply),
+            timestamp,
+        });
+
+        Ok(())
+    }
+}
+
+#[event]
+pub struct ReserveAccrued {
+    pub reserve: Pubkey,
+    pub total_debt: TokenAmount,
+    pub last_update: UnixTimestamp,
+}
+
+// Permissionless, compounds the reserve debt up to now so that opens and deposits pass the
+// `max_accrual_age` check again
+#[derive(Accounts)]
+pub struct ReserveAccrue<'info> {
+    #[account(
+        mut,
+        constraint = *(*reserve_lendable_vault).as_ref().key == reserve.lendable_vault,
+    )]
+    reserve: Box<Account<'info, Reserve>>,
+
+    #[account(constraint = *(*governance).as_ref().key == governance::ID)]
+    governance: Box<Account<'info, Governance>>,
+
+    reserve_lendable_vault: Box<Account<'info, TokenAccount>>,
+}
+
+impl<'info> ReserveAccrue<'info> {
+    pub fn handle(&mut self) -> WowswapResultEmpty {
+        self.governance.check_initialized()?;
+        let timestamp = UnixTimestamp::now()?;
+
+        let reserve = &mut self.reserve;
+        let governance = &self.governance;
+        let liquidity = TokenAmount::new(self.reserve_lendable_vault.amount);
+        let total_debt = reserve
+            .debt
+            .get_total_debt(timestamp, governance.compound_terms());
+        reserve.update_state(governance, total_debt, liquidity, timestamp);
+
+        emit!(ReserveAccrued {
+            reserve: *(**reserve).as_ref().key,
+            total_debt: reserve.debt.total,
+            last_update: reserve.debt.last_update,
+        });
+
+        Ok(())
+    }
+}
+
+#[event]
+pub struct ReserveBadDebt {
+    pub reserve: Pubkey,
+    pub bad_debt: TokenAmount,
+}
+
+#[derive(Accounts)]
+pub struct ReserveBadDebtView<'info> {
+    reserve: Box<Account<'info, Reserve>>,
+}
+
+impl<'info> ReserveBadDebtView<'info> {
+    pub fn handle(&self) -> WowswapResultEmpty {
+        emit!(ReserveBadDebt {
+            reserve: *(*self.reserve).as_ref().key,
+            bad_debt: self.reserve.bad_debt,
+        });
+        Ok(())
+    }
+}
+
+#[event]
+pub struct ReserveStats {
+    pub reserve: Pubkey,
+    pub tvl: TokenAmount,
+    pub liquidity: TokenAmount,
+    pub deployed_liquidity: TokenAmount,
+    pub total_debt: TokenAmount,
+    pub bad_debt: TokenAmount,
+    pub total_supply: TokenAmount,
+    pub cumulative_borrowed: u128,
+}
+
+#[derive(Accounts)]
+pub struct ReserveStatsView<'info> {
+    #[account(
+        constraint = *(*reserve_lendable_vault).as_ref().key == reserve.lendable_vault,
+        constraint = *(*reserve_redeemable_mint).as_ref().key == reserve.redeemable_mint,
+    )]
+    reserve: Box<Account<'info, Reserve>>,
+    reserve_lendable_vault: Box<Account<'info, TokenAccount>>,
+    reserve_redeemable_mint: Box<Account<'info, TokenMint>>,
+
+    #[account(constraint = *(*governance).as_ref().key == governance::ID)]
+    governance: Box<Account<'info, Governance>>,
+}
+
+impl<'info> ReserveStatsView<'info> {
+    pub fn handle(&self) -> WowswapResultEmpty {
+        let timestamp = UnixTimestamp::now()?;
+
+        let liquidity = TokenAmount::new(self.reserve_lendable_vault.amount);
+        emit!(ReserveStats {
+            reserve: *(*self.reserve).as_ref().key,
+            tvl: self
+                .reserve
+                .tvl(liquidity, timestamp, self.governance.compound_terms()),
+            liquidity,
+            deployed_liquidity: self.reserve.deployed_liquidity,
+            total_debt: self
+                .reserve
+                .debt
+                .get_total_debt(timestamp, self.governance.compound_terms()),
+            bad_debt: self.reserve.bad_debt,
+            total_supply: TokenAmount::new(self.reserve_redeemable_mint.supply),
+            cumulative_borrowed: self.reserve.cumulative_borrowed,
+        });
+        Ok(())
+    }
+}
+
+#[event]
+pub struct GlobalDebt {
+    pub reserves: Vec<Pubkey>,
+    pub debts: Vec<TokenAmount>,
+    // Plain sum of native amounts, only meaningful across reserves of the same mint. Clients
+    // pricing each mint should use `debts`.
+    pub total_debt: u128,
+}
+
+// Read-only, reports through the `GlobalDebt` event the current total debt of each reserve in
+// `remaining_accounts`, accrued the same way the reserve accrues it. Each reserve is deserialized
+// and compounded, so at most `MAX_RESERVES` fit the default compute budget. A reserve listed twice
+// is rejected rather than counted twice.
+#[derive(Accounts)]
+pub struct GlobalDebtView<'info> {
+    #[account(constraint = *(*governance).as_ref().key == governance::ID)]
+    governance: Box<Account<'info, Governance>>,
+}
+
+impl<'info> GlobalDebtView<'info> {
+    pub const MAX_RESERVES: usize = 16;
+
+    pub fn handle(
+        &self,
+        program_id: &Pubkey,
+        remaining_accounts: &[AccountInfo<'info>],
+    ) -> WowswapResultEmpty {
+        require!(
+            remaining_accounts.len() <= Self::MAX_RESERVES,
+            WowswapError::InvalidArgument
+        );
+
+        let timestamp = UnixTimestamp::now()?;
+        let compound_terms = self.governance.compound_terms();
+
+        let mut reserves = Vec::with_capacity(remaining_accounts.len());
+        let mut debts = Vec::with_capacity(remaining_accounts.len());
+        let mut total_debt = 0u128;
+        for info in remaining_accounts {
+            require!(
+                info.owner == program_id && !reserves.contains(info.key),
+                WowswapError::InvalidArgument
+            );
+            let reserve = Reserve::try_deserialize(&mut &info.try_borrow_data()?[..])?;
+            let debt = reserve.debt.get_total_debt(timestamp, compound_terms);
+
+            total_debt = total_debt
+                .checked_add(debt.into_inner() as u128)
+                .expect("total_debt overflow");
+            reserves.push(*info.key);
+            debts.push(debt);
+        }
+
+        emit!(GlobalDebt {
+            reserves,
+            debts,
+            total_debt,
+        });
+        Ok(())
+    }
+}
+
+#[event]
+pub struct ReserveDependents {
+    pub reserve: Pubkey,
+    pub swaps: Vec<Pubkey>,
+}
+
+// Read-only, reports through the `ReserveDependents` event the swaps borrowing from the reserve
+#[derive(Accounts)]
+pub struct ReserveDependentsView<'info> {
+    reserve_swap_registry: Box<Account<'info, ReserveSwapRegistry>>,
+}
+
+impl<'info> ReserveDependentsView<'info> {
+    pub fn handle(&self) -> WowswapResultEmpty {
+        let default = Pubkey::default();
+        emit!(ReserveDependents {
+            reserve: self.reserve_swap_registry.reserve,
+            swaps: self
+                .reserve_swap_registry
+                .swaps
+                .iter()
+                .filter(|key| **key != default)
+                .copied()
+                .collect(),
+        });
+        Ok(())
+    }
+}
+
+// Swaps created against a reserve, in creation order. Kept in its own account so that `Reserve`
+// doesn't grow with the number of markets.
+#[account]
+#[derive(Debug, Default)]
+pub struct ReserveSwapRegistry {
+    pub nonce: u8,
+
+    pub reserve: Pubkey,
+
+    // Free slots hold the default key
+    pub swaps: [Pubkey; 16],
+}
+
+impl ReserveSwapRegistry {
+    // Length of `swaps`, registering more swaps on one reserve fails
+    pub const MAX_SWAPS: usize = 16;
+
+    pub fn find_address(reserve: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[b"reserve_swaps", reserve.as_ref()], &crate::ID)
+    }
+
+    pub fn add(&mut self, swap: &Pubkey) -> WowswapResultEmpty {
+        require!(!self.swaps.contains(swap), WowswapError::InvalidArgument);
+
+        let slot = self
+            .swaps
+            .iter_mut()
+            .find(|v| **v == Pubkey::default())
+            .ok_or(WowswapError::TooManyDependents)?;
+        *slot = *swap;
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(nonce: u8)]
+pub struct ReserveSwapRegistryInitialize<'info> {
+    #[account(
+        init,
+        seeds = [b"reserve_swaps".as_ref(), (*reserve).as_ref().key.as_ref()],
+        bump = nonce,
+        payer = payer,
+        space = 1024, // Current size is 553
+    )]
+    reserve_swap_registry: Box<Account<'info, ReserveSwapRegistry>>,
+
+    reserve: Box<Account<'info, Reserve>>,
+
+    #[account(mut)]
+    payer: Signer<'info>,
+
+    system_program: Program<'info, System>, // Required because the registry is `init` with `seeds`
+}
+
+impl<'info> ReserveSwapRegistryInitialize<'info> {
+    pub fn handle(&mut self, nonce: u8) -> WowswapResultEmpty {
+        let (_, canonical_nonce) = ReserveSwapRegistry::find_address((*self.reserve).as_ref().key);
+        require!(nonce == canonical_nonce, WowswapError::InvalidNonce);
+
+        self.reserve_swap_registry.nonce = nonce;
+        self.reserve_swap_registry.reserve = *(*self.reserve).as_ref().key;
+
+        Ok(())
+    }
+}
+
+// Records a swap created before the registry existed, new swaps are registered by
+// `swap_initialize`
+#[derive(Accounts)]
+pub struct ReserveRegisterSwap<'info> {
+    #[account(
+        mut,
+        seeds = [b"reserve_swaps".as_ref(), swap.reserve.as_ref()],
+        bump = reserve_swap_registry.nonce,
+    )]
+    reserve_swap_registry: Box<Account<'info, ReserveSwapRegistry>>,
+
+    swap: Box<Account<'info, Swap>>,
+
+    #[account(constraint = *authority.as_ref().key == authority::ID)]
+    authority: Signer<'info>,
+}
+
+impl<'info> ReserveRegisterSwap<'info> {
+    pub fn handle(&mut self) -> WowswapResultEmpty {
+        self.reserve_swap_registry.add((*self.swap).as_ref().key)
+    }
+}
+
+#[event]
+pub struct ReserveProjectedExchangeRate {
+    pub reserve: Pubkey,
+    pub horizon: u64,
+    // Both scaled by 1e9
+    pub exchange_rate: u128,
+    pub projected_exchange_rate: u128,
+}
+
+// Read-only, reports through the `ReserveProjectedExchangeRate` event the exchange rate
+// `horizon` seconds from now
+#[derive(Accounts)]
+pub struct ReserveProjectedExchangeRateView<'info> {
+    #[account(
+        constraint = *(*reserve_lendable_vault).as_ref().key == reserve.lendable_vault,
+        constraint = *(*reserve_redeemable_mint).as_ref().key == reserve.redeemable_mint,
+    )]
+    reserve: Box<Account<'info, Reserve>>,
+    reserve_lendable_vault: Box<Account<'info, TokenAccount>>,
+    reserve_redeemable_mint: Box<Account<'info, TokenMint>>,
+
+    #[account(constraint = *(*governance).as_ref().key == governance::ID)]
+    governance: Box<Account<'info, Governance>>,
+}
+
+impl<'info> ReserveProjectedExchangeRateView<'info> {
+    pub fn handle(&self, horizon: u64) -> WowswapResultEmpty {
+        let timestamp = UnixTimestamp::now()?;
+
+        let liquidity = TokenAmount::new(self.reserve_lendable_vault.amount);
+        let total_supply = TokenAmount::new(self.reserve_redeemable_mint.supply);
+        // Accrued to now the same way, so that both rates are comparable
+        let exchange_rate = self.reserve.projected_exchange_rate(
+            &self.governance,
+            0,
+            timestamp,
+            liquidity,
+            total_supply,
+        );
+        let projected_exchange_rate = self.reserve.projected_exchange_rate(
+            &self.governance,
+            horizon,
+            timestamp,
+            liquidity,
+            total_supply,
+        );
+
+        emit!(ReserveProjectedExchangeRate {
+            reserve: *(*self.reserve).as_ref().key,
+            horizon,
+            exchange_rate: exchange_rate.into_inner(),
+            projected_exchange_rate: projected_exchange_rate.into_inner(),
+        });
+        Ok(())
+    }
+}
+
+#[event]
+pub struct ReserveBreakEvenUtilization {
+    pub reserve: Pubkey,
+    pub target_lender_apr_bps: u64,
+    pub reachable: bool,
+    // Scaled by 1e18, zero when not reachable
+    pub utilization: u128,
+}
+
+// Read-only, reports through the `ReserveBreakEvenUtilization` event the utilization at which
+// lenders of the reserve earn `target_lender_apr_bps` under the current curve
+#[derive(Accounts)]
+pub struct ReserveBreakEvenUtilizationView<'info> {
+    reserve: Box<Account<'info, Reserve>>,
+
+    #[account(constraint = *(*governance).as_ref().key == governance::ID)]
+    governance: Box<Account<'info, Governance>>,
+}
+
+impl<'info> ReserveBreakEvenUtilizationView<'info> {
+    pub fn handle(&self, target_lender_apr_bps: u64) -> WowswapResultEmpty {
+        let utilization = Reserve::break_even_utilization(&self.governance, target_lender_apr_bps);
+
+        emit!(ReserveBreakEvenUtilization {
+            reserve: *(*self.reserve).as_ref().key,
+            target_lender_apr_bps,
+            reachable: utilization.is_some(),
+            utilization: utilization.map_or(0, Ray::into_inner),
+        });
+        Ok(())
+    }
+}
+
+#[event]
+pub struct ReserveCurveSimulation {
+    // Borrow rates at each utilization point, raw `Rate` values
+    pub current_borrow_rates: Vec<u128>,
+    pub proposed_borrow_rates: Vec<u128>,
+}
+
+// Read-only, reports through the `ReserveCurveSimulation` event the borrow rate at each of
+// `utilizations` (scaled by 1e18) under the current governance and under `proposed`. Only the
+// rate curve parameters of `proposed` are used.
+#[derive(Accounts)]
+pub struct ReserveSimulateCurve<'info> {
+    #[account(constraint = *(*governance).as_ref().key == governance::ID)]
+    governance: Box<Account<'info, Governance>>,
+}
+
+impl<'info> ReserveSimulateCurve<'info> {
+    pub const MAX_POINTS: usize = 32;
+
+    pub fn handle(&self, proposed: Governance, utilizations: Vec<u128>) -> WowswapResultEmpty {
+        require!(
+            utilizations.len() <= Self::MAX_POINTS
+                && utilizations.iter().all(|v| *v <= Ray::ONE.into_inner()),
+            WowswapError::InvalidArgument
+        );
+
+        let rates = |governance: &Governance| {
+            utilizations
+                .iter()
+                .map(|utilization| {
+                    math::interest::borrow_rate_at(
+                        Ray::new(*utilization),
+                        governance.base_borrow_rate(),
+                        governance.excess_slope(),
+                        governance.optimal_slope(),
+                        governance.optimal_utilization(),
+                        governance.max_borrow_rate(),
+                    )
+                    .into_inner()
+                })
+                .collect()
+        };
+
+        emit!(ReserveCurveSimulation {
+            current_borrow_rates: rates(&self.governance),
+            proposed_borrow_rates: rates(&proposed),
+        });
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, AnchorSerialize, AnchorDeserialize)]
+pub struct ExchangeRateSnapshot {
+    pub timestamp: UnixTimestamp,
+    // Scaled by 1e9
+    pub exchange_rate: u128,
+}
+
+// Exchange rates of a reserve over time, so a front-end can chart realized yield without indexing
+// events. Once full the oldest snapshot is overwritten.
+#[account]
+#[derive(Debug, Default)]
+pub struct ReserveHistory {
+    pub nonce: u8,
+
+    pub reserve: Pubkey,
+
+    // Slot the next snapshot is written to
+    pub head: u8,
+    pub len: u8,
+    pub snapshots: [ExchangeRateSnapshot; 32],
+}
+
+impl ReserveHistory {
+    pub const MAX_SNAPSHOTS: usize = 32;
+
+    pub fn find_address(reserve: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[b"reserve_history", reserve.as_ref()], &crate::ID)
+    }
+
+    pub fn last(&self) -> Option<&ExchangeRateSnapshot> {
+        if self.len == 0 {
+            None
+        } else {
+            let index = (self.head as usize + Self::MAX_SNAPSHOTS - 1) % Self::MAX_SNAPSHOTS;
+            Some(&self.snapshots[index])
+        }
+    }
+
+    pub fn push(&mut self, snapshot: ExchangeRateSnapshot) {
+        self.snapshots[self.head as usize] = snapshot;
+        self.head = ((self.head as usize + 1) % Self::MAX_SNAPSHOTS) as u8;
+        if (self.len as usize) < Self::MAX_SNAPSHOTS {
+            self.len += 1;
+        }
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(nonce: u8)]
+pub struct ReserveHistoryInitialize<'info> {
+    #[account(
+        init,
+        seeds = [b"reserve_history".as_ref(), (*reserve).as_ref().key.as_ref()],
+        bump = nonce,
+        payer = payer,
+        space = 1024, // Current size is 811
+    )]
+    reserve_history: Box<Account<'info, ReserveHistory>>,
+
+    reserve: Box<Account<'info, Reserve>>,
+
+    #[account(mut)]
+    payer: Signer<'info>,
+
+    system_program: Program<'info, System>, // Required because `reserve_history` is `init` with `seeds`
+}
+
+impl<'info> ReserveHistoryInitialize<'info> {
+    pub fn handle(&mut self, nonce: u8) -> WowswapResultEmpty {
+        let (_, canonical_nonce) = ReserveHistory::find_address((*self.reserve).as_ref().key);
+        require!(nonce == canonical_nonce, WowswapError::InvalidNonce);
+
+        self.reserve_history.nonce = nonce;
+        self.reserve_history.reserve = *(*self.reserve).as_ref().key;
+
+        Ok(())
+    }
+}
+
+// Permissionless, appends the current exchange rate to the reserve history at most once per
+// `reserve_snapshot_interval`
+#[derive(Accounts)]
+pub struct ReserveTakeSnapshot<'info> {
+    #[account(
+        mut,
+        has_one = reserve,
+        seeds = [b"reserve_history".as_ref(), (*reserve).as_ref().key.as_ref()],
+        bump = reserve_history.nonce,
+    )]
+    reserve_history: Box<Account<'info, ReserveHistory>>,
+
+    #[account(
+        constraint = *(*reserve_lendable_vault).as_ref().key == reserve.lendable_vault,
+        constraint = *(*reserve_redeemable_mint).as_ref().key == reserve.redeemable_mint,
+    )]
+    reserve: Box<Account<'info, Reserve>>,
+    reserve_lendable_vault: Box<Account<'info, TokenAccount>>,
+    reserve_redeemable_mint: Box<Account<'info, TokenMint>>,
+
+    #[account(constraint = *(*governance).as_ref().key == governance::ID)]
+    governance: Box<Account<'info, Governance>>,
+}
+
+impl<'info> ReserveTakeSnapshot<'info> {
+    pub fn handle(&mut self) -> WowswapResultEmpty {
+        self.governance.check_initialized()?;
+        let timestamp = UnixTimestamp::now()?;
+
+        if let Some(last) = self.reserve_history.last() {
+            let interval = self.governance.reserve_snapshot_interval();
+            require!(
+                last.timestamp
+                    .checked_add(interval)
+                    .map_or(false, |v| timestamp >= v && timestamp > last.timestamp),
+                WowswapError::SnapshotTooEarly
+            );
+        }
+
+        // Accrued to now, as the stored state is only as recent as the last update
+        let exchange_rate = self.reserve.projected_exchange_rate(
+            &self.governance,
+            0,
+            timestamp,
+            TokenAmount::new(self.reserve_lendable_vault.amount),
+            TokenAmount::new(self.reserve_redeemable_mint.supply),
+        );
+        self.reserve_history.push(ExchangeRateSnapshot {
+            timestamp,
+            exchange_rate: exchange_rate.into_inner(),
+        });
+
+        Ok(())
+    }
+}
+
+#[event]
+pub struct BadDebtCovered {
+    pub reserve: Pubkey,
+    pub amount: TokenAmount,
+    pub bad_debt: TokenAmount,
+}
+
+#[derive(Accounts)]
+pub struct ReserveCoverBadDebt<'info> {
+    #[account(
+        mut,
+        constraint = *(*reserve_lendable_vault).as_ref().key == reserve.lendable_vault,
+    )]
+    reserve: Box<Account<'info, Reserve>>,
+    #[account(mut)]
+    reserve_lendable_vault: Box<Account<'info, TokenAccount>>,
+
+    #[account(constraint = *(*governance).as_ref().key == governance::ID)]
+    governance: Box<Account<'info, Governance>>,
+
+    funder: Signer<'info>,
+    #[account(mut, constraint = funder_vault.owner == *funder.key)]
+    funder_vault: Box<Account<'info, TokenAccount>>,
+
+    spl_token_program: Program<'info, SplToken>,
+}
+
+impl<'info> ReserveCoverBadDebt<'info> {
+    pub fn handle(&mut self, amount: TokenAmount) -> WowswapResultEmpty {
+        // Partial covers are fine, anything above the outstanding bad debt is not taken
+        let amount = std::cmp::min(amount, self.reserve.bad_debt);
+        require!(!amount.is_zero(), WowswapError::InvalidArgument);
+        self.governance.check_initialized()?;
+
+        self.reserve_update_state(amount)?;
+        self.take_funder_funds(amount)?;
+
+        emit!(BadDebtCovered {
+            reserve: *(*self.reserve).as_ref().key,
+            amount,
+            bad_debt: self.reserve.bad_debt,
+        });
+
+        Ok(())
+    }
+
+    fn reserve_update_state(&mut self, amount: TokenAmount) -> WowswapResultEmpty {
+        let timestamp = UnixTimestamp::now()?;
+
+        let reserve = &mut self.reserve;
+        let governance = &self.governance;
+        let total_debt = reserve
+            .debt
+            .get_total_debt(timestamp, governance.compound_terms());
+        let liquidity = TokenAmount::new(self.reserve_lendable_vault.amount);
+        reserve.update_state(governance, total_debt, liquidity, timestamp);
+
+        reserve.update_borrow_rate(
+            governance,
+            liquidity,
+            amount,
+            TokenAmount::ZERO,
+            total_debt,
+            TokenAmount::ZERO,
+            TokenAmount::ZERO,
+        );
+
+        reserve.bad_debt = reserve.bad_debt.safe_sub(amount);
+
+        Ok(())
+    }
+
+    fn take_funder_funds(&self, amount: TokenAmount) -> WowswapResultEmpty {
+        token::transfer(
+            self.funder_vault.to_account_info(),
+            self.reserve_lendable_vault.to_account_info(),
+            self.funder.to_account_info(),
+            amount,
+            &[],
+        )
+    }
+}
+
+#[derive(Accounts)]
+pub struct ReserveSetAllowlist<'info> {
+    #[account(mut)]
+    reserve: Box<Account<'info, Reserve>>,
+
+    #[account(constraint = *authority.as_ref().key == authority::ID)]
+    authority: Signer<'info>,
+}
+
+impl<'info> ReserveSetAllowlist<'info> {
+    pub fn handle(&mut self, allowlist: Vec<Pubkey>) -> WowswapResultEmpty {
+        require!(
+            allowlist.len() <= Reserve::MAX_ALLOWED_BORROWERS,
+            WowswapError::InvalidArgument
+        );
+
+        let mut borrower_allowlist = [Pubkey::default(); Reserve::MAX_ALLOWED_BORROWERS];
+        borrower_allowlist[..allowlist.len()].copy_from_slice(&allowlist);
+        self.reserve.borrower_allowlist = borrower_allowlist;
+
+        Ok(())
+    }
+}
+
+// Only meant for emergencies, borrows and the rest of the reserve are not affected
+#[derive(Accounts)]
+pub struct ReserveSetWithdrawalsPaused<'info> {
+    #[account(mut)]
+    reserve: Box<Account<'info, Reserve>>,
+
+    #[account(constraint = *authority.as_ref().key == authority::ID)]
+    authority: Signer<'info>,
+}
+
+impl<'info> ReserveSetWithdrawalsPaused<'info> {
+    pub fn handle(&mut self, paused: bool) -> WowswapResultEmpty {
+        self.reserve.withdrawals_paused = paused;
+        Ok(())
+    }
+}
+
+// Core reserve math on plain data, shared by the handlers and off-chain simulations so both
+// produce identical results.
+pub mod sim {
+    use super::{
+        math, Factor, Governance, Rate, Ray, Reserve, ReserveDebt, SwapPositionState, TokenAmount,
+        UnixTimestamp, WowswapError, WowswapResult, WowswapResultEmpty,
+    };
+
+    // Returns the amount of redeemable tokens to mint for `amount` deposited
+    pub fn deposit(
+        reserve: &mut Reserve,
+        governance: &Governance,
+        liquidity: TokenAmount,
+        total_supply: TokenAmount,
+        amount: TokenAmount,
+        timestamp: UnixTimestamp,
+    ) -> WowswapResult<TokenAmount> {
+        reserve.check_accrual_age(governance, timestamp)?;
+
+        let total_debt = reserve
+            .debt
+            .get_total_debt(timestamp, governance.compound_terms());
+        reserve.update_state(governance, total_debt, liquidity, timestamp);
+
+        reserve.update_borrow_rate(
+            governance,
+            liquidity,
+            amount,
+            TokenAmount::ZERO,
+            total_debt,
+            TokenAmount::ZERO,
+            TokenAmount::ZERO,
+        );
+
+        let total_liquidity = reserve.get_total_liquidity(total_debt, liquidity);
+        let mint_amount = reserve
+            .preview_deposit(amount, total_supply, total_liquidity)
+            .ok_or(WowswapError::InvalidArgument)?;
+
+        Ok(mint_amount)
+    }
+
+    // Returns amounts of redeemable tokens to burn and lendable tokens to pay out for `amount`
+    // redeemed, the payout is limited by available liquidity
+    pub fn withdraw(
+        reserve: &mut Reserve,
+        governance: &Governance,
+        liquidity: TokenAmount,
+        total_supply: TokenAmount,
+        amount: TokenAmount,
+        timestamp: UnixTimestamp,
+    ) -> WowswapResult<(TokenAmount, TokenAmount)> {
+        let total_debt = reserve
+            .debt
+            .get_total_debt(timestamp, governance.compound_terms());
+        let total_liquidity = reserve.get_total_liquidity(total_debt, liquidity);
+        let mut amount_to_withdraw =
+            math::liquidity::calculate_share(amount, total_supply, total_liquidity);
+
+        // Keep enough liquidity in the vault for open positions to be closed and liquidated
+        let available_liquidity = if total_debt.is_zero() {
+            liquidity
+        } else {
+            let floor = TokenAmount::from_u128(
+                governance
+                    .reserve_liquidity_floor()
+                    .percentage_mul(total_liquidity.into_inner() as u128),
+            );
+            liquidity.checked_sub(floor).unwrap_or(TokenAmount::ZERO)
+        };
+
+        let burn_amount = if amount_to_withdraw > available_liquidity {
+            let portion = available_liquidity
+                .into_wad()
+                .checked_wad_div(amount_to_withdraw.into_wad())
+                .ok_or(WowswapError::InvalidArgument)?;
+            let portion_amount = amount.into_wad().wad_mul(portion);
+            amount_to_withdraw = available_liquidity;
+            portion_amount.as_token_amount()
+        } else {
+            amount
+        };
+
+        reserve.update_state(governance, total_debt, liquidity, timestamp);
+
+        reserve.update_borrow_rate(
+            governance,
+            liquidity,
+            TokenAmount::ZERO,
+            amount_to_withdraw,
+            total_debt,
+            TokenAmount::ZERO,
+            TokenAmount::ZERO,
+        );
+
+        Ok((burn_amount, amount_to_withdraw))
+    }
+
+    // Books a loan of `amount` to `position`, as opens do. `total_debt` and `liquidity` are from
+    // before the loan leaves the vault.
+    #[allow(clippy::too_many_arguments)]
+    pub fn borrow(
+        reserve: &mut Reserve,
+        governance: &Governance,
+        position: &mut SwapPositionState,
+        total_debt: TokenAmount,
+        liquidity: TokenAmount,
+        amount: TokenAmount,
+        rate_multiplier: Factor,
+        timestamp: UnixTimestamp,
+    ) -> WowswapResultEmpty {
+        reserve.update_state(governance, total_debt, liquidity, timestamp);
+
+        reserve.update_borrow_rate(
+            governance,
+            liquidity,
+            TokenAmount::ZERO,
+            amount,
+            total_debt,
+            amount,
+            TokenAmount::ZERO,
+        );
+
+        reserve.increase_debt(
+            position,
+            timestamp,
+            total_debt,
+            amount,
+            rate_multiplier,
+            governance.compound_terms(),
+        )
+    }
+
+    // Books `debt_change` of the debt of `position` as repaid or written off, as closes,
+    // liquidations and force closes do. `liquidity` is the vault balance before
+    // `liquidity_returned` is paid into it.
+    #[allow(clippy::too_many_arguments)]
+    pub fn repay(
+        reserve: &mut Reserve,
+        governance: &Governance,
+        position: &mut SwapPositionState,
+        liquidity: TokenAmount,
+        debt_change: TokenAmount,
+        liquidity_returned: TokenAmount,
+        grace: UnixTimestamp,
+        timestamp: UnixTimestamp,
+    ) {
+        let total_debt = reserve
+            .debt
+            .get_total_debt(timestamp, governance.compound_terms());
+        reserve.update_state(governance, total_debt, liquidity, timestamp);
+
+        reserve.decrease_debt(
+            position,
+            timestamp,
+            grace,
+            total_debt,
+            debt_change,
+            governance.compound_terms(),
+        );
+
+        let total_debt = reserve
+            .debt
+            .get_total_debt(timestamp, governance.compound_terms());
+        reserve.update_borrow_rate(
+            governance,
+            liquidity,
+            liquidity_returned,
+            TokenAmount::ZERO,
+            total_debt,
+            TokenAmount::ZERO,
+            TokenAmount::ZERO,
+        );
+    }
+
+    pub fn increase_debt(
+        reserve_debt: &mut ReserveDebt,
+        borrow_rate: Rate,
+        position: &mut SwapPositionState,
+        timestamp: UnixTimestamp,
+        previous_total: TokenAmount,
+        amount: TokenAmount,
+        rate_multiplier: Factor,
+        compound_terms: u8,
+    ) -> WowswapResultEmpty {
+        let rate = Rate::new(rate_multiplier.percentage_mul(borrow_rate.into_inner()));
+        let amount_ray_rate = amount
+            .checked_into_ray()
+            .and_then(|v| v.checked_ray_mul(rate.into_ray()))
+            .ok_or(WowswapError::InvalidArgument)?;
+
+        // No grace on top ups, otherwise a position could roll its interest forever
+        let (current_debt, debt_increase) =
+            position.calculate_debt_increase(timestamp, UnixTimestamp::ZERO, compound_terms);
+        let debt = current_debt.checked_add(amount).expect("debt overflow");
+        let next_total = previous_total
+            .checked_add(amount)
+            .expect("total debt overflow");
+
+        // Both rates are computed before anything is updated, so an overflow leaves the debt as is
+        let position_rate = average_rate(position.rate, current_debt, amount_ray_rate, debt)
+            .ok_or(WowswapError::InvalidArgument)?;
+        let reserve_rate = average_rate(
+            reserve_debt.average_rate,
+            previous_total,
+            amount_ray_rate,
+            next_total,
+        )
+        .ok_or(WowswapError::InvalidArgument)?;
+
+        // Update user debt
+        position.amount = position
+            .amount
+            .checked_add(amount)
+            .and_then(|v| v.checked_add(debt_increase))
+            .expect("amount overflow");
+        position.rate = position_rate;
+        position.timestamp = timestamp;
+
+        reserve_debt.total = next_total;
+        reserve_debt.average_rate = reserve_rate;
+        reserve_debt.last_update = timestamp;
+
+        Ok(())
+    }
+
+    // (rate * amount + amount_ray_rate) / total, where `amount_ray_rate` is the rate of the added
+    // amount already multiplied by it. `None` on overflow, only reachable with high decimal mints.
+    fn average_rate(
+        rate: Rate,
+        amount: TokenAmount,
+        amount_ray_rate: Ray,
+        total: TokenAmount,
+    ) -> Option<Rate> {
+        rate.into_ray()
+            .checked_ray_mul(amount.checked_into_ray()?)?
+            .checked_add(amount_ray_rate)?
+            .checked_ray_div(total.checked_into_ray()?)
+            .map(Ray::as_rate)
+    }
+
+    // `grace` has to match the one the caller used to compute `debt_change`, and is only nonzero
+    // when the position is repaid in full. The reserve total keeps accruing at the average rate
+    // through the grace period, so the interest waived for the position is removed from the total
+    // along with the repayment, as if it had been paid.
+    pub fn decrease_debt(
+        reserve_debt: &mut ReserveDebt,
+        position: &mut SwapPositionState,
+        timestamp: UnixTimestamp,
+        grace: UnixTimestamp,
+        reserve_total_debt: TokenAmount,
+        debt_change: TokenAmount,
+        compound_terms: u8,
+    ) {
+        let (current_debt, debt_increase) =
+            position.calculate_debt_increase(timestamp, grace, compound_terms);
+        let waived = if grace.is_zero() {
+            TokenAmount::ZERO
+        } else {
+            let (accrued_debt, _) =
+                position.calculate_debt_increase(timestamp, UnixTimestamp::ZERO, compound_terms);
+            accrued_debt
+                .checked_sub(current_debt)
+                .unwrap_or(TokenAmount::ZERO)
+        };
+        let reserve_debt_change = debt_change
+            .checked_add(waived)
+            .expect("debt_change overflow");
+
+        // Since the total debt and each individual user's debts are accrued separately, due to an
+        // accumulation error the last borrower to repay loan may try to repay more than the total
+        // debt outstanding.
+        // In this case when the last borrower repays the debt, we simply set the total outstanding
+        // debt and the average stable rate to 0.
+        if reserve_total_debt <= reserve_debt_change {
+            reserve_debt.average_rate = Rate::ZERO;
+            reserve_debt.total = TokenAmount::ZERO;
+        } else {
+            let next_total = reserve_total_debt
+                .checked_sub(reserve_debt_change)
+                .expect("total debt overflow");
+            reserve_debt.total = next_total;
+
+            // For the reason described above, when the last user repays the debt, it might happen
+            // that user's rate * user's balance > avg rate * total debt. In that case, we simply
+            // set the avg rate to 0
+            let first_term = reserve_debt
+                .average_rate
+                .into_ray()
+                .ray_mul(reserve_total_debt.into_wad().into_ray());
+            let second_term = position
+                .rate
+                .into_ray()
+                .ray_mul(reserve_debt_change.into_wad().into_ray());
+
+            if second_term >= first_term {
+                reserve_debt.average_rate = Rate::ZERO;
+                reserve_debt.total = TokenAmount::ZERO;
+            } else {
+                reserve_debt.average_rate = first_term
+                    .checked_sub(second_term)
+                    .expect("rate overflow")
+                    .ray_div(next_total.into_wad().into_ray())
+                    .as_rate();
+            }
+        }
+
+        if debt_change == current_debt {
+            position.rate = Rate::ZERO;
+            position.amount = TokenAmount::ZERO;
+            position.timestamp = UnixTimestamp::ZERO;
+        } else {
+            position.amount = position
+                .amount
+                .checked_add(debt_increase)
+                .and_then(|v| v.checked_sub(debt_change))
+                .expect("amount overflow");
+            position.timestamp = timestamp;
+        }
+
+        reserve_debt.last_update = timestamp;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::swap::SwapPosition;
+
+    const NOW: UnixTimestamp = UnixTimestamp::new(1_600_000_000);
+
+    // Governance accounts hold Rays and 1e18-scaled Factors
+    const RAY: u128 = 1_000_000_000_000_000_000;
+
+    fn sample_governance() -> Governance {
+        Governance {
+            optimal_utilization: RAY * 8 / 10,
+            optimal_slope: RAY / 10,
+            excess_slope: RAY,
+            max_leverage_factor: 50_000 * RAY,
+            pool_utilization_allowance: 5_000 * RAY,
+            ..Governance::default()
+        }
+    }
+
+    fn after(seconds: u64) -> UnixTimestamp {
+        NOW.checked_add(UnixTimestamp::new(seconds)).unwrap()
+    }
+
+    fn reserve_with_debt(total_debt: u64) -> Reserve {
+        Reserve {
+            state: ReserveState {
+                treasurer_update: NOW,
+                ..ReserveState::default()
+            },
+            debt: ReserveDebt {
+                average_rate: Rate::ZERO,
+                total: TokenAmount::new(total_debt),
+                last_update: NOW,
+            },
+            ..Reserve::default()
+        }
+    }
+
+    #[test]
+    fn signer_nonce_must_be_canonical() {
+        let reserve = Pubkey::new_unique();
+        let (signer, canonical) = Reserve::find_signer_address(&reserve);
+        Reserve::check_signer_nonce(&reserve, canonical).unwrap();
+
+        // A lower bump that is off the curve derives another signer for the same reserve
+        let (other, other_signer) = (0..canonical)
+            .rev()
+            .find_map(|nonce| {
+                Pubkey::create_program_address(&[reserve.as_ref(), &[nonce]], &crate::ID)
+                    .ok()
+                    .map(|signer| (nonce, signer))
+            })
+            .unwrap();
+        assert_ne!(other_signer, signer);
+        assert!(Reserve::check_signer_nonce(&reserve, other).is_err());
+    }
+
+    #[test]
+    fn update_state_keeps_fees_above_vault_balance() {
+        let governance = Governance::default();
+        let mut reserve = reserve_with_debt(900);
+        reserve.state.treasure_accrued = TokenAmount::new(150);
+        reserve.insurance_accrued = TokenAmount::new(20);
+
+        // Most of the liquidity is on loan, the vault holds less than the accrued fees
+        let liquidity = TokenAmount::new(100);
+        reserve.update_state(&governance, TokenAmount::new(900), liquidity, NOW);
+
+        assert_eq!(reserve.state.treasure_accrued, TokenAmount::new(150));
+        assert_eq!(reserve.insurance_accrued, TokenAmount::new(20));
+        assert_eq!(
+            reserve.get_total_liquidity(TokenAmount::new(900), liquidity),
+            TokenAmount::new(830)
+        );
+    }
+
+    #[test]
+    fn update_state_caps_drift_at_gross_liquidity() {
+        let governance = Governance::default();
+        let mut reserve = reserve_with_debt(900);
+        reserve.state.treasure_accrued = TokenAmount::new(1_050);
+        reserve.insurance_accrued = TokenAmount::new(20);
+
+        let liquidity = TokenAmount::new(100);
+        reserve.update_state(&governance, TokenAmount::new(900), liquidity, NOW);
+
+        assert_eq!(reserve.state.treasure_accrued, TokenAmount::new(1_000));
+        assert_eq!(reserve.insurance_accrued, TokenAmount::ZERO);
+        assert_eq!(
+            reserve.get_total_liquidity(TokenAmount::new(900), liquidity),
+            TokenAmount::ZERO
+        );
+    }
+
+    #[test]
+    fn reconcile_reports_the_capped_drift() {
+        let governance = Governance {
+            treasure_factor: 1_000 * RAY,
+            ..sample_governance()
+        };
+        let liquidity = TokenAmount::new(100);
+
+        // Nothing to cap, the fees are as recorded
+        let mut reserve = reserve_with_debt(900);
+        reserve.state.treasure_accrued = TokenAmount::new(150);
+        assert_eq!(
+            reserve.reconcile(&governance, TokenAmount::new(900), liquidity, NOW),
+            (TokenAmount::new(150), TokenAmount::ZERO)
+        );
+        assert_eq!(reserve.state.treasure_accrued, TokenAmount::new(150));
+
+        // Fees drifted above the 1_000 the reserve holds, debt included
+        let mut reserve = reserve_with_debt(900);
+        reserve.state.treasure_accrued = TokenAmount::new(1_050);
+        reserve.insurance_accrued = TokenAmount::new(20);
+        assert_eq!(
+            reserve.reconcile(&governance, TokenAmount::new(900), liquidity, NOW),
+            (TokenAmount::new(1_050), TokenAmount::new(20))
+        );
+        assert_eq!(reserve.state.treasure_accrued, TokenAmount::new(1_000));
+        assert_eq!(reserve.insurance_accrued, TokenAmount::ZERO);
+
+        // Debt accrued since the last update is included in the expected fees
+        let mut reserve = reserve_with_debt(900);
+        reserve.debt.total = TokenAmount::new(800);
+        reserve.debt.last_update = NOW;
+        let (treasure_expected, _) =
+            reserve.reconcile(&governance, TokenAmount::new(900), liquidity, NOW);
+        assert_eq!(treasure_expected, TokenAmount::new(10));
+        assert_eq!(reserve.state.treasure_accrued, TokenAmount::new(10));
+    }
+
+    fn bonus_governance() -> Governance {
+        Governance {
+            lender_bonus_rate: Rate::from_apr_bps(10_000).into_inner(),
+            ..sample_governance()
+        }
+    }
+
+    #[test]
+    fn no_lender_bonus_below_optimal_utilization() {
+        let governance = bonus_governance();
+        let mut reserve = reserve_with_debt(500_000_000);
+        reserve.lender_bonus_pool = TokenAmount::new(100_000_000);
+
+        // The vault holds the pool, utilization is 500 / 1100
+        let total_debt = TokenAmount::new(500_000_000);
+        let liquidity = TokenAmount::new(600_000_000);
+        assert_eq!(
+            reserve.get_total_liquidity(total_debt, liquidity),
+            TokenAmount::new(1_000_000_000)
+        );
+
+        reserve.update_state(&governance, total_debt, liquidity, after(86_400));
+
+        assert_eq!(reserve.lender_bonus_pool, TokenAmount::new(100_000_000));
+        assert_eq!(
+            reserve.get_total_liquidity(total_debt, liquidity),
+            TokenAmount::new(1_000_000_000)
+        );
+    }
+
+    #[test]
+    fn lender_bonus_above_optimal_utilization_is_paid_from_the_pool() {
+        let governance = bonus_governance();
+        let total_debt = TokenAmount::new(900_000_000);
+        let liquidity = TokenAmount::new(100_000_000);
+
+        // Utilization is 0.9, half way from optimal to full. Lender liquidity excludes the pool.
+        let mut reserve = reserve_with_debt(900_000_000);
+        reserve.lender_bonus_pool = TokenAmount::new(50_000_000);
+        reserve.update_state(&governance, total_debt, liquidity, after(86_400));
+
+        // 950M at half of a 100% APR for a day
+        let bonus = 50_000_000 - reserve.lender_bonus_pool.into_inner();
+        assert!(bonus > 1_301_000 && bonus < 1_302_000);
+        assert_eq!(
+            reserve.get_total_liquidity(total_debt, liquidity),
+            TokenAmount::new(950_000_000 + bonus)
+        );
+
+        // Over a year the bonus would exceed the pool, lenders get what was funded and no more
+        let mut reserve = reserve_with_debt(900_000_000);
+        reserve.lender_bonus_pool = TokenAmount::new(50_000_000);
+        reserve.update_state(&governance, total_debt, liquidity, after(31_536_000));
+
+        assert!(reserve.lender_bonus_pool.is_zero());
+        assert_eq!(
+            reserve.get_total_liquidity(total_debt, liquidity),
+            TokenAmount::new(1_000_000_000)
+        );
+
+        // An unfunded bonus adds nothing
+        let mut reserve = reserve_with_debt(900_000_000);
+        reserve.update_state(&governance, total_debt, liquidity, after(31_536_000));
+
+        assert_eq!(
+            reserve.get_total_liquidity(total_debt, liquidity),
+            TokenAmount::new(1_000_000_000)
+        );
+    }
+
+    #[test]
+    fn accrual_age_threshold() {
+        let governance = Governance {
+            max_accrual_age: 3_600,
+            ..sample_governance()
+        };
+        let reserve = reserve_with_debt(1_000);
+
+        assert!(reserve.check_accrual_age(&governance, after(3_599)).is_ok());
+        assert!(reserve.check_accrual_age(&governance, after(3_600)).is_ok());
+        assert!(reserve
+            .check_accrual_age(&governance, after(3_601))
+            .is_err());
+
+        // Nothing to compound without debt, and zero disables the check
+        assert!(reserve_with_debt(0)
+            .check_accrual_age(&governance, after(3_601))
+            .is_ok());
+        assert!(reserve
+            .check_accrual_age(&sample_governance(), after(3_601))
+            .is_ok());
+    }
+
+    #[test]
+    fn stale_reserve_blocks_deposits_but_not_withdrawals() {
+        let governance = Governance {
+            max_accrual_age: 3_600,
+            ..sample_governance()
+        };
+        let liquidity = TokenAmount::new(1_000);
+        let total_supply = TokenAmount::new(2_000);
+        let amount = TokenAmount::new(100);
+
+        let mut reserve = reserve_with_debt(1_000);
+        assert!(sim::deposit(
+            &mut reserve,
+            &governance,
+            liquidity,
+            total_supply,
+            amount,
+            after(3_601),
+        )
+        .is_err());
+
+        let mut reserve = reserve_with_debt(1_000);
+        assert!(sim::withdraw(
+            &mut reserve,
+            &governance,
+            liquidity,
+            total_supply,
+            amount,
+            after(3_601),
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn break_even_utilization_inverts_the_lender_rate() {
+        // 10% APR at the 80% optimum, up to 110% at full utilization, lenders keep 90%
+        let slope = |apr_bps| Rate::from_apr_bps(apr_bps).into_ray().into_inner();
+        let governance = Governance {
+            optimal_utilization: RAY * 8 / 10,
+            optimal_slope: slope(1_000),
+            excess_slope: slope(10_000),
+            treasure_factor: 1_000 * RAY,
+            ..Governance::default()
+        };
+        let utilization = |target_bps| {
+            Reserve::break_even_utilization(&governance, target_bps)
+                .map(|v| v.into_inner() as f64 / RAY as f64)
+        };
+
+        // Below the optimum lenders earn 0.9 * 0.1 * u^2 / 0.8, 5% at u = 2/3
+        let below = utilization(500).unwrap();
+        assert!((below - 2.0 / 3.0).abs() < 1e-4, "utilization: {}", below);
+        // Above it 0.9 * u * (0.1 + (u - 0.8) / 0.2), 20% at u = 5/6
+        let above = utilization(2_000).unwrap();
+        assert!((above - 5.0 / 6.0).abs() < 1e-4, "utilization: {}", above);
+
+        // Lenders earn at most 99% at full utilization
+        assert!(utilization(9_800).unwrap() < 1.0);
+        assert_eq!(utilization(10_000), None);
+    }
+
+    #[test]
+    fn withdrawals_stop_at_the_liquidity_floor() {
+        // 90% utilized, 5% of the total liquidity has to stay in the vault
+        let governance = Governance {
+            reserve_liquidity_floor: 500 * RAY,
+            ..sample_governance()
+        };
+        let withdraw = |governance: &Governance, debt: u64, vault: u64, shares: u64| {
+            let (burned, paid) = sim::withdraw(
+                &mut reserve_with_debt(debt),
+                governance,
+                TokenAmount::new(vault),
+                TokenAmount::new(10_000),
+                TokenAmount::new(shares),
+                NOW,
+            )
+            .unwrap();
+            (burned.into_inner(), paid.into_inner())
+        };
+
+        // Within what is above the floor
+        assert_eq!(withdraw(&governance, 9_000, 1_000, 300), (300, 300));
+        assert_eq!(withdraw(&governance, 9_000, 1_000, 500), (500, 500));
+        // Cut to it, only the shares paid out are burned
+        assert_eq!(withdraw(&governance, 9_000, 1_000, 2_000), (500, 500));
+        // Nothing is paid once the vault is at or below the floor
+        assert_eq!(withdraw(&governance, 9_500, 500, 100), (0, 0));
+        assert_eq!(withdraw(&governance, 9_800, 200, 100), (0, 0));
+
+        // Without a floor the whole vault can be withdrawn
+        assert_eq!(
+            withdraw(&sample_governance(), 9_000, 1_000, 2_000),
+            (1_000, 1_000)
+        );
+    }
+
+    #[test]
+    fn swap_registry_is_bounded() {
+        let mut registry = ReserveSwapRegistry::default();
+        for _ in 0..ReserveSwapRegistry::MAX_SWAPS {
+            registry.add(&Pubkey::new_unique()).unwrap();
+        }
+        assert!(registry.add(&Pubkey::new_unique()).is_err());
+
+        // A swap is registered once
+        let mut registry = ReserveSwapRegistry::default();
+        let swap = Pubkey::new_unique();
+        registry.add(&swap).unwrap();
+        assert!(registry.add(&swap).is_err());
+    }
+
+    #[test]
+    fn history_overwrites_the_oldest_snapshot_once_full() {
+        let snapshot = |i: u64| ExchangeRateSnapshot {
+            timestamp: after(i),
+            exchange_rate: i as u128,
+        };
+        let mut history = ReserveHistory::default();
+        assert!(history.last().is_none());
+
+        for i in 0..ReserveHistory::MAX_SNAPSHOTS as u64 {
+            history.push(snapshot(i));
+            assert_eq!(history.last(), Some(&snapshot(i)));
+        }
+        assert_eq!(history.head, 0);
+        assert_eq!(history.len as usize, ReserveHistory::MAX_SNAPSHOTS);
+
+        // Wraps around to the first slot, the second snapshot is now the oldest
+        history.push(snapshot(32));
+        assert_eq!(history.head, 1);
+        assert_eq!(history.len as usize, ReserveHistory::MAX_SNAPSHOTS);
+        assert_eq!(history.last(), Some(&snapshot(32)));
+        assert_eq!(history.snapshots[0], snapshot(32));
+        assert_eq!(history.snapshots[1], snapshot(1));
+    }
+
+    #[test]
+    fn recalled_yield_is_shared_with_the_treasury() {
+        let governance = Governance {
+            treasure_factor: 1_000 * RAY,
+            ..sample_governance()
+        };
+        let mut reserve = reserve_with_debt(0);
+        reserve.deployed_liquidity = TokenAmount::new(1_000);
+
+        // Part of the principal, no yield
+        assert_eq!(
+            reserve.recall_deployed(&governance, TokenAmount::new(400)),
+            TokenAmount::ZERO
+        );
+        assert_eq!(reserve.deployed_liquidity, TokenAmount::new(600));
+        assert!(reserve.state.treasure_accrued.is_zero());
+
+        // The rest of the principal and 100 of yield, 10% of which goes to the treasury
+        assert_eq!(
+            reserve.recall_deployed(&governance, TokenAmount::new(700)),
+            TokenAmount::new(100)
+        );
+        assert!(reserve.deployed_liquidity.is_zero());
+        assert_eq!(reserve.state.treasure_accrued, TokenAmount::new(10));
+    }
+
+    #[test]
+    fn strategy_loss_is_written_down() {
+        let mut reserve = reserve_with_debt(0);
+        reserve.deployed_liquidity = TokenAmount::new(1_000);
+        let liquidity = TokenAmount::new(500);
+        assert_eq!(
+            reserve.get_total_liquidity(TokenAmount::ZERO, liquidity),
+            TokenAmount::new(1_500)
+        );
+
+        assert!(reserve.write_down_deployed(TokenAmount::new(300)).is_ok());
+        assert_eq!(reserve.deployed_liquidity, TokenAmount::new(700));
+        assert_eq!(
+            reserve.get_total_liquidity(TokenAmount::ZERO, liquidity),
+            TokenAmount::new(1_200)
+        );
+
+        // More than is deployed
+        assert!(reserve.write_down_deployed(TokenAmount::new(701)).is_err());
+        assert_eq!(reserve.deployed_liquidity, TokenAmount::new(700));
+    }
+
+    // A reserve with a single market, driven the way the handlers drive it. `vault` is the
+    // reserve lendable vault balance.
+    struct Market {
+        reserve: Reserve,
+        vault: TokenAmount,
+        total_supply: TokenAmount,
+        total_loan: TokenAmount,
+        written_off: TokenAmount,
+        positions: Vec<SwapPositionState>,
+    }
+
+    impl Market {
+        fn deposit(&mut self, governance: &Governance, amount: TokenAmount, ts: UnixTimestamp) {
+            let minted = sim::deposit(
+                &mut self.reserve,
+                governance,
+                self.vault,
+                self.total_supply,
+                amount,
+                ts,
+            )
+            .unwrap();
+            self.vault = self.vault.checked_add(amount).unwrap();
+            self.total_supply = self.total_supply.checked_add(minted).unwrap();
+        }
+
+        fn withdraw(&mut self, governance: &Governance, shares: TokenAmount, ts: UnixTimestamp) {
+            let (burned, paid) = sim::withdraw(
+                &mut self.reserve,
+                governance,
+                self.vault,
+                self.total_supply,
+                shares,
+                ts,
+            )
+            .unwrap();
+            self.vault = self.vault.checked_sub(paid).unwrap();
+            self.total_supply = self.total_supply.checked_sub(burned).unwrap();
+        }
+
+        // As `SwapPositionOpen`, the vault balance is read before the loan leaves it
+        fn open(
+            &mut self,
+            governance: &Governance,
+            index: usize,
+            loan: TokenAmount,
+            ts: UnixTimestamp,
+        ) {
+            let total_debt = self
+                .reserve
+                .debt
+                .get_total_debt(ts, governance.compound_terms());
+            let position = &mut self.positions[index];
+            if position.amount.is_zero() {
+                position.opened_at = ts;
+            }
+            sim::borrow(
+                &mut self.reserve,
+                governance,
+                position,
+                total_debt,
+                self.vault,
+                loan,
+                Factor::ONE,
+                ts,
+            )
+            .unwrap();
+            position.loan = position.loan.checked_add(loan).unwrap();
+
+            self.total_loan = self.total_loan.checked_add(loan).unwrap();
+            self.vault = self.vault.checked_sub(loan).unwrap();
+        }
+
+        // As `SwapPositionClose` without a grace period, repaying all of the debt or half of it
+        fn close(&mut self, governance: &Governance, index: usize, full: bool, ts: UnixTimestamp) {
+            let terms = governance.compound_terms();
+            let position = &mut self.positions[index];
+            let current_debt = position.get_debt(ts, UnixTimestamp::ZERO, terms);
+            let (debt_change, loan_change) = if full {
+                (current_debt, position.loan)
+            } else {
+                let debt_change = TokenAmount::new(current_debt.into_inner() / 2);
+                let loan_change =
+                    math::liquidity::calculate_share(debt_change, current_debt, position.loan);
+                (debt_change, loan_change)
+            };
+            position.loan = position.loan.checked_sub(loan_change).unwrap();
+            self.total_loan = self.total_loan.checked_sub(loan_change).unwrap();
+
+            sim::repay(
+                &mut self.reserve,
+                governance,
+                position,
+                self.vault,
+                debt_change,
+                debt_change,
+                UnixTimestamp::ZERO,
+                ts,
+            );
+
+            self.vault = self.vault.checked_add(debt_change).unwrap();
+        }
+
+        // As `SwapPositionLiquidate` of a position that sold for `output`, with the liquidator
+        // paid in pc. Any shortfall against the debt is written off as bad debt.
+        fn liquidate(
+            &mut self,
+            governance: &Governance,
+            index: usize,
+            output: TokenAmount,
+            ts: UnixTimestamp,
+        ) {
+            let position = &mut self.positions[index];
+            let current_debt =
+                position.get_debt(ts, UnixTimestamp::ZERO, governance.compound_terms());
+            let reward = SwapPosition::liquidation_reward(governance, output, current_debt);
+            let (_, treasury_share, insurance_share) =
+                SwapPosition::split_liquidation_reward(governance, reward, false);
+            let protocol_share = treasury_share.checked_add(insurance_share).unwrap();
+
+            let amount_left = output.checked_sub(reward).unwrap();
+            let (repaid, _, shortfall) =
+                SwapPosition::settle_liquidation(amount_left, current_debt);
+            self.reserve.add_bad_debt(shortfall);
+            self.written_off = self.written_off.checked_add(shortfall).unwrap();
+
+            self.total_loan = self.total_loan.checked_sub(position.loan).unwrap();
+            position.loan = TokenAmount::ZERO;
+
+            sim::repay(
+                &mut self.reserve,
+                governance,
+                position,
+                self.vault,
+                current_debt,
+                current_debt.checked_add(protocol_share).unwrap(),
+                UnixTimestamp::ZERO,
+                ts,
+            );
+            self.reserve.add_treasure(treasury_share);
+            self.reserve.add_insurance(insurance_share);
+
+            let returned = repaid.checked_add(protocol_share).unwrap();
+            self.vault = self.vault.checked_add(returned).unwrap();
+            assert!(position.amount.is_zero());
+        }
+
+        // Token amounts are unsigned and every subtraction on the reserve debt is checked, so a
+        // total going negative panics instead of being observed here
+        fn check_invariants(&self, governance: &Governance, ts: UnixTimestamp) {
+            let loans = self
+                .positions
+                .iter()
+                .fold(TokenAmount::ZERO, |sum, p| sum.checked_add(p.loan).unwrap());
+            assert_eq!(self.total_loan, loans);
+
+            let total_debt = self
+                .reserve
+                .debt
+                .get_total_debt(ts, governance.compound_terms());
+            let gross_liquidity = total_debt.checked_add(self.vault).unwrap();
+            let fees = self
+                .reserve
+                .state
+                .treasure_accrued
+                .checked_add(self.reserve.insurance_accrued)
+                .unwrap();
+            assert!(fees <= gross_liquidity);
+
+            assert_eq!(self.reserve.bad_debt, self.written_off);
+        }
+    }
+
+    #[test]
+    fn random_operations_keep_the_books_consistent() {
+        let governance = Governance {
+            treasure_factor: 1_000 * RAY,
+            insurance_factor: 500 * RAY,
+            base_borrow_rate: Rate::from_apr_bps(200).into_inner(),
+            optimal_slope: Rate::from_apr_bps(1_000).into_ray().into_inner(),
+            excess_slope: Rate::from_apr_bps(10_000).into_ray().into_inner(),
+            liquidation_reward: 500 * RAY,
+            liquidation_treasury_share: 2_000 * RAY,
+            liquidation_insurance_share: 1_000 * RAY,
+            ..sample_governance()
+        };
+        let mut market = Market {
+            reserve: reserve_with_debt(0),
+            vault: TokenAmount::ZERO,
+            total_supply: TokenAmount::ZERO,
+            total_loan: TokenAmount::ZERO,
+            written_off: TokenAmount::ZERO,
+            positions: vec![SwapPositionState::default(); 4],
+        };
+        market.deposit(&governance, TokenAmount::new(10_000_000_000), NOW);
+
+        // Linear congruential generator, so every run replays the same sequence
+        let mut seed: u64 = 0x5eed;
+        let mut next = move |bound: u64| {
+            seed = seed
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            (seed >> 33) % bound
+        };
+
+        let mut ts = NOW;
+        for _ in 0..500 {
+            ts = ts.checked_add(UnixTimestamp::new(next(86_400))).unwrap();
+            let index = next(4) as usize;
+
+            match next(6) {
+                0 => {
+                    let amount = TokenAmount::new(1 + next(1_000_000_000));
+                    market.deposit(&governance, amount, ts);
+                }
+                1 => {
+                    let shares = TokenAmount::new(market.total_supply.into_inner() / (2 + next(8)));
+                    market.withdraw(&governance, shares, ts);
+                }
+                2 => {
+                    let loan = TokenAmount::new(1 + next(1_000_000_000));
+                    if loan <= market.vault {
+                        market.open(&governance, index, loan, ts);
+                    }
+                }
+                3 => {
+                    let position = &market.positions[index];
+                    if !position.amount.is_zero() {
+                        // Sold for between nothing and one and a half times the debt
+                        let debt =
+                            position.get_debt(ts, UnixTimestamp::ZERO, governance.compound_terms());
+                        let output = debt.into_inner() as u128 * next(150) as u128 / 100;
+                        market.liquidate(&governance, index, TokenAmount::from_u128(output), ts);
+                    }
+                }
+                _ => {
+                    if !market.positions[index].amount.is_zero() {
+                        market.close(&governance, index, next(2) == 0, ts);
+                    }
+                }
+            }
+
+            market.check_invariants(&governance, ts);
+        }
+
+        // Everything repaid, no loan is left behind
+        for index in 0..market.positions.len() {
+            if !market.positions[index].amount.is_zero() {
+                market.close(&governance, index, true, ts);
+            }
+        }
+        market.check_invariants(&governance, ts);
+        assert!(market.total_loan.is_zero());
+        assert!(!market.written_off.is_zero());
+    }
+
+    #[test]
+    fn oversized_borrow_of_an_18_decimal_token_is_rejected() {
+        const TOKEN: u64 = 1_000_000_000_000_000_000;
+        let terms = math::interest::DEFAULT_COMPOUND_TERMS;
+        let mut reserve = reserve_with_debt(0);
+        reserve.state.borrow_rate = Rate::from_apr_bps(10_000);
+
+        let mut position = SwapPositionState::default();
+        reserve
+            .increase_debt(
+                &mut position,
+                NOW,
+                TokenAmount::ZERO,
+                TokenAmount::new(10 * TOKEN),
+                Factor::ONE,
+                terms,
+            )
+            .unwrap();
+        assert_eq!(position.amount, TokenAmount::new(10 * TOKEN));
+        assert_eq!(
+            position.rate.into_ray(),
+            reserve.state.borrow_rate.into_ray()
+        );
+
+        // A full u64 of base units at 100% APR doesn't fit, nothing is updated
+        let debt = reserve.debt;
+        let cumulative_borrowed = reserve.cumulative_borrowed;
+        let mut oversized = SwapPositionState::default();
+        assert!(reserve
+            .increase_debt(
+                &mut oversized,
+                NOW,
+                debt.total,
+                TokenAmount::new(u64::MAX),
+                Factor::ONE,
+                terms,
+            )
+            .is_err());
+        assert_eq!(oversized, SwapPositionState::default());
+        assert_eq!(reserve.debt.total, debt.total);
+        assert_eq!(reserve.debt.average_rate, debt.average_rate);
+        assert_eq!(reserve.cumulative_borrowed, cumulative_borrowed);
+    }
+
+    #[test]
+    fn waived_interest_leaves_no_phantom_debt() {
+        let terms = math::interest::DEFAULT_COMPOUND_TERMS;
+        let rate = Rate::from_apr_bps(10_000);
+        let mut first = SwapPositionState {
+            loan: TokenAmount::new(1_000_000_000),
+            rate,
+            amount: TokenAmount::new(1_000_000_000),
+            timestamp: NOW,
+            opened_at: NOW,
+            ..SwapPositionState::default()
+        };
+        let second = first;
+        let mut reserve_debt = ReserveDebt {
+            average_rate: rate,
+            total: TokenAmount::new(2_000_000_000),
+            last_update: NOW,
+        };
+
+        // The first position is repaid in full within the grace period
+        let grace = UnixTimestamp::new(60);
+        let timestamp = after(60);
+        let reserve_total_debt = reserve_debt.get_total_debt(timestamp, terms);
+        let debt_change = first.get_debt(timestamp, grace, terms);
+        assert_eq!(debt_change, first.amount);
+
+        sim::decrease_debt(
+            &mut reserve_debt,
+            &mut first,
+            timestamp,
+            grace,
+            reserve_total_debt,
+            debt_change,
+            terms,
+        );
+
+        assert!(first.amount.is_zero());
+        // Only the second position is left, with the interest it accrued
+        let remaining = second
+            .get_debt(timestamp, UnixTimestamp::ZERO, terms)
+            .into_inner();
+        let total = reserve_debt.total.into_inner();
+        assert!(total.max(remaining) - total.min(remaining) <= 1);
+    }
+}
@@ -1,7 +1,7 @@
 use anchor_lang::prelude::*;
 use serum_dex::state::{MarketState, ToAlignedBytes};
 use solana_program::{
-    entrypoint::ProgramResult, program_error::ProgramError, program_option::COption,
+    entrypoint::ProgramResult, instruction::AccountMeta, program_option::COption,
 };
 use std::convert::identity;
 
@@ -11,11 +11,11 @@ use super::{
         self, Dex, DexAccounts, DexLimitPrice, DexNonZeroTokenAmount, DexNonZeroTokenQty,
         DexTokenQty, __client_accounts_dex_accounts, __cpi_client_accounts_dex_accounts,
     },
-    error::{WowswapError, WowswapResultEmpty},
+    error::{WowswapError, WowswapResult, WowswapResultEmpty},
     governance::{self, Governance},
     math::{self, Factor, Rate, TokenAmount, UnixTimestamp},
-    reserve::Reserve,
-    token::{self, SplToken, TokenAccount, TokenAccountState, TokenMint},
+    reserve::{sim, Reserve, ReserveSwapRegistry},
+    token::{self, AssociatedToken, SplToken, TokenAccount, TokenAccountState, TokenMint},
 };
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, AnchorSerialize, AnchorDeserialize)]
@@ -43,6 +43,249 @@ pub struct Swap {
     pub dex_program: Pubkey,
     pub dex_market: Pubkey,
     pub dex_open_orders: Pubkey,
+
+    pub max_leverage_override: Factor,
+
+    // Program issuing trader credentials, default key disables gating
+    pub trader_gate: Pubkey,
+
+    // Blocked markets accept no new positions and allow emergency force closes
+    pub blocked: bool,
+
+    // Market share of the fees taken on this market, held in `fee_vault` until collected and kept
+    // apart from the reserve treasury so they can be shared with the market operator
+    pub fees_accrued: TokenAmount,
+
+    // Cap on the proxy token supply, bounds the size of the market independently of the reserve
+    // borrow limits. Zero means no cap.
+    pub max_proxy_supply: TokenAmount,
+
+    // Proxy tokens minted and not yet burned, i.e. the coin held for open positions
+    pub total_coin_outstanding: TokenAmount,
+
+    // Pc account of the swap signer holding `fees_accrued`, separate from `pc_vault` which is
+    // swept on every close. Default key until `swap_set_fee_vault`.
+    pub fee_vault: Pubkey,
+}
+
+impl Swap {
+    pub fn find_signer_address(swap: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[swap.as_ref()], &crate::ID)
+    }
+
+    // Handlers only compare the signer against the stored key, so it's pinned to the canonical
+    // bump at initialization
+    pub fn check_signer_nonce(swap: &Pubkey, nonce: u8) -> WowswapResultEmpty {
+        let (_, canonical_nonce) = Self::find_signer_address(swap);
+        require!(nonce == canonical_nonce, WowswapError::InvalidNonce);
+        Ok(())
+    }
+
+    // Seeds of the signer for the swap at `address`, as `find_signer_address` derives them
+    pub fn signer_seeds<'a>(&'a self, address: &'a Pubkey) -> [&'a [u8]; 2] {
+        [address.as_ref(), std::slice::from_ref(&self.nonce)]
+    }
+
+    // Part of `close_fee` the market keeps, rounded down so the reserve never gets less than its
+    // share. Nothing is kept without a fee vault.
+    pub fn market_fee(&self, close_fee: TokenAmount, governance: &Governance) -> TokenAmount {
+        if self.fee_vault == Pubkey::default() {
+            return TokenAmount::ZERO;
+        }
+        TokenAmount::from_u128(
+            governance
+                .market_fee_share()
+                .percentage_mul_down(close_fee.into_inner() as u128),
+        )
+    }
+
+    pub fn accrue_fees(&mut self, amount: TokenAmount) {
+        self.fees_accrued = self
+            .fees_accrued
+            .checked_add(amount)
+            .expect("fees_accrued overflow");
+    }
+
+    pub fn add_coin_outstanding(&mut self, amount: TokenAmount) {
+        self.total_coin_outstanding = self
+            .total_coin_outstanding
+            .checked_add(amount)
+            .expect("total_coin_outstanding overflow");
+    }
+
+    // Saturates, positions opened before the counter existed were never added to it
+    pub fn sub_coin_outstanding(&mut self, amount: TokenAmount) {
+        self.total_coin_outstanding = self
+            .total_coin_outstanding
+            .checked_sub(amount)
+            .unwrap_or(TokenAmount::ZERO);
+    }
+
+    // Nonce and accounts for `swap_initialize`, so that tooling standing up many markets derives
+    // the signer and vault addresses the same way `SwapInitialize` checks them. Vaults are the
+    // associated token accounts of the swap signer and have to be created beforehand.
+    #[allow(clippy::too_many_arguments)]
+    pub fn initialize_account_metas(
+        swap: &Pubkey,
+        reserve: &Pubkey,
+        coin_mint: &Pubkey,
+        pc_mint: &Pubkey,
+        proxy_token_mint: &Pubkey,
+        dex_market: &Pubkey,
+        dex_open_orders: &Pubkey,
+        payer: &Pubkey,
+    ) -> (u8, Vec<AccountMeta>) {
+        let (signer, nonce) = Self::find_signer_address(swap);
+        let accounts = crate::accounts::SwapInitialize {
+            swap: *swap,
+            signer,
+            reserve: *reserve,
+            reserve_swap_registry: ReserveSwapRegistry::find_address(reserve).0,
+            coin_mint: *coin_mint,
+            coin_vault: spl_associated_token_account::get_associated_token_address(
+                &signer, coin_mint,
+            ),
+            pc_mint: *pc_mint,
+            pc_vault: spl_associated_token_account::get_associated_token_address(&signer, pc_mint),
+            proxy_token_mint: *proxy_token_mint,
+            dex_program: dex::ID,
+            dex_market: *dex_market,
+            dex_open_orders: *dex_open_orders,
+            authority: authority::ID,
+            payer: *payer,
+            system_program: solana_program::system_program::ID,
+        };
+        (nonce, accounts.to_account_metas(None))
+    }
+
+    // Checks that `reserve` can lend `loan` to this swap right now. Part of the reserve liquidity
+    // can be deployed into an external strategy, so only the vault balance counts.
+    pub fn check_loan_liquidity(
+        swap: &Pubkey,
+        reserve: &Reserve,
+        liquidity: TokenAmount,
+        loan: TokenAmount,
+    ) -> Result<(), WowswapError> {
+        require!(
+            reserve.is_borrower_allowed(swap),
+            WowswapError::BorrowerNotAllowed
+        );
+        if loan > liquidity {
+            msg!(
+                "Insufficient liquidity. Loan: {:?}, liquidity: {:?}.",
+                loan,
+                liquidity
+            );
+            return Err(WowswapError::InsufficientLiquidity);
+        }
+        Ok(())
+    }
+
+    // Checks `total_loan`, including the new loan, against the share of reserve liquidity the
+    // swap may borrow
+    pub fn check_borrow_limit(
+        &self,
+        reserve: &Reserve,
+        governance: &Governance,
+        total_debt: TokenAmount,
+        liquidity: TokenAmount,
+    ) -> Result<(), WowswapError> {
+        let borrow_limit = reserve.borrow_limit(governance, total_debt, liquidity);
+        if self.state.total_loan >= borrow_limit {
+            msg!(
+                "Borrow limit exceeded. Total loan: {:?}, limit: {:?}.",
+                self.state.total_loan,
+                borrow_limit
+            );
+            return Err(WowswapError::BorrowLimitExceeded);
+        }
+        Ok(())
+    }
+
+    // Checks that minting `amount` on top of the current proxy `supply` stays within
+    // `max_proxy_supply`
+    pub fn check_proxy_supply(
+        &self,
+        supply: TokenAmount,
+        amount: TokenAmount,
+    ) -> Result<(), WowswapError> {
+        if !self.max_proxy_supply.is_zero()
+            && supply
+                .checked_add(amount)
+                .map_or(true, |v| v > self.max_proxy_supply)
+        {
+            msg!(
+                "Proxy supply cap exceeded. Supply: {:?}, amount: {:?}, cap: {:?}.",
+                supply,
+                amount,
+                self.max_proxy_supply
+            );
+            return Err(WowswapError::BorrowLimitExceeded);
+        }
+        Ok(())
+    }
+
+    // Permissioned markets take the trader credential as the first of the accounts following
+    // those of the instruction, permissionless markets take none. Advances `accounts` past it.
+    pub fn take_trader_credential<'a, 'info>(
+        &self,
+        accounts: &mut &'a [AccountInfo<'info>],
+    ) -> Option<&'a AccountInfo<'info>> {
+        if self.trader_gate == Pubkey::default() {
+            return None;
+        }
+        let (credential, rest) = accounts.split_first()?;
+        *accounts = rest;
+        Some(credential)
+    }
+
+    // Permissioned markets require a credential account issued by `trader_gate` at the
+    // `[swap, trader]` address of the gate program
+    pub fn check_trader_permitted(
+        &self,
+        swap: &Pubkey,
+        trader: &Pubkey,
+        credential: Option<&AccountInfo>,
+    ) -> Result<(), WowswapError> {
+        if self.trader_gate == Pubkey::default() {
+            return Ok(());
+        }
+
+        let (address, _) =
+            Pubkey::find_program_address(&[swap.as_ref(), trader.as_ref()], &self.trader_gate);
+        require!(
+            credential.map_or(false, |credential| *credential.key == address
+                && *credential.owner == self.trader_gate
+                && !credential.data_is_empty()),
+            WowswapError::TraderNotPermitted
+        );
+        Ok(())
+    }
+
+    // Per market override can only tighten the global cap, zero means no override
+    pub fn max_leverage_factor(&self, governance: &Governance) -> Factor {
+        let max_leverage_factor = governance.max_leverage_factor();
+        if self.max_leverage_override == Factor::new(0)
+            || self.max_leverage_override > max_leverage_factor
+        {
+            max_leverage_factor
+        } else {
+            self.max_leverage_override
+        }
+    }
+
+    // Gross value in native pc of `amount` proxy tokens at `price`, proxy tokens are minted one to
+    // one with the coin the swap holds
+    pub fn proxy_to_pc_value(
+        amount: TokenAmount,
+        price: DexLimitPrice,
+        lot_sizes: dex::MarketLotSizes,
+    ) -> TokenAmount {
+        amount
+            .into_wad()
+            .wad_mul(dex::price_to_pc_per_coin(price, lot_sizes))
+            .as_token_amount()
+    }
 }
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, AnchorSerialize, AnchorDeserialize)]
@@ -51,28 +294,76 @@ pub struct SwapPositionState {
     pub rate: Rate,
     pub amount: TokenAmount,
     pub timestamp: UnixTimestamp,
+    pub last_closed: UnixTimestamp,
+    // Term of the position counted from `opened_at`, zero means no term
+    pub opened_at: UnixTimestamp,
+    pub max_duration: UnixTimestamp,
 }
 
 impl SwapPositionState {
-    pub fn calculate_debt_increase(&self, timestamp: UnixTimestamp) -> (TokenAmount, TokenAmount) {
+    // Past its term the position can also be closed by a keeper, the trader keeps full control
+    // until then
+    pub fn is_expired(&self, timestamp: UnixTimestamp) -> bool {
+        !self.max_duration.is_zero()
+            && self
+                .opened_at
+                .checked_add(self.max_duration)
+                .map_or(false, |deadline| timestamp > deadline)
+    }
+
+    // A closed or liquidated position can't be reopened, nor its account reclaimed, until
+    // `cooldown` has passed since it was last closed
+    pub fn is_cooling_down(&self, timestamp: UnixTimestamp, cooldown: UnixTimestamp) -> bool {
+        !cooldown.is_zero()
+            && self
+                .last_closed
+                .checked_add(cooldown)
+                .map_or(true, |v| timestamp < v)
+    }
+
+    pub fn calculate_debt_increase(
+        &self,
+        timestamp: UnixTimestamp,
+        grace: UnixTimestamp,
+        compound_terms: u8,
+    ) -> (TokenAmount, TokenAmount) {
         if self.amount.is_zero() {
             (TokenAmount::ZERO, TokenAmount::ZERO)
         } else {
-            let current_debt = self.get_debt(timestamp);
-            let increase = current_debt
-                .checked_sub(self.amount)
-                .expect("invalid increase");
-            (current_debt, increase)
+            // Compounding rounds half-up, so for a tiny elapsed time the accrued debt may come out
+            // below the stored amount. Clamp the increase to zero instead of aborting.
+            let current_debt = self.get_debt(timestamp, grace, compound_terms);
+            match current_debt.checked_sub(self.amount) {
+                Some(increase) => (current_debt, increase),
+                None => (self.amount, TokenAmount::ZERO),
+            }
         }
     }
 
-    pub fn get_debt(&self, timestamp: UnixTimestamp) -> TokenAmount {
+    // No interest is charged when the debt is repaid within `grace` of the position opening.
+    // Only a close repaying the whole debt passes a grace period, partial closes, liquidations and
+    // top ups pay the full interest.
+    pub fn get_debt(
+        &self,
+        timestamp: UnixTimestamp,
+        grace: UnixTimestamp,
+        compound_terms: u8,
+    ) -> TokenAmount {
+        if !grace.is_zero()
+            && timestamp
+                .checked_sub(self.opened_at)
+                .map_or(false, |elapsed| elapsed <= grace)
+        {
+            return self.amount;
+        }
+
         self.amount
             .into_ray()
             .ray_mul(math::interest::calculate_compounded(
                 self.rate,
                 self.timestamp,
                 timestamp,
+                compound_terms,
             ))
             .as_token_amount()
     }
@@ -89,12 +380,277 @@ pub struct SwapPosition {
     pub proxy_token_account: Pubkey,
 
     pub state: SwapPositionState,
+
+    // Highest nonce passed to an open, so a resubmitted open is rejected instead of adding to the
+    // position
+    pub last_client_nonce: u64,
+}
+
+impl SwapPosition {
+    pub fn find_address(swap: &Pubkey, trader: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[swap.as_ref(), trader.as_ref()], &crate::ID)
+    }
+
+    // Only the canonical bump is accepted, otherwise a trader could hold several positions for
+    // the same swap
+    pub fn check_nonce(swap: &Pubkey, trader: &Pubkey, nonce: u8) -> WowswapResultEmpty {
+        let (_, canonical_nonce) = Self::find_address(swap, trader);
+        require!(nonce == canonical_nonce, WowswapError::InvalidNonce);
+        Ok(())
+    }
+
+    // Client nonces must be strictly increasing per position. Remembering a single nonce only
+    // catches the last open being resubmitted, a replay of an older one must be rejected as well.
+    // Zero opts out.
+    pub fn record_client_nonce(&mut self, client_nonce: u64) -> WowswapResultEmpty {
+        if client_nonce != 0 {
+            require!(
+                client_nonce > self.last_client_nonce,
+                WowswapError::DuplicateRequest
+            );
+            self.last_client_nonce = client_nonce;
+        }
+        Ok(())
+    }
+
+    // Position can be liquidated once its collateral is worth no more than this
+    pub fn liquidation_threshold(debt: TokenAmount, governance: &Governance) -> TokenAmount {
+        debt.checked_add(TokenAmount::from_u128(
+            governance
+                .liquidation_margin()
+                .percentage_mul(debt.into_inner() as u128),
+        ))
+        .expect("token amount overflow")
+    }
+
+    // Whole coin lots `native_coin_qty` can be sold in, None for a position below one lot
+    pub fn sellable_coin_lots(
+        native_coin_qty: TokenAmount,
+        coin_lot_size: u64,
+    ) -> Option<DexNonZeroTokenQty> {
+        native_coin_qty
+            .checked_div(TokenAmount::new(coin_lot_size))
+            .and_then(DexNonZeroTokenQty::from_token_amount)
+    }
+
+    // Debt left after a close that may be written off, zero when there is none. Only a position
+    // holding no more coins qualifies, otherwise a trader could size a partial close to keep the
+    // rest of the coins free of debt.
+    pub fn writable_dust(
+        remaining_debt: TokenAmount,
+        proxy_amount: TokenAmount,
+        dust_threshold: TokenAmount,
+    ) -> TokenAmount {
+        if proxy_amount.is_zero() && remaining_debt < dust_threshold {
+            remaining_debt
+        } else {
+            TokenAmount::ZERO
+        }
+    }
+
+    // Liquidator reward out of `amount`, the liquidation output. The reward share is bounded by
+    // `min_liquidation_reward` and `max_liquidation_reward`, then cut so the reserve recovers at
+    // least `min_debt_recovery` of `current_debt`, or all of it with `liquidation_reserve_first`.
+    pub fn liquidation_reward(
+        governance: &Governance,
+        amount: TokenAmount,
+        current_debt: TokenAmount,
+    ) -> TokenAmount {
+        let max_reward = governance.max_liquidation_reward();
+        let mut reward = std::cmp::max(
+            TokenAmount::from_u128(
+                governance
+                    .liquidation_reward()
+                    .percentage_mul(amount.into_inner() as u128),
+            ),
+            governance.min_liquidation_reward(),
+        );
+        if !max_reward.is_zero() && max_reward < reward {
+            reward = max_reward;
+        }
+
+        let min_recovery = if governance.liquidation_reserve_first() {
+            current_debt
+        } else {
+            TokenAmount::from_u128(
+                governance
+                    .min_debt_recovery()
+                    .percentage_mul(current_debt.into_inner() as u128),
+            )
+        };
+        let reward_available = amount
+            .checked_sub(min_recovery)
+            .unwrap_or(TokenAmount::ZERO);
+        std::cmp::min(reward, reward_available)
+    }
+
+    // Keeper fee out of `amount`, the output of an expired position's close. It comes out of what
+    // is left for the trader once the debt is repaid, so an underwater position pays none.
+    pub fn keeper_close_fee(
+        governance: &Governance,
+        amount: TokenAmount,
+        current_debt: TokenAmount,
+    ) -> TokenAmount {
+        let fee = TokenAmount::from_u128(
+            governance
+                .keeper_close_fee()
+                .percentage_mul(amount.into_inner() as u128),
+        );
+        let fee_available = amount
+            .checked_sub(current_debt)
+            .unwrap_or(TokenAmount::ZERO);
+        std::cmp::min(fee, fee_available)
+    }
+
+    // Splits `amount_left`, the liquidation output less the reward, into the debt repaid to the
+    // reserve, the surplus returned to the trader and the shortfall written off as bad debt.
+    pub fn settle_liquidation(
+        amount_left: TokenAmount,
+        current_debt: TokenAmount,
+    ) -> (TokenAmount, TokenAmount, TokenAmount) {
+        match amount_left.checked_sub(current_debt) {
+            Some(trader_amount) => (current_debt, trader_amount, TokenAmount::ZERO),
+            None => (
+                amount_left,
+                TokenAmount::ZERO,
+                current_debt.safe_sub(amount_left),
+            ),
+        }
+    }
+
+    // Splits a liquidation reward by `liquidation_treasury_share` and `liquidation_insurance_share`,
+    // both rounded down, the liquidator gets the rest so the parts always add up to `reward`. A
+    // keeper close fee (`expired`) goes to the keeper in full.
+    // Returns the liquidator, treasury and insurance parts.
+    pub fn split_liquidation_reward(
+        governance: &Governance,
+        reward: TokenAmount,
+        expired: bool,
+    ) -> (TokenAmount, TokenAmount, TokenAmount) {
+        if expired {
+            return (reward, TokenAmount::ZERO, TokenAmount::ZERO);
+        }
+        let treasury_share = TokenAmount::from_u128(
+            governance
+                .liquidation_treasury_share()
+                .percentage_mul_down(reward.into_inner() as u128),
+        );
+        let insurance_share = TokenAmount::from_u128(
+            governance
+                .liquidation_insurance_share()
+                .percentage_mul_down(reward.into_inner() as u128),
+        );
+        let liquidator_share = reward
+            .checked_sub(treasury_share)
+            .and_then(|v| v.checked_sub(insurance_share))
+            .expect("liquidation reward overflow");
+        (liquidator_share, treasury_share, insurance_share)
+    }
+
+    // Pc to transfer to repay the position in full at `timestamp`, the debt plus `repay_buffer`
+    pub fn repay_amount(&self, timestamp: UnixTimestamp, governance: &Governance) -> TokenAmount {
+        let debt = self.state.get_debt(
+            timestamp,
+            governance.position_grace_period(),
+            governance.compound_terms(),
+        );
+        debt.checked_add(TokenAmount::from_u128(
+            governance
+                .repay_buffer()
+                .percentage_mul(debt.into_inner() as u128),
+        ))
+        .expect("token amount overflow")
+    }
+
+    // Effective leverage of `proxy_amount` collateral at `price`, i.e. collateral value over
+    // equity. It drifts from the opening leverage as the price moves and interest accrues. `None`
+    // when the position is underwater or holds no collateral.
+    pub fn current_leverage(
+        &self,
+        proxy_amount: TokenAmount,
+        price: DexLimitPrice,
+        lot_sizes: dex::MarketLotSizes,
+        timestamp: UnixTimestamp,
+        governance: &Governance,
+    ) -> Option<Factor> {
+        let collateral_value = Swap::proxy_to_pc_value(proxy_amount, price, lot_sizes);
+        let debt = self
+            .state
+            .get_debt(timestamp, UnixTimestamp::ZERO, governance.compound_terms());
+        let equity = collateral_value.checked_sub(debt)?;
+        if equity.is_zero() {
+            return None;
+        }
+
+        (collateral_value.into_inner() as u128)
+            .checked_mul(Factor::ONE.into_inner() as u128)
+            .map(|v| v / equity.into_inner() as u128)
+            .map(|v| Factor::new(v.min(u64::MAX as u128) as u64))
+    }
+}
+
+// Swaps on which the trader has an open position, so a front-end can find all of them with one
+// account fetch. Free slots hold the default key.
+#[account]
+#[derive(Debug, Default)]
+pub struct TraderIndex {
+    pub nonce: u8,
+
+    pub trader: Pubkey,
+
+    pub swaps: [Pubkey; 16],
+}
+
+impl TraderIndex {
+    pub const MAX_SWAPS: usize = 16;
+
+    pub fn find_address(trader: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[b"trader_index", trader.as_ref()], &crate::ID)
+    }
+
+    pub fn insert(&mut self, swap: &Pubkey) -> WowswapResultEmpty {
+        if self.swaps.contains(swap) {
+            return Ok(());
+        }
+        let slot = self
+            .swaps
+            .iter_mut()
+            .find(|v| **v == Pubkey::default())
+            .ok_or(WowswapError::TooManyPositions)?;
+        *slot = *swap;
+        Ok(())
+    }
+
+    pub fn remove(&mut self, swap: &Pubkey) {
+        if let Some(slot) = self.swaps.iter_mut().find(|v| *v == swap) {
+            *slot = Pubkey::default();
+        }
+    }
+
+    // Handlers that take a position out of the index don't require it, a trader who never
+    // created one must not keep their positions from being closed or liquidated. It's looked up
+    // among `accounts` by address and left alone when absent.
+    pub fn remove_from_accounts<'info>(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo<'info>],
+        trader: &Pubkey,
+        swap: &Pubkey,
+    ) -> WowswapResultEmpty {
+        let (address, _) = Self::find_address(trader);
+        if let Some(info) = accounts.iter().find(|v| *v.key == address) {
+            require!(info.is_writable, WowswapError::InvalidArgument);
+            let mut trader_index: Account<'info, TraderIndex> = Account::try_from(info)?;
+            trader_index.remove(swap);
+            trader_index.exit(program_id)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Accounts)]
-#[instruction(nonce: u8)]
+#[instruction(nonce: u8, max_leverage_override: Factor)]
 pub struct SwapInitialize<'info> {
-    #[account(init, payer = payer, space = 657)] // Current size is 337
+    #[account(init, payer = payer, space = 657)] // Current size is 434
     swap: Box<Account<'info, Swap>>,
     #[account(seeds = [(*swap).as_ref().key.as_ref()], bump = nonce)]
     signer: AccountInfo<'info>,
@@ -103,6 +659,13 @@ pub struct SwapInitialize<'info> {
         constraint = reserve.lendable_mint == *(*pc_mint).as_ref().key
     )]
     reserve: Box<Account<'info, Reserve>>,
+    #[account(
+        mut,
+        has_one = reserve,
+        seeds = [b"reserve_swaps".as_ref(), (*reserve).as_ref().key.as_ref()],
+        bump = reserve_swap_registry.nonce,
+    )]
+    reserve_swap_registry: Box<Account<'info, ReserveSwapRegistry>>,
 
     coin_mint: Box<Account<'info, TokenMint>>,
     #[account(
@@ -129,6 +692,7 @@ pub struct SwapInitialize<'info> {
 
     #[account(
         constraint = proxy_token_mint.mint_authority == COption::Some(*signer.key),
+        constraint = proxy_token_mint.freeze_authority.is_none(),
         constraint = proxy_token_mint.supply == 0,
         constraint = proxy_token_mint.decimals == pc_mint.decimals,
     )]
@@ -147,9 +711,22 @@ pub struct SwapInitialize<'info> {
 }
 
 impl<'info> SwapInitialize<'info> {
-    pub fn handle(&mut self, nonce: u8) -> WowswapResultEmpty {
+    pub fn handle(
+        &mut self,
+        nonce: u8,
+        max_leverage_override: Factor,
+        trader_gate: Pubkey,
+    ) -> WowswapResultEmpty {
+        require!(
+            max_leverage_override == Factor::new(0) || max_leverage_override >= Factor::ONE,
+            WowswapError::InvalidLeverageFactor
+        );
+
+        Swap::check_signer_nonce((*self.swap).as_ref().key, nonce)?;
+
         self.validate_market()?;
-        self.initialize(nonce);
+        self.initialize(nonce, max_leverage_override, trader_gate);
+        self.reserve_swap_registry.add((*self.swap).as_ref().key)?;
         self.init_open_orders()?;
         Ok(())
     }
@@ -170,7 +747,7 @@ impl<'info> SwapInitialize<'info> {
         Ok(())
     }
 
-    fn initialize(&mut self, nonce: u8) {
+    fn initialize(&mut self, nonce: u8, max_leverage_override: Factor, trader_gate: Pubkey) {
         let swap = &mut self.swap;
 
         swap.nonce = nonce;
@@ -189,71 +766,427 @@ impl<'info> SwapInitialize<'info> {
         swap.dex_program = *self.dex_program.as_ref().key;
         swap.dex_market = *self.dex_market.key;
         swap.dex_open_orders = *self.dex_open_orders.key;
+
+        swap.max_leverage_override = max_leverage_override;
+        swap.trader_gate = trader_gate;
     }
 
-    fn init_open_orders(&self) -> ProgramResult {
+    fn init_open_orders(&self) -> WowswapResultEmpty {
         dex::init_open_orders(
             self.dex_program.to_account_info(),
             self.dex_open_orders.clone(),
             self.signer.clone(),
             self.dex_market.clone(),
-            &[&[(*self.swap).as_ref().key.as_ref(), &[self.swap.nonce]]],
+            &[&self.swap.signer_seeds((*self.swap).as_ref().key)],
         )
     }
 }
 
 #[derive(Accounts)]
-#[instruction(nonce: u8)]
-pub struct SwapPositionInitialize<'info> {
+pub struct SwapRotateProxyAuthority<'info> {
     #[account(
-        init,
-        seeds = [
-            (*swap).as_ref().key.as_ref(),
-            trader.key.as_ref()
-        ],
-        bump = nonce,
-        payer = trader,
-        space = 465, // Current size is 145
+        constraint = swap.signer == *swap_signer.key,
+        has_one = proxy_token_mint,
     )]
-    position: Box<Account<'info, SwapPosition>>,
+    swap: Box<Account<'info, Swap>>,
+    swap_signer: AccountInfo<'info>,
 
-    #[account(has_one = proxy_token_mint)]
+    // Rotating authority with live positions would orphan the minted proxy tokens
+    #[account(mut, constraint = proxy_token_mint.supply == 0)]
+    proxy_token_mint: Box<Account<'info, TokenMint>>,
+
+    #[account(constraint = *authority.as_ref().key == authority::ID)]
+    authority: Signer<'info>,
+
+    spl_token_program: Program<'info, SplToken>,
+}
+
+impl<'info> SwapRotateProxyAuthority<'info> {
+    pub fn handle(&mut self, new_authority: Pubkey) -> WowswapResultEmpty {
+        token::set_mint_authority(
+            self.proxy_token_mint.to_account_info(),
+            self.swap_signer.clone(),
+            &new_authority,
+            &[&self.swap.signer_seeds((*self.swap).as_ref().key)],
+        )?;
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct SwapSetBlocked<'info> {
+    #[account(mut)]
     swap: Box<Account<'info, Swap>>,
 
+    #[account(constraint = *authority.as_ref().key == authority::ID)]
+    authority: Signer<'info>,
+}
+
+impl<'info> SwapSetBlocked<'info> {
+    pub fn handle(&mut self, blocked: bool) -> WowswapResultEmpty {
+        self.swap.blocked = blocked;
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct SwapSetMaxProxySupply<'info> {
     #[account(mut)]
-    trader: Signer<'info>,
+    swap: Box<Account<'info, Swap>>,
 
-    proxy_token_mint: Box<Account<'info, TokenMint>>,
-    #[account(
-        constraint = proxy_token_account.mint == *(*proxy_token_mint).as_ref().key,
-        constraint = proxy_token_account.owner == swap.signer,
-        constraint = proxy_token_account.delegate.is_none(),
-        constraint = proxy_token_account.state == TokenAccountState::Initialized,
-        constraint = proxy_token_account.close_authority.is_none(),
-        constraint = token::check_associated_address(&proxy_token_account.mint, &trader, &proxy_token_account),
-    )]
-    proxy_token_account: Box<Account<'info, TokenAccount>>,
+    #[account(constraint = *authority.as_ref().key == authority::ID)]
+    authority: Signer<'info>,
+}
 
-    system_program: Program<'info, System>, // Required because `position` is `init` with `seeds`
+impl<'info> SwapSetMaxProxySupply<'info> {
+    pub fn handle(&mut self, max_proxy_supply: TokenAmount) -> WowswapResultEmpty {
+        self.swap.max_proxy_supply = max_proxy_supply;
+        Ok(())
+    }
 }
 
-impl<'info> SwapPositionInitialize<'info> {
-    pub fn handle(&mut self, nonce: u8) -> WowswapResultEmpty {
-        let position = &mut self.position;
+#[derive(Accounts)]
+pub struct SwapSetFeeVault<'info> {
+    #[account(mut)]
+    swap: Box<Account<'info, Swap>>,
 
-        position.nonce = nonce;
+    #[account(
+        constraint = fee_vault.mint == swap.pc_mint,
+        constraint = fee_vault.owner == swap.signer,
+        constraint = fee_vault.delegate.is_none(),
+        constraint = *(*fee_vault).as_ref().key != swap.pc_vault,
+    )]
+    fee_vault: Box<Account<'info, TokenAccount>>,
 
-        position.swap = *(*self.swap).as_ref().key;
-        position.trader = *self.trader.key;
+    #[account(constraint = *authority.as_ref().key == authority::ID)]
+    authority: Signer<'info>,
+}
 
-        position.proxy_token_account = *(*self.proxy_token_account).as_ref().key;
+impl<'info> SwapSetFeeVault<'info> {
+    pub fn handle(&mut self) -> WowswapResultEmpty {
+        // Fees accrued so far sit in the current vault
+        require!(
+            self.swap.fees_accrued.is_zero(),
+            WowswapError::InvalidArgument
+        );
 
+        self.swap.fee_vault = *(*self.fee_vault).as_ref().key;
         Ok(())
     }
 }
 
 #[derive(Accounts)]
-pub struct SwapPositionOpen<'info> {
+pub struct SwapCollectFees<'info> {
+    #[account(
+        mut,
+        constraint = swap.signer == *swap_signer.key,
+        constraint = swap.fee_vault == *(*swap_fee_vault).as_ref().key,
+    )]
+    swap: Box<Account<'info, Swap>>,
+    swap_signer: AccountInfo<'info>,
+    #[account(mut)]
+    swap_fee_vault: Box<Account<'info, TokenAccount>>,
+
+    #[account(mut, constraint = recipient_pc_vault.mint == swap.pc_mint)]
+    recipient_pc_vault: Box<Account<'info, TokenAccount>>,
+
+    #[account(constraint = *authority.as_ref().key == authority::ID)]
+    authority: Signer<'info>,
+
+    spl_token_program: Program<'info, SplToken>,
+}
+
+impl<'info> SwapCollectFees<'info> {
+    pub fn handle(&mut self) -> WowswapResultEmpty {
+        let amount = self.swap.fees_accrued;
+        if amount.is_zero() {
+            return Ok(());
+        }
+
+        token::transfer(
+            self.swap_fee_vault.to_account_info(),
+            self.recipient_pc_vault.to_account_info(),
+            self.swap_signer.clone(),
+            amount,
+            &[&self.swap.signer_seeds((*self.swap).as_ref().key)],
+        )?;
+        self.swap.fees_accrued = TokenAmount::ZERO;
+
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(nonce: u8)]
+pub struct TraderIndexInitialize<'info> {
+    #[account(
+        init,
+        seeds = [b"trader_index".as_ref(), trader.key.as_ref()],
+        bump = nonce,
+        payer = trader,
+        space = 1024, // Current size is 553
+    )]
+    trader_index: Box<Account<'info, TraderIndex>>,
+
+    #[account(mut)]
+    trader: Signer<'info>,
+
+    system_program: Program<'info, System>, // Required because `trader_index` is `init` with `seeds`
+}
+
+impl<'info> TraderIndexInitialize<'info> {
+    pub fn handle(&mut self, nonce: u8) -> WowswapResultEmpty {
+        let (_, canonical_nonce) = TraderIndex::find_address(self.trader.key);
+        require!(nonce == canonical_nonce, WowswapError::InvalidNonce);
+
+        self.trader_index.nonce = nonce;
+        self.trader_index.trader = *self.trader.key;
+
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(nonce: u8)]
+pub struct SwapPositionInitialize<'info> {
+    #[account(
+        init,
+        seeds = [
+            (*swap).as_ref().key.as_ref(),
+            trader.key.as_ref()
+        ],
+        bump = nonce,
+        payer = trader,
+        space = 465, // Current size is 177
+    )]
+    position: Box<Account<'info, SwapPosition>>,
+
+    #[account(has_one = proxy_token_mint)]
+    swap: Box<Account<'info, Swap>>,
+
+    #[account(mut)]
+    trader: Signer<'info>,
+
+    proxy_token_mint: Box<Account<'info, TokenMint>>,
+    #[account(
+        constraint = proxy_token_account.mint == *(*proxy_token_mint).as_ref().key,
+        constraint = proxy_token_account.owner == swap.signer,
+        constraint = proxy_token_account.delegate.is_none(),
+        constraint = proxy_token_account.state == TokenAccountState::Initialized,
+        constraint = proxy_token_account.close_authority.is_none(),
+        constraint = token::check_associated_address(&proxy_token_account.mint, &trader, &proxy_token_account),
+    )]
+    proxy_token_account: Box<Account<'info, TokenAccount>>,
+
+    system_program: Program<'info, System>, // Required because `position` is `init` with `seeds`
+}
+
+impl<'info> SwapPositionInitialize<'info> {
+    // `remaining_accounts` holds the trader credential on permissioned markets
+    pub fn handle(
+        &mut self,
+        remaining_accounts: &mut &[AccountInfo<'info>],
+        nonce: u8,
+    ) -> WowswapResultEmpty {
+        SwapPosition::check_nonce((*self.swap).as_ref().key, self.trader.key, nonce)?;
+
+        let credential = self.swap.take_trader_credential(remaining_accounts);
+        self.swap
+            .check_trader_permitted((*self.swap).as_ref().key, self.trader.key, credential)?;
+
+        let position = &mut self.position;
+
+        position.nonce = nonce;
+
+        position.swap = *(*self.swap).as_ref().key;
+        position.trader = *self.trader.key;
+
+        position.proxy_token_account = *(*self.proxy_token_account).as_ref().key;
+
+        Ok(())
+    }
+}
+
+// `swap_position_initialize` that also succeeds when the position already exists, so clients can
+// call it unconditionally before opening. An existing account has to be this trader's position
+// on this swap. Otherwise `remaining_accounts` must hold the `swap_position_initialize` accounts,
+// followed by the trader credential on permissioned markets.
+#[derive(Accounts)]
+pub struct SwapPositionInitIfNeeded<'info> {
+    #[account(
+        constraint = *position.key == SwapPosition::find_address((*swap).as_ref().key, trader.key).0,
+    )]
+    position: AccountInfo<'info>,
+
+    swap: Box<Account<'info, Swap>>,
+
+    trader: Signer<'info>,
+}
+
+impl<'info> SwapPositionInitIfNeeded<'info> {
+    pub fn handle(
+        &self,
+        program_id: &Pubkey,
+        remaining_accounts: &[AccountInfo<'info>],
+        nonce: u8,
+    ) -> WowswapResultEmpty {
+        if self.position.data_is_empty() {
+            let mut accounts = remaining_accounts;
+            let mut initialize =
+                SwapPositionInitialize::try_accounts(program_id, &mut accounts, &[nonce])?;
+            require!(
+                *(*initialize.position).as_ref().key == *self.position.key,
+                WowswapError::InvalidArgument
+            );
+            initialize.handle(&mut accounts, nonce)?;
+            initialize.exit(program_id)?;
+            return Ok(());
+        }
+
+        require!(
+            self.position.owner == program_id,
+            WowswapError::InconsistentPositionState
+        );
+        let position = SwapPosition::try_deserialize(&mut &self.position.try_borrow_data()?[..])?;
+        require!(
+            position.swap == *(*self.swap).as_ref().key && position.trader == *self.trader.key,
+            WowswapError::InconsistentPositionState
+        );
+
+        Ok(())
+    }
+}
+
+// Sets up a first-time trader and opens the position in one instruction. `proxy_token_account`
+// is created (if missing) as the trader's associated token account and handed over to the swap
+// signer, and `trader_index` is created (if missing) as by `trader_index_initialize`. After that
+// `remaining_accounts` must hold the `swap_position_initialize` accounts followed by the
+// `swap_position_open` accounts, which are processed by the existing handlers.
+// On permissioned markets each of them is followed by the trader credential.
+#[derive(Accounts)]
+pub struct SwapPositionOpenWithSetup<'info> {
+    #[account(has_one = proxy_token_mint)]
+    swap: Box<Account<'info, Swap>>,
+
+    #[account(mut)]
+    trader: Signer<'info>,
+
+    proxy_token_mint: Box<Account<'info, TokenMint>>,
+    #[account(
+        mut,
+        constraint = *proxy_token_account.key == spl_associated_token_account::get_associated_token_address(
+            trader.key,
+            (*proxy_token_mint).as_ref().key,
+        ),
+    )]
+    proxy_token_account: AccountInfo<'info>,
+    // Validated by `trader_index_initialize` when created and by `swap_position_open` otherwise
+    #[account(mut)]
+    trader_index: AccountInfo<'info>,
+
+    spl_token_program: Program<'info, SplToken>,
+    associated_token_program: Program<'info, AssociatedToken>,
+    system_program: Program<'info, System>,
+    rent: Sysvar<'info, Rent>,
+}
+
+impl<'info> SwapPositionOpenWithSetup<'info> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn handle(
+        &mut self,
+        program_id: &Pubkey,
+        remaining_accounts: &[AccountInfo<'info>],
+        nonce: u8,
+        limit_price: DexLimitPrice,
+        coin_qty: DexNonZeroTokenQty,
+        leverage_factor: Factor,
+        max_pc_with_fees: Option<TokenAmount>,
+        max_duration: UnixTimestamp,
+        client_nonce: u64,
+    ) -> WowswapResultEmpty {
+        self.setup_proxy_token_account()?;
+        self.setup_trader_index(program_id)?;
+
+        let mut accounts = remaining_accounts;
+
+        let mut initialize =
+            SwapPositionInitialize::try_accounts(program_id, &mut accounts, &[nonce])?;
+        initialize.handle(&mut accounts, nonce)?;
+        initialize.exit(program_id)?;
+
+        let mut open = SwapPositionOpen::try_accounts(program_id, &mut accounts, &[])?;
+        open.handle(
+            &mut accounts,
+            limit_price,
+            coin_qty,
+            leverage_factor,
+            max_pc_with_fees,
+            max_duration,
+            client_nonce,
+        )?;
+        open.exit(program_id)?;
+
+        Ok(())
+    }
+
+    fn setup_trader_index(&self, program_id: &Pubkey) -> WowswapResultEmpty {
+        if !self.trader_index.data_is_empty() {
+            return Ok(());
+        }
+
+        let (_, nonce) = TraderIndex::find_address(self.trader.key);
+        let accounts = [
+            self.trader_index.clone(),
+            self.trader.to_account_info(),
+            self.system_program.to_account_info(),
+        ];
+        let mut initialize =
+            TraderIndexInitialize::try_accounts(program_id, &mut &accounts[..], &[nonce])?;
+        initialize.handle(nonce)?;
+        initialize.exit(program_id)
+    }
+
+    fn setup_proxy_token_account(&self) -> WowswapResultEmpty {
+        if self.proxy_token_account.data_is_empty() {
+            token::create_associated_account(
+                self.trader.to_account_info(),
+                self.proxy_token_account.clone(),
+                self.trader.to_account_info(),
+                self.proxy_token_mint.to_account_info(),
+                self.system_program.to_account_info(),
+                self.spl_token_program.to_account_info(),
+                self.rent.to_account_info(),
+            )?;
+        } else {
+            let proxy_token_account = TokenAccount::try_deserialize(
+                &mut &self.proxy_token_account.try_borrow_data()?[..],
+            )?;
+            // Already handed over, `swap_position_initialize` validates the rest
+            if proxy_token_account.owner != *self.trader.key {
+                return Ok(());
+            }
+        }
+
+        token::set_account_owner(
+            self.proxy_token_account.clone(),
+            self.trader.to_account_info(),
+            &self.swap.signer,
+            &[],
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SwapPositionOpenBreakdown {
+    pub coin_qty: DexNonZeroTokenQty,
+    pub native_coin_qty: TokenAmount,
+    pub native_pc_qty_loan: TokenAmount,
+    pub native_pc_qty_trader: TokenAmount,
+    pub native_pc_qty_including_fees: DexNonZeroTokenAmount,
+}
+
+#[derive(Accounts)]
+pub struct SwapPositionOpen<'info> {
     #[account(
         mut,
         has_one = swap,
@@ -285,7 +1218,12 @@ pub struct SwapPositionOpen<'info> {
 
     #[account(mut)]
     proxy_token_mint: Box<Account<'info, TokenMint>>,
-    #[account(mut)]
+    // Proxy tokens only ever sit in the swap signer owned account, nobody else may move them
+    #[account(
+        mut,
+        constraint = proxy_token_account.owner == swap.signer,
+        constraint = proxy_token_account.delegate.is_none(),
+    )]
     proxy_token_account: Box<Account<'info, TokenAccount>>,
 
     #[account(
@@ -303,76 +1241,191 @@ pub struct SwapPositionOpen<'info> {
 
     trader: Signer<'info>,
 
-    #[account(mut, constraint = trader_pc_vault.owner == *trader.key)]
+    // Aliasing the swap vault would make the transfers in and out of it self-referencing
+    #[account(
+        mut,
+        constraint = trader_pc_vault.owner == *trader.key,
+        constraint = *(*trader_pc_vault).as_ref().key != *(*swap_pc_vault).as_ref().key,
+    )]
     trader_pc_vault: Box<Account<'info, TokenAccount>>,
 
+    #[account(
+        mut,
+        has_one = trader,
+        seeds = [b"trader_index".as_ref(), trader.key.as_ref()],
+        bump = trader_index.nonce,
+    )]
+    trader_index: Box<Account<'info, TraderIndex>>,
+
     spl_token_program: Program<'info, SplToken>,
 
     dex_accounts: DexAccounts<'info>,
 }
 
 impl<'info> SwapPositionOpen<'info> {
+    // `remaining_accounts` holds the trader credential on permissioned markets
+    #[allow(clippy::too_many_arguments)]
     pub fn handle(
         &mut self,
+        remaining_accounts: &mut &[AccountInfo<'info>],
+        limit_price: DexLimitPrice,
+        coin_qty: DexNonZeroTokenQty,
+        leverage_factor: Factor,
+        max_pc_with_fees: Option<TokenAmount>,
+        max_duration: UnixTimestamp,
+        client_nonce: u64,
+    ) -> WowswapResultEmpty {
+        self.open(
+            remaining_accounts,
+            limit_price,
+            coin_qty,
+            leverage_factor,
+            max_pc_with_fees,
+            max_duration,
+            client_nonce,
+            None,
+        )
+    }
+
+    // `held` is only passed by `SwapPositionRoll`, the funds its close left in `swap_pc_vault`.
+    // They are spent first and only the difference is moved to or from the reserve and trader
+    // vaults. The cooldown doesn't apply, the position was closed by this same instruction.
+    #[allow(clippy::too_many_arguments)]
+    fn open(
+        &mut self,
+        remaining_accounts: &mut &[AccountInfo<'info>],
         limit_price: DexLimitPrice,
         coin_qty: DexNonZeroTokenQty,
         leverage_factor: Factor,
+        max_pc_with_fees: Option<TokenAmount>,
+        max_duration: UnixTimestamp,
+        client_nonce: u64,
+        held: Option<SwapPositionRollFunds>,
     ) -> WowswapResultEmpty {
         let timestamp = UnixTimestamp::now()?;
 
-        let max_leverage_factor = self.governance.max_leverage_factor();
+        require!(!self.swap.blocked, WowswapError::MarketBlocked);
+        self.governance.check_initialized()?;
+        self.reserve
+            .check_accrual_age(&self.governance, timestamp)?;
+
+        self.position.record_client_nonce(client_nonce)?;
+
+        let credential = self.swap.take_trader_credential(remaining_accounts);
+        self.swap
+            .check_trader_permitted((*self.swap).as_ref().key, self.trader.key, credential)?;
+
+        // An unlevered position holds proxy tokens without a loan, but a loan without proxy
+        // tokens means a previous close or liquidation went wrong
         require!(
-            leverage_factor >= Factor::ONE && leverage_factor <= max_leverage_factor,
-            WowswapError::InvalidLeverageFactor
+            self.position.state.loan.is_zero() || self.proxy_token_account.amount > 0,
+            WowswapError::InconsistentPositionState
         );
-        let coin_qty_loan = DexTokenQty::from_u128(
-            leverage_factor
-                .checked_sub(Factor::ONE)
-                .ok_or(WowswapError::InvalidLeverageFactor)?
-                .percentage_mul(coin_qty.into_inner().get() as u128),
+
+        require!(
+            held.is_some()
+                || !self
+                    .position
+                    .state
+                    .is_cooling_down(timestamp, self.governance.position_cooldown()),
+            WowswapError::PositionCooldown
         );
-        let coin_qty = coin_qty
-            .checked_add(coin_qty_loan)
-            .expect("coin_qty overflow");
 
-        let lot_sizes = dex::market_lot_sizes(&self.dex_accounts)?;
-        let native_coin_qty = coin_qty
-            .checked_mul_lot_size(lot_sizes.coin)
-            .ok_or(WowswapError::InvalidArgument)?
-            .as_token_amount();
-        let pc_lot_limit_price = limit_price.checked_mul_lot_size(lot_sizes.pc);
-        let native_pc_qty_loan = pc_lot_limit_price
-            .and_then(|v| v.checked_mul_token_qty(coin_qty_loan))
-            .ok_or(WowswapError::InvalidArgument)?;
-        let native_pc_qty_including_fees = pc_lot_limit_price
-            .and_then(|v| v.checked_mul_nonzero_token_qty(coin_qty))
-            .ok_or(WowswapError::InvalidArgument)?;
+        let lot_sizes = self.dex_accounts.validate_and_load(&self.swap.dex_market)?;
+
+        // Fail before drawing reserve funds rather than deep in the dex when it can't match
+        let min_free_slots = self.governance.min_event_queue_free_slots();
+        require!(
+            min_free_slots == 0
+                || dex::event_queue_free_slots(&self.dex_accounts.event_queue)? >= min_free_slots,
+            WowswapError::MarketCongested
+        );
+
+        let max_leverage_factor = self.governance.max_leverage_factor();
+        let SwapPositionOpenBreakdown {
+            coin_qty,
+            native_coin_qty: _,
+            native_pc_qty_loan,
+            native_pc_qty_trader,
+            native_pc_qty_including_fees,
+        } = Self::breakdown(
+            coin_qty,
+            leverage_factor,
+            limit_price,
+            max_pc_with_fees,
+            lot_sizes,
+            self.swap.max_leverage_factor(&self.governance),
+        )?;
 
+        // Reserve liquidity as it would be had a roll's close paid back what it holds. The vault
+        // is not reloaded below, so this stays the liquidity before the loan.
+        let held = held.unwrap_or_default();
+        let liquidity = TokenAmount::new(self.reserve_lendable_vault.amount)
+            .checked_add(held.reserve)
+            .expect("liquidity overflow");
+        let (reserve_in, reserve_held) =
+            SwapPositionRollFunds::net(held.reserve, native_pc_qty_loan);
+        let (trader_in, trader_held) =
+            SwapPositionRollFunds::net(held.trader, native_pc_qty_trader);
+
+        // The vault balance is checked against the loan before any funds move, so a short reserve
+        // fails with `InsufficientLiquidity` instead of inside the token transfer
         if native_pc_qty_loan > TokenAmount::ZERO {
-            self.take_reserve_funds(native_pc_qty_loan)?;
+            Swap::check_loan_liquidity(
+                (*self.swap).as_ref().key,
+                &self.reserve,
+                liquidity,
+                native_pc_qty_loan,
+            )?;
+            if reserve_in > TokenAmount::ZERO {
+                self.take_reserve_funds(reserve_in)?;
+            }
         }
 
-        self.take_trader_funds(
-            native_pc_qty_including_fees
-                .as_token_amount()
-                .safe_sub(native_pc_qty_loan),
-        )?;
+        self.take_trader_funds(trader_in)?;
+
+        self.swap_pc_vault.reload()?;
+        let coin_before = TokenAmount::new(self.swap_coin_vault.amount);
+        let pc_before = TokenAmount::new(self.swap_pc_vault.amount);
 
         self.make_swap(limit_price, coin_qty, native_pc_qty_including_fees)?;
+        self.swap_coin_vault.reload()?;
         self.swap_pc_vault.reload()?;
 
+        let coin_received = TokenAmount::new(self.swap_coin_vault.amount)
+            .checked_sub(coin_before)
+            .expect("coin_received overflow");
+        let pc_spent = pc_before
+            .checked_sub(TokenAmount::new(self.swap_pc_vault.amount))
+            .unwrap_or(TokenAmount::ZERO);
+
+        self.check_fill_price(limit_price, lot_sizes, coin_received, pc_spent)?;
+
+        // The order may be filled partially. Only what this order put into the vault and did not
+        // spend is returned, the loan is repaid first so that a partial fill lowers leverage.
+        let pc_unspent = native_pc_qty_loan
+            .checked_add(native_pc_qty_trader)
+            .expect("pc_deposited overflow")
+            .checked_sub(pc_spent)
+            .unwrap_or(TokenAmount::ZERO);
+        let loan_returned = std::cmp::min(native_pc_qty_loan, pc_unspent);
+        let trader_returned = pc_unspent
+            .safe_sub(loan_returned)
+            .checked_add(trader_held)
+            .expect("trader_returned overflow");
+
+        let reserve_returned = loan_returned
+            .checked_add(reserve_held)
+            .expect("reserve_returned overflow");
+        if reserve_returned > TokenAmount::ZERO {
+            self.return_reserve_funds(reserve_returned)?;
+        }
+
         if native_pc_qty_loan > TokenAmount::ZERO {
-            let return_amount = std::cmp::min(
-                native_pc_qty_loan,
-                TokenAmount::new(self.swap_pc_vault.amount),
-            );
             let native_pc_qty_loan = native_pc_qty_loan
-                .checked_sub(return_amount)
+                .checked_sub(loan_returned)
                 .expect("native_pc_qty_loan overflow");
 
-            self.return_reserve_funds(return_amount)?;
-            self.swap_pc_vault.reload()?;
-
             if native_pc_qty_loan > TokenAmount::ZERO {
                 self.swap.state.total_loan = self
                     .swap
@@ -387,19 +1440,20 @@ impl<'info> SwapPositionOpen<'info> {
                     .checked_add(native_pc_qty_loan)
                     .expect("loan overflow");
 
-                let pool_utilization = self.governance.pool_utilization_allowance();
-                let total_debt = self.reserve.debt.get_total_debt(timestamp);
-                let total_liquidity = self.reserve.get_total_liquidity(
+                // The limit is a share of total liquidity as it was before this loan: the debt
+                // excludes the loan and `reserve_lendable_vault` is not reloaded, so it still
+                // includes it. A loan only moves value from the vault to debt, adding it to the
+                // debt here would count it twice. The loan itself is already in `total_loan`.
+                let total_debt = self
+                    .reserve
+                    .debt
+                    .get_total_debt(timestamp, self.governance.compound_terms());
+                self.swap.check_borrow_limit(
+                    &self.reserve,
+                    &self.governance,
                     total_debt,
-                    TokenAmount::new(self.reserve_lendable_vault.amount),
-                );
-                let borrow_limit = TokenAmount::from_u128(
-                    pool_utilization.percentage_mul(total_liquidity.into_inner() as u128),
-                );
-                require!(
-                    self.swap.state.total_loan < borrow_limit,
-                    WowswapError::BorrowLimitExceeded
-                );
+                    liquidity,
+                )?;
 
                 let rate_multiplier = leverage_factor
                     .checked_sub(Factor::ONE)
@@ -424,30 +1478,181 @@ impl<'info> SwapPositionOpen<'info> {
                 self.reserve_update_state(
                     timestamp,
                     total_debt,
+                    liquidity,
                     native_pc_qty_loan,
                     rate_multiplier,
-                );
+                )?;
             }
         }
 
-        self.return_trader_funds()?;
+        self.return_trader_funds(trader_returned)?;
 
-        self.mint_proxy_token(native_coin_qty)?;
+        // The term is fixed when the position is opened, adding to it does not extend it
+        if self.proxy_token_account.amount == 0 {
+            self.position.state.opened_at = timestamp;
+            self.position.state.max_duration = max_duration;
+        }
+        self.mint_proxy_token(coin_received)?;
+        if !coin_received.is_zero() {
+            self.trader_index.insert((*self.swap).as_ref().key)?;
+        }
 
         Ok(())
     }
 
-    fn take_reserve_funds(&self, amount: TokenAmount) -> ProgramResult {
-        token::transfer(
-            self.reserve_lendable_vault.to_account_info(),
-            self.swap_pc_vault.to_account_info(),
-            self.reserve_signer.clone(),
-            amount,
-            &[&[(*self.reserve).as_ref().key.as_ref(), &[self.reserve.nonce]]],
-        )
-    }
-
-    fn take_trader_funds(&self, amount: TokenAmount) -> ProgramResult {
+    // Splits the order into trader and reserve funded parts, shared with `swap_position_quote`
+    // so quotes never diverge from execution.
+    // By default the order budget is `limit_price * coin_qty`, which leaves nothing for taker fees
+    // on fee-bearing markets. `max_pc_with_fees` raises the budget, the extra is paid by the trader
+    // and whatever is not spent is returned to them after the swap.
+    pub fn breakdown(
+        coin_qty: DexNonZeroTokenQty,
+        leverage_factor: Factor,
+        limit_price: DexLimitPrice,
+        max_pc_with_fees: Option<TokenAmount>,
+        lot_sizes: dex::MarketLotSizes,
+        max_leverage_factor: Factor,
+    ) -> WowswapResult<SwapPositionOpenBreakdown> {
+        if leverage_factor < Factor::ONE || leverage_factor > max_leverage_factor {
+            msg!(
+                "Invalid leverage factor. Leverage: {:?}, max: {:?}.",
+                leverage_factor,
+                max_leverage_factor
+            );
+            return Err(WowswapError::InvalidLeverageFactor.into());
+        }
+        // `coin_qty` counts lots, the borrowed part is rounded down to a whole lot so the loan never
+        // exceeds the requested leverage. Both pc amounts below derive from the rounded value.
+        // A large `coin_qty` at high leverage can exceed `u64`, reject it instead of aborting
+        let coin_qty_loan = leverage_factor
+            .checked_sub(Factor::ONE)
+            .ok_or(WowswapError::InvalidLeverageFactor)?
+            .percentage_mul_down(coin_qty.into_inner().get() as u128);
+        require!(
+            coin_qty_loan <= u64::MAX as u128,
+            WowswapError::InvalidArgument
+        );
+        let coin_qty_loan = DexTokenQty::from_u128(coin_qty_loan);
+        let coin_qty = coin_qty
+            .checked_add(coin_qty_loan)
+            .ok_or(WowswapError::InvalidArgument)?;
+
+        let native_coin_qty = coin_qty
+            .checked_mul_lot_size(lot_sizes.coin)
+            .ok_or(WowswapError::InvalidArgument)?
+            .as_token_amount();
+        let pc_lot_limit_price = limit_price.checked_mul_lot_size(lot_sizes.pc);
+        let native_pc_qty_loan = pc_lot_limit_price
+            .and_then(|v| v.checked_mul_token_qty(coin_qty_loan))
+            .ok_or(WowswapError::InvalidArgument)?;
+        let mut native_pc_qty_including_fees = pc_lot_limit_price
+            .and_then(|v| v.checked_mul_nonzero_token_qty(coin_qty))
+            .ok_or(WowswapError::InvalidArgument)?;
+        if let Some(max_pc_with_fees) = max_pc_with_fees {
+            require!(
+                max_pc_with_fees >= native_pc_qty_including_fees.as_token_amount(),
+                WowswapError::InvalidArgument
+            );
+            native_pc_qty_including_fees =
+                DexNonZeroTokenAmount::from_token_amount(max_pc_with_fees)
+                    .ok_or(WowswapError::InvalidArgument)?;
+        }
+        let native_pc_qty_trader = native_pc_qty_including_fees
+            .as_token_amount()
+            .safe_sub(native_pc_qty_loan);
+
+        Ok(SwapPositionOpenBreakdown {
+            coin_qty,
+            native_coin_qty,
+            native_pc_qty_loan,
+            native_pc_qty_trader,
+            native_pc_qty_including_fees,
+        })
+    }
+
+    // Largest `coin_qty` the trader can open with `trader_pc` of their own funds, rounded down to
+    // a whole lot. The trader funds `limit_price * coin_qty` whatever the leverage, so leverage is
+    // only validated here. Taker fees are not included, a trader relying on `max_pc_with_fees`
+    // has to keep a margin for them.
+    pub fn max_coin_qty(
+        trader_pc: TokenAmount,
+        leverage_factor: Factor,
+        limit_price: DexLimitPrice,
+        lot_sizes: dex::MarketLotSizes,
+        max_leverage_factor: Factor,
+    ) -> WowswapResult<DexNonZeroTokenQty> {
+        let pc_lot_limit_price = limit_price
+            .checked_mul_lot_size(lot_sizes.pc)
+            .ok_or(WowswapError::InvalidArgument)?
+            .as_token_amount();
+        let coin_qty = trader_pc
+            .checked_div(pc_lot_limit_price)
+            .and_then(DexNonZeroTokenQty::from_token_amount)
+            .ok_or(WowswapError::InvalidArgument)?;
+
+        // Same checks as on open
+        Self::breakdown(
+            coin_qty,
+            leverage_factor,
+            limit_price,
+            None,
+            lot_sizes,
+            max_leverage_factor,
+        )?;
+
+        Ok(coin_qty)
+    }
+
+    // Defense in depth against a compromised market: the average fill price, derived from the
+    // vault deltas, must not be worse than `limit_price` plus the allowed slippage (taker fees)
+    fn check_fill_price(
+        &self,
+        limit_price: DexLimitPrice,
+        lot_sizes: dex::MarketLotSizes,
+        coin_received: TokenAmount,
+        pc_spent: TokenAmount,
+    ) -> WowswapResultEmpty {
+        let max_slippage = self.governance.max_fill_slippage();
+        if max_slippage == Factor::new(0) {
+            return Ok(());
+        }
+
+        if coin_received.is_zero() {
+            return Ok(());
+        }
+
+        let pc_at_limit_price = coin_received
+            .into_wad()
+            .wad_mul(dex::price_to_pc_per_coin(limit_price, lot_sizes))
+            .as_token_amount()
+            .into_inner() as u128;
+        let pc_allowed = pc_at_limit_price
+            .checked_add(max_slippage.percentage_mul(pc_at_limit_price))
+            .expect("pc_allowed overflow");
+
+        if pc_spent.into_inner() as u128 > pc_allowed {
+            msg!(
+                "Fill price is worse than limit price. Spent: {:?}, allowed: {:?}.",
+                pc_spent,
+                pc_allowed
+            );
+            return Err(WowswapError::FillPriceExceeded.into());
+        }
+
+        Ok(())
+    }
+
+    fn take_reserve_funds(&self, amount: TokenAmount) -> WowswapResultEmpty {
+        token::transfer(
+            self.reserve_lendable_vault.to_account_info(),
+            self.swap_pc_vault.to_account_info(),
+            self.reserve_signer.clone(),
+            amount,
+            &[&self.reserve.signer_seeds((*self.reserve).as_ref().key)],
+        )
+    }
+
+    fn take_trader_funds(&self, amount: TokenAmount) -> WowswapResultEmpty {
         token::transfer(
             self.trader_pc_vault.to_account_info(),
             self.swap_pc_vault.to_account_info(),
@@ -462,7 +1667,7 @@ impl<'info> SwapPositionOpen<'info> {
         limit_price: DexLimitPrice,
         coin_qty: DexNonZeroTokenQty,
         max_native_pc_qty_including_fees: DexNonZeroTokenAmount,
-    ) -> ProgramResult {
+    ) -> WowswapResultEmpty {
         dex::buy(
             &self.dex_accounts,
             self.swap_coin_vault.to_account_info(),
@@ -471,17 +1676,17 @@ impl<'info> SwapPositionOpen<'info> {
             limit_price,
             coin_qty,
             max_native_pc_qty_including_fees,
-            &[&[(*self.swap).as_ref().key.as_ref(), &[self.swap.nonce]]],
+            &[&self.swap.signer_seeds((*self.swap).as_ref().key)],
         )
     }
 
-    fn return_reserve_funds(&self, amount: TokenAmount) -> ProgramResult {
+    fn return_reserve_funds(&self, amount: TokenAmount) -> WowswapResultEmpty {
         token::transfer(
             self.swap_pc_vault.to_account_info(),
             self.reserve_lendable_vault.to_account_info(),
             self.swap_signer.clone(),
             amount,
-            &[&[(*self.swap).as_ref().key.as_ref(), &[self.swap.nonce]]],
+            &[&self.swap.signer_seeds((*self.swap).as_ref().key)],
         )
     }
 
@@ -489,54 +1694,59 @@ impl<'info> SwapPositionOpen<'info> {
         &mut self,
         timestamp: UnixTimestamp,
         total_debt: TokenAmount,
+        liquidity: TokenAmount,
         amount: TokenAmount,
         rate_multiplier: Factor,
-    ) {
-        let reserve = &mut self.reserve;
-        let governance = &self.governance;
-        reserve.update_state(governance, total_debt, timestamp);
-
-        reserve.update_borrow_rate(
-            governance,
-            // We did not reload `reserve_lendable_vault` after transfers, so it's ok
-            TokenAmount::new(self.reserve_lendable_vault.amount),
-            TokenAmount::ZERO,
-            amount,
-            total_debt,
-            amount,
-            TokenAmount::ZERO,
-        );
-
-        reserve.increase_debt(
+    ) -> WowswapResultEmpty {
+        sim::borrow(
+            &mut self.reserve,
+            &self.governance,
             &mut self.position.state,
-            timestamp,
             total_debt,
+            // `liquidity` is from before the transfers, so it's ok
+            liquidity,
             amount,
             rate_multiplier,
-        );
+            timestamp,
+        )
     }
 
-    fn return_trader_funds(&self) -> ProgramResult {
+    fn return_trader_funds(&self, amount: TokenAmount) -> WowswapResultEmpty {
         token::transfer(
             self.swap_pc_vault.to_account_info(),
             self.trader_pc_vault.to_account_info(),
             self.swap_signer.clone(),
-            TokenAmount::new(self.swap_pc_vault.amount),
-            &[&[(*self.swap).as_ref().key.as_ref(), &[self.swap.nonce]]],
+            amount,
+            &[&self.swap.signer_seeds((*self.swap).as_ref().key)],
         )
     }
 
-    fn mint_proxy_token(&self, amount: TokenAmount) -> ProgramResult {
+    fn mint_proxy_token(&mut self, amount: TokenAmount) -> WowswapResultEmpty {
+        self.swap
+            .check_proxy_supply(TokenAmount::new(self.proxy_token_mint.supply), amount)?;
+
         token::mint_to(
             self.proxy_token_mint.to_account_info(),
             self.proxy_token_account.to_account_info(),
             self.swap_signer.clone(),
             amount,
-            &[&[(*self.swap).as_ref().key.as_ref(), &[self.swap.nonce]]],
-        )
+            &[&self.swap.signer_seeds((*self.swap).as_ref().key)],
+        )?;
+        self.swap.add_coin_outstanding(amount);
+        Ok(())
     }
 }
 
+#[event]
+pub struct PositionClosed {
+    pub swap: Pubkey,
+    pub trader: Pubkey,
+    pub proceeds: TokenAmount,
+    pub close_fee: TokenAmount,
+    // Remaining debt below `position_dust_threshold` moved to the reserve bad debt
+    pub dust_written_off: TokenAmount,
+}
+
 #[derive(Accounts)]
 pub struct SwapPositionClose<'info> {
     #[account(
@@ -567,10 +1777,18 @@ pub struct SwapPositionClose<'info> {
     swap_coin_vault: Box<Account<'info, TokenAccount>>,
     #[account(mut)]
     swap_pc_vault: Box<Account<'info, TokenAccount>>,
+    // `swap.fee_vault`, writable once it's set. Markets without one pass the default key.
+    #[account(constraint = swap.fee_vault == *swap_fee_vault.key)]
+    swap_fee_vault: AccountInfo<'info>,
 
     #[account(mut)]
     proxy_token_mint: Box<Account<'info, TokenMint>>,
-    #[account(mut)]
+    // Proxy tokens only ever sit in the swap signer owned account, nobody else may move them
+    #[account(
+        mut,
+        constraint = proxy_token_account.owner == swap.signer,
+        constraint = proxy_token_account.delegate.is_none(),
+    )]
     proxy_token_account: Box<Account<'info, TokenAccount>>,
 
     #[account(
@@ -587,7 +1805,12 @@ pub struct SwapPositionClose<'info> {
     governance: Box<Account<'info, Governance>>,
 
     trader: Signer<'info>,
-    #[account(mut, constraint = trader_pc_vault.owner == *trader.key)]
+    // Aliasing the swap vault would make the transfers in and out of it self-referencing
+    #[account(
+        mut,
+        constraint = trader_pc_vault.owner == *trader.key,
+        constraint = *(*trader_pc_vault).as_ref().key != *(*swap_pc_vault).as_ref().key,
+    )]
     trader_pc_vault: Box<Account<'info, TokenAccount>>,
 
     spl_token_program: Program<'info, SplToken>,
@@ -596,14 +1819,35 @@ pub struct SwapPositionClose<'info> {
 }
 
 impl<'info> SwapPositionClose<'info> {
+    // `remaining_accounts` may hold the trader index, see `TraderIndex::remove_from_accounts`
     pub fn handle(
         &mut self,
+        program_id: &Pubkey,
+        remaining_accounts: &[AccountInfo<'info>],
         limit_price: DexLimitPrice,
         coin_qty: DexNonZeroTokenQty,
     ) -> WowswapResultEmpty {
+        self.close(program_id, remaining_accounts, limit_price, coin_qty, false)?;
+        Ok(())
+    }
+
+    // With `roll` the repayment, the reserve part of the close fee and the proceeds are held in
+    // `swap_pc_vault` for the re-open and returned, they are accounted for as if paid out. A roll
+    // keeps the position open, so no interest is waived and no dust is written off.
+    fn close(
+        &mut self,
+        program_id: &Pubkey,
+        remaining_accounts: &[AccountInfo<'info>],
+        limit_price: DexLimitPrice,
+        coin_qty: DexNonZeroTokenQty,
+        roll: bool,
+    ) -> WowswapResult<SwapPositionRollFunds> {
         let timestamp = UnixTimestamp::now()?;
+        let mut held = SwapPositionRollFunds::default();
 
-        let lot_sizes = dex::market_lot_sizes(&self.dex_accounts)?;
+        self.governance.check_initialized()?;
+
+        let lot_sizes = self.dex_accounts.validate_and_load(&self.swap.dex_market)?;
         let native_coin_qty = coin_qty
             .checked_mul_lot_size(lot_sizes.coin)
             .ok_or(WowswapError::InvalidArgument)?;
@@ -615,11 +1859,31 @@ impl<'info> SwapPositionClose<'info> {
         self.burn_proxy_token(native_coin_qty.as_token_amount())?;
 
         self.make_swap(limit_price, coin_qty, native_pc_qty_including_fees)?;
+        self.settle_remaining_funds(lot_sizes)?;
         self.swap_pc_vault.reload()?;
 
-        let current_debt = self.position.state.get_debt(timestamp);
+        // The grace period only waives interest when this close repays the whole debt
+        let swap_pc_vault_balance = TokenAmount::new(self.swap_pc_vault.amount);
+        let compound_terms = self.governance.compound_terms();
+        let grace = if roll {
+            UnixTimestamp::ZERO
+        } else {
+            self.governance.position_grace_period()
+        };
+        let waived_debt = self
+            .position
+            .state
+            .get_debt(timestamp, grace, compound_terms);
+        let (current_debt, grace) = if waived_debt <= swap_pc_vault_balance {
+            (waived_debt, grace)
+        } else {
+            let current_debt =
+                self.position
+                    .state
+                    .get_debt(timestamp, UnixTimestamp::ZERO, compound_terms);
+            (current_debt, UnixTimestamp::ZERO)
+        };
         if current_debt > TokenAmount::ZERO {
-            let swap_pc_vault_balance = TokenAmount::new(self.swap_pc_vault.amount);
             let (debt_change, loan_change) = if current_debt > swap_pc_vault_balance {
                 let loan_change = math::liquidity::calculate_share(
                     swap_pc_vault_balance,
@@ -644,25 +1908,125 @@ impl<'info> SwapPositionClose<'info> {
                 .checked_sub(loan_change)
                 .expect("loan overflow");
 
-            self.return_reserve_funds(debt_change)?;
+            if roll {
+                held.reserve = debt_change;
+            } else {
+                self.return_reserve_funds(debt_change)?;
+                self.swap_pc_vault.reload()?;
+            }
+
+            self.reserve_update_state(timestamp, grace, debt_change, debt_change);
+        }
+        self.proxy_token_account.reload()?;
+        let dust_written_off = if roll {
+            TokenAmount::ZERO
+        } else {
+            self.write_off_dust(timestamp)?
+        };
+
+        // What is left in the vault is the trader's net proceeds
+        let close_fee = TokenAmount::from_u128(
+            self.governance.close_fee().percentage_mul(
+                TokenAmount::new(self.swap_pc_vault.amount)
+                    .safe_sub(held.reserve)
+                    .into_inner() as u128,
+            ),
+        );
+        if !close_fee.is_zero() {
+            let market_fee = self.swap.market_fee(close_fee, &self.governance);
+            let reserve_fee = close_fee.safe_sub(market_fee);
+            if !reserve_fee.is_zero() {
+                if roll {
+                    held.reserve = held
+                        .reserve
+                        .checked_add(reserve_fee)
+                        .expect("held reserve overflow");
+                } else {
+                    self.return_reserve_funds(reserve_fee)?;
+                }
+                if self.governance.close_fee_to_treasury() {
+                    self.reserve.add_treasure(reserve_fee);
+                }
+            }
+            if !market_fee.is_zero() {
+                self.take_market_fee(market_fee)?;
+                self.swap.accrue_fees(market_fee);
+            }
             self.swap_pc_vault.reload()?;
+        }
+        let proceeds = TokenAmount::new(self.swap_pc_vault.amount).safe_sub(held.reserve);
+        if roll {
+            held.trader = proceeds;
+        } else {
+            self.return_trader_funds()?;
+        }
 
-            self.reserve_update_state(timestamp, debt_change);
+        if self.proxy_token_account.amount == 0 {
+            self.position.state.last_closed = timestamp;
+            TraderIndex::remove_from_accounts(
+                program_id,
+                remaining_accounts,
+                self.trader.key,
+                (*self.swap).as_ref().key,
+            )?;
         }
 
-        self.return_trader_funds()?;
+        emit!(PositionClosed {
+            swap: *(*self.swap).as_ref().key,
+            trader: *self.trader.key,
+            proceeds,
+            close_fee,
+            dust_written_off,
+        });
 
-        Ok(())
+        Ok(held)
+    }
+
+    // A close that sells all the coins but falls short of the debt can leave a remainder too
+    // small to be worth chasing. Below `position_dust_threshold` it's written off the same way a
+    // force close does, lenders absorb it through the reserve bad debt. Returns the amount
+    // written off. `proxy_token_account` has to be reloaded after the burn.
+    fn write_off_dust(&mut self, timestamp: UnixTimestamp) -> WowswapResult<TokenAmount> {
+        let remaining_debt = self.position.state.get_debt(
+            timestamp,
+            UnixTimestamp::ZERO,
+            self.governance.compound_terms(),
+        );
+        let dust = SwapPosition::writable_dust(
+            remaining_debt,
+            TokenAmount::new(self.proxy_token_account.amount),
+            self.governance.position_dust_threshold(),
+        );
+        if dust.is_zero() {
+            return Ok(TokenAmount::ZERO);
+        }
+
+        self.swap.state.total_loan = self
+            .swap
+            .state
+            .total_loan
+            .checked_sub(self.position.state.loan)
+            .expect("total_loan overflow");
+        self.position.state.loan = TokenAmount::ZERO;
+
+        // Nothing is paid into the vault, reload it so the repayment of this close is counted once
+        self.reserve_lendable_vault.reload()?;
+        self.reserve_update_state(timestamp, UnixTimestamp::ZERO, dust, TokenAmount::ZERO);
+        self.reserve.add_bad_debt(dust);
+
+        Ok(dust)
     }
 
-    fn burn_proxy_token(&self, amount: TokenAmount) -> ProgramResult {
+    fn burn_proxy_token(&mut self, amount: TokenAmount) -> WowswapResultEmpty {
         token::burn(
             self.proxy_token_mint.to_account_info(),
             self.proxy_token_account.to_account_info(),
             self.swap_signer.clone(),
             amount,
-            &[&[(*self.swap).as_ref().key.as_ref(), &[self.swap.nonce]]],
-        )
+            &[&self.swap.signer_seeds((*self.swap).as_ref().key)],
+        )?;
+        self.swap.sub_coin_outstanding(amount);
+        Ok(())
     }
 
     fn make_swap(
@@ -670,7 +2034,7 @@ impl<'info> SwapPositionClose<'info> {
         limit_price: DexLimitPrice,
         coin_qty: DexNonZeroTokenQty,
         max_native_pc_qty_including_fees: DexNonZeroTokenAmount,
-    ) -> ProgramResult {
+    ) -> WowswapResultEmpty {
         dex::sell(
             &self.dex_accounts,
             self.swap_coin_vault.to_account_info(),
@@ -679,140 +2043,513 @@ impl<'info> SwapPositionClose<'info> {
             limit_price,
             coin_qty,
             max_native_pc_qty_including_fees,
-            &[&[(*self.swap).as_ref().key.as_ref(), &[self.swap.nonce]]],
+            &[&self.swap.signer_seeds((*self.swap).as_ref().key)],
+        )
+    }
+
+    // Proceeds should be settled by `make_swap` already, but if anything is left free in the open
+    // orders account it would be under-counted in the repayment below
+    fn settle_remaining_funds(&self, lot_sizes: dex::MarketLotSizes) -> WowswapResultEmpty {
+        let (native_coin_free, native_pc_free) =
+            dex::open_orders_free(&self.dex_accounts.open_orders.try_borrow_data()?)?;
+        if native_coin_free == 0 && native_pc_free == 0 {
+            return Ok(());
+        }
+
+        // Settling is only worth it for coins or pc above the dust threshold
+        if native_coin_free == 0 && native_pc_free <= lot_sizes.pc_dust_threshold {
+            msg!("Pc dust is left in open orders: {:?}.", native_pc_free);
+            return Ok(());
+        }
+
+        dex::settle_funds(
+            &self.dex_accounts,
+            self.swap_coin_vault.to_account_info(),
+            self.swap_pc_vault.to_account_info(),
+            self.swap_signer.clone(),
+            &[&self.swap.signer_seeds((*self.swap).as_ref().key)],
         )
     }
 
-    fn return_reserve_funds(&self, amount: TokenAmount) -> ProgramResult {
+    fn return_reserve_funds(&self, amount: TokenAmount) -> WowswapResultEmpty {
         token::transfer(
             self.swap_pc_vault.to_account_info(),
             self.reserve_lendable_vault.to_account_info(),
             self.swap_signer.clone(),
             amount,
-            &[&[(*self.swap).as_ref().key.as_ref(), &[self.swap.nonce]]],
+            &[&self.swap.signer_seeds((*self.swap).as_ref().key)],
         )
     }
 
-    fn reserve_update_state(&mut self, timestamp: UnixTimestamp, debt_change: TokenAmount) {
-        let reserve = &mut self.reserve;
-        let governance = &self.governance;
-
-        let total_debt = reserve.debt.get_total_debt(timestamp);
-        reserve.update_state(governance, total_debt, timestamp);
-
-        reserve.decrease_debt(&mut self.position.state, timestamp, total_debt, debt_change);
-
-        let total_debt = reserve.debt.get_total_debt(timestamp);
-        reserve.update_borrow_rate(
-            governance,
+    fn reserve_update_state(
+        &mut self,
+        timestamp: UnixTimestamp,
+        grace: UnixTimestamp,
+        debt_change: TokenAmount,
+        liquidity_returned: TokenAmount,
+    ) {
+        sim::repay(
+            &mut self.reserve,
+            &self.governance,
+            &mut self.position.state,
             // We did not reload `reserve_lendable_vault` after transfers, so it's ok
             TokenAmount::new(self.reserve_lendable_vault.amount),
             debt_change,
-            TokenAmount::ZERO,
-            total_debt,
-            TokenAmount::ZERO,
-            TokenAmount::ZERO,
+            liquidity_returned,
+            grace,
+            timestamp,
         );
     }
 
-    fn return_trader_funds(&self) -> ProgramResult {
+    fn return_trader_funds(&self) -> WowswapResultEmpty {
         token::transfer(
             self.swap_pc_vault.to_account_info(),
             self.trader_pc_vault.to_account_info(),
             self.swap_signer.clone(),
             TokenAmount::new(self.swap_pc_vault.amount),
-            &[&[(*self.swap).as_ref().key.as_ref(), &[self.swap.nonce]]],
+            &[&self.swap.signer_seeds((*self.swap).as_ref().key)],
+        )
+    }
+    fn take_market_fee(&self, amount: TokenAmount) -> WowswapResultEmpty {
+        token::transfer(
+            self.swap_pc_vault.to_account_info(),
+            self.swap_fee_vault.clone(),
+            self.swap_signer.clone(),
+            amount,
+            &[&self.swap.signer_seeds((*self.swap).as_ref().key)],
         )
     }
 }
 
-#[derive(Accounts)]
-pub struct SwapPositionLiquidate<'info> {
-    #[account(
-        mut,
-        has_one = swap,
-        has_one = trader,
-        has_one = proxy_token_account,
-        seeds = [
-            (*swap).as_ref().key.as_ref(),
-            trader.key.as_ref()
-        ],
-        bump = position.nonce,
-    )]
-    position: Box<Account<'info, SwapPosition>>,
+// Funds a roll's close holds in `swap_pc_vault` for the re-open instead of paying them out
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SwapPositionRollFunds {
+    // Debt repaid and the reserve part of the close fee, owed to `reserve_lendable_vault`
+    pub reserve: TokenAmount,
+    // Close proceeds, owed to `trader_pc_vault`
+    pub trader: TokenAmount,
+}
 
-    #[account(
-        mut,
-        constraint = swap.signer == *swap_signer.key,
-        has_one = reserve,
-        constraint = swap.coin_vault == *(*swap_coin_vault).as_ref().key,
-        constraint = swap.pc_vault == *(*swap_pc_vault).as_ref().key,
-        has_one = proxy_token_mint,
-    )]
-    swap: Box<Account<'info, Swap>>,
-    swap_signer: AccountInfo<'info>,
+impl SwapPositionRollFunds {
+    // Splits `amount` to be paid into `swap_pc_vault` into the part `held` doesn't cover, to be
+    // transferred in, and what is left of `held` to be paid back
+    pub fn net(held: TokenAmount, amount: TokenAmount) -> (TokenAmount, TokenAmount) {
+        (
+            amount.checked_sub(held).unwrap_or(TokenAmount::ZERO),
+            held.checked_sub(amount).unwrap_or(TokenAmount::ZERO),
+        )
+    }
+}
 
-    #[account(mut)]
-    swap_coin_vault: Box<Account<'info, TokenAccount>>,
-    #[account(mut)]
-    swap_pc_vault: Box<Account<'info, TokenAccount>>,
+// Closes `coin_qty` of the position and opens it again at `open_limit_price` in one instruction,
+// so the trader is never left unhedged between two transactions. `remaining_accounts` must hold
+// the `swap_position_close` accounts followed by the `swap_position_open` accounts, and the
+// trader credential on permissioned markets.
+// The accounting is that of a close followed by an open, the debt is repaid and borrowed again
+// at the current rate, without a grace period. Funds are netted: the close holds the repayment
+// and its proceeds in `swap_pc_vault`, the open spends them first, and only the difference
+// between the repayment and the new loan moves to or from the reserve, and between the proceeds
+// and the trader's part to or from `trader_pc_vault`. Both legs trade against the book, so the
+// round trip pays the spread and taker fees twice and each leg is bounded by its own limit price
+// only. The re-open isn't subject to the position cooldown. The position keeps its term.
+#[derive(Accounts)]
+pub struct SwapPositionRoll<'info> {
+    trader: Signer<'info>,
+}
 
-    #[account(mut)]
-    proxy_token_mint: Box<Account<'info, TokenMint>>,
-    #[account(mut)]
-    proxy_token_account: Box<Account<'info, TokenAccount>>,
+impl<'info> SwapPositionRoll<'info> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn handle(
+        &mut self,
+        program_id: &Pubkey,
+        remaining_accounts: &[AccountInfo<'info>],
+        close_limit_price: DexLimitPrice,
+        open_limit_price: DexLimitPrice,
+        coin_qty: DexNonZeroTokenQty,
+        leverage_factor: Factor,
+        max_pc_with_fees: Option<TokenAmount>,
+    ) -> WowswapResultEmpty {
+        let mut accounts = remaining_accounts;
 
-    #[account(
-        mut,
-        constraint = reserve.signer == *reserve_signer.key,
-        constraint = reserve.lendable_vault == *(*reserve_lendable_vault).as_ref().key,
-    )]
-    reserve: Box<Account<'info, Reserve>>,
-    reserve_signer: AccountInfo<'info>,
-    #[account(mut)]
-    reserve_lendable_vault: Box<Account<'info, TokenAccount>>,
+        let mut close = SwapPositionClose::try_accounts(program_id, &mut accounts, &[])?;
+        require!(
+            *close.trader.key == *self.trader.key,
+            WowswapError::InvalidArgument
+        );
+        let SwapPositionState {
+            opened_at,
+            max_duration,
+            ..
+        } = close.position.state;
+        let held = close.close(program_id, accounts, close_limit_price, coin_qty, true)?;
+        close.exit(program_id)?;
+
+        let mut open = SwapPositionOpen::try_accounts(program_id, &mut accounts, &[])?;
+        require!(
+            *(*open.position).as_ref().key == *(*close.position).as_ref().key,
+            WowswapError::InvalidArgument
+        );
+        open.open(
+            &mut accounts,
+            open_limit_price,
+            coin_qty,
+            leverage_factor,
+            max_pc_with_fees,
+            max_duration,
+            0,
+            Some(held),
+        )?;
+        open.position.state.opened_at = opened_at;
+        open.position.state.max_duration = max_duration;
+        open.exit(program_id)?;
 
-    #[account(constraint = *(*governance).as_ref().key == governance::ID)]
-    governance: Box<Account<'info, Governance>>,
+        Ok(())
+    }
+}
 
-    trader: AccountInfo<'info>,
+// Closes `coin_qty` of the position and spends the proceeds on the coin of a second market
+// (`route`) whose quote is the swap's pc, delivering it to `trader_output_vault`.
+// `remaining_accounts` must hold the `swap_position_close` accounts, optionally followed by the
+// trader index. The second leg is a trader order: `route.open_orders` belongs to the trader and
+// the proceeds are paid from `trader_pc_vault`, the same account the close pays into.
+// Each leg is bounded by its own limit price, and the second one also by `min_output`. The second
+// leg is immediate-or-cancel, so when it fills partially the pc it didn't spend is settled back to
+// `trader_pc_vault` and the trader ends up holding both tokens. If less than `min_output` is
+// bought the whole instruction fails and the position stays open.
+#[derive(Accounts)]
+pub struct SwapPositionCloseAndRoute<'info> {
+    trader: Signer<'info>,
     #[account(mut, constraint = trader_pc_vault.owner == *trader.key)]
     trader_pc_vault: Box<Account<'info, TokenAccount>>,
-
-    liquidator: Signer<'info>,
     #[account(
         mut,
-        constraint = liquidator_pc_vault.mint == trader_pc_vault.mint,
-        constraint = liquidator_pc_vault.owner == *liquidator.key,
-        constraint = token::check_associated_address(&liquidator_pc_vault.mint, &liquidator, &liquidator_pc_vault),
+        constraint = trader_output_vault.owner == *trader.key,
+        constraint = *(*trader_output_vault).as_ref().key != *(*trader_pc_vault).as_ref().key,
     )]
-    liquidator_pc_vault: Box<Account<'info, TokenAccount>>,
+    trader_output_vault: Box<Account<'info, TokenAccount>>,
 
-    spl_token_program: Program<'info, SplToken>,
+    route: DexAccounts<'info>,
+}
+
+impl<'info> SwapPositionCloseAndRoute<'info> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn handle(
+        &mut self,
+        program_id: &Pubkey,
+        remaining_accounts: &[AccountInfo<'info>],
+        close_limit_price: DexLimitPrice,
+        coin_qty: DexNonZeroTokenQty,
+        route_limit_price: DexLimitPrice,
+        route_max_coin_qty: DexNonZeroTokenQty,
+        min_output: TokenAmount,
+    ) -> WowswapResultEmpty {
+        self.validate_route()?;
+
+        let mut accounts = remaining_accounts;
+
+        let mut close = SwapPositionClose::try_accounts(program_id, &mut accounts, &[])?;
+        require!(
+            *close.trader.key == *self.trader.key,
+            WowswapError::InvalidArgument
+        );
+        require!(
+            *(*close.trader_pc_vault).as_ref().key == *(*self.trader_pc_vault).as_ref().key,
+            WowswapError::InvalidArgument
+        );
+        let pc_before = TokenAmount::new(close.trader_pc_vault.amount);
+        close.handle(program_id, accounts, close_limit_price, coin_qty)?;
+        close.exit(program_id)?;
+
+        self.trader_pc_vault.reload()?;
+        let proceeds = TokenAmount::new(self.trader_pc_vault.amount)
+            .checked_sub(pc_before)
+            .unwrap_or(TokenAmount::ZERO);
+        let max_pc = match DexNonZeroTokenAmount::from_token_amount(proceeds) {
+            Some(max_pc) => max_pc,
+            // Nothing left for the trader after repaying the loan
+            None => {
+                require!(min_output.is_zero(), WowswapError::FillPriceExceeded);
+                return Ok(());
+            }
+        };
+
+        let output_before = TokenAmount::new(self.trader_output_vault.amount);
+        dex::buy(
+            &self.route,
+            self.trader_output_vault.to_account_info(),
+            self.trader_pc_vault.to_account_info(),
+            self.trader.to_account_info(),
+            route_limit_price,
+            route_max_coin_qty,
+            max_pc,
+            &[],
+        )?;
+        self.trader_output_vault.reload()?;
+
+        let output = TokenAmount::new(self.trader_output_vault.amount)
+            .checked_sub(output_before)
+            .unwrap_or(TokenAmount::ZERO);
+        if output < min_output {
+            msg!(
+                "Route output is below minimum. Output: {:?}, minimum: {:?}.",
+                output,
+                min_output
+            );
+            return Err(WowswapError::FillPriceExceeded.into());
+        }
+
+        Ok(())
+    }
+
+    fn validate_route(&self) -> ProgramResult {
+        let market = MarketState::load(&self.route.market, self.route.dex_program.key)?;
+
+        require!(
+            identity(market.pc_mint) == self.trader_pc_vault.mint.to_aligned_bytes(),
+            WowswapError::InvalidMint
+        );
+        require!(
+            identity(market.coin_mint) == self.trader_output_vault.mint.to_aligned_bytes(),
+            WowswapError::InvalidMint
+        );
+
+        Ok(())
+    }
+}
+
+// Returns the rent of an empty position to the trader. The account holds the cooldown and the
+// last client nonce, so it can only be reclaimed once the cooldown is over, otherwise a reclaim
+// and a fresh initialize would reset both.
+#[derive(Accounts)]
+pub struct SwapPositionReclaim<'info> {
+    #[account(
+        mut,
+        has_one = swap,
+        has_one = trader,
+        has_one = proxy_token_account,
+        seeds = [
+            (*swap).as_ref().key.as_ref(),
+            trader.key.as_ref()
+        ],
+        bump = position.nonce,
+        constraint = position.state.loan.is_zero(),
+        constraint = position.state.amount.is_zero(),
+        close = trader,
+    )]
+    position: Box<Account<'info, SwapPosition>>,
+
+    swap: Box<Account<'info, Swap>>,
+
+    #[account(mut)]
+    trader: Signer<'info>,
+
+    #[account(constraint = proxy_token_account.amount == 0)]
+    proxy_token_account: Box<Account<'info, TokenAccount>>,
+
+    #[account(constraint = *(*governance).as_ref().key == governance::ID)]
+    governance: Box<Account<'info, Governance>>,
+}
+
+impl<'info> SwapPositionReclaim<'info> {
+    pub fn handle(&self) -> WowswapResultEmpty {
+        self.governance.check_initialized()?;
+        let timestamp = UnixTimestamp::now()?;
+
+        require!(
+            !self
+                .position
+                .state
+                .is_cooling_down(timestamp, self.governance.position_cooldown()),
+            WowswapError::PositionCooldown
+        );
+
+        Ok(())
+    }
+}
+
+// Accounts a liquidation takes from `remaining_accounts` when rewards are paid in redeemable
+// tokens, so liquidators of markets paying in pc don't have to pass them
+#[derive(Accounts)]
+pub struct LiquidatorRedeemableAccounts<'info> {
+    #[account(mut)]
+    reserve_redeemable_mint: Box<Account<'info, TokenMint>>,
+    #[account(mut)]
+    liquidator_redeemable_vault: Box<Account<'info, TokenAccount>>,
+}
+
+impl<'info> LiquidatorRedeemableAccounts<'info> {
+    fn check(&self, reserve: &Reserve, liquidator: &Pubkey) -> WowswapResultEmpty {
+        require!(
+            *(*self.reserve_redeemable_mint).as_ref().key == reserve.redeemable_mint
+                && self.liquidator_redeemable_vault.mint == reserve.redeemable_mint
+                && self.liquidator_redeemable_vault.owner == *liquidator,
+            WowswapError::InvalidArgument
+        );
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct SwapPositionLiquidate<'info> {
+    #[account(
+        mut,
+        has_one = swap,
+        has_one = trader,
+        has_one = proxy_token_account,
+        seeds = [
+            (*swap).as_ref().key.as_ref(),
+            trader.key.as_ref()
+        ],
+        bump = position.nonce,
+    )]
+    position: Box<Account<'info, SwapPosition>>,
+
+    #[account(
+        mut,
+        constraint = swap.signer == *swap_signer.key,
+        has_one = reserve,
+        constraint = swap.coin_vault == *(*swap_coin_vault).as_ref().key,
+        constraint = swap.pc_vault == *(*swap_pc_vault).as_ref().key,
+        has_one = proxy_token_mint,
+    )]
+    swap: Box<Account<'info, Swap>>,
+    swap_signer: AccountInfo<'info>,
+
+    #[account(mut)]
+    swap_coin_vault: Box<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    swap_pc_vault: Box<Account<'info, TokenAccount>>,
+
+    #[account(mut)]
+    proxy_token_mint: Box<Account<'info, TokenMint>>,
+    #[account(mut, constraint = proxy_token_account.owner == swap.signer)]
+    proxy_token_account: Box<Account<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        constraint = reserve.signer == *reserve_signer.key,
+        constraint = reserve.lendable_vault == *(*reserve_lendable_vault).as_ref().key,
+    )]
+    reserve: Box<Account<'info, Reserve>>,
+    reserve_signer: AccountInfo<'info>,
+    #[account(mut)]
+    reserve_lendable_vault: Box<Account<'info, TokenAccount>>,
+
+    #[account(constraint = *(*governance).as_ref().key == governance::ID)]
+    governance: Box<Account<'info, Governance>>,
+
+    trader: AccountInfo<'info>,
+    // Aliasing the swap vault would make the transfers in and out of it self-referencing
+    #[account(
+        mut,
+        constraint = trader_pc_vault.owner == *trader.key,
+        constraint = *(*trader_pc_vault).as_ref().key != *(*swap_pc_vault).as_ref().key,
+    )]
+    trader_pc_vault: Box<Account<'info, TokenAccount>>,
+
+    liquidator: Signer<'info>,
+    #[account(
+        mut,
+        constraint = liquidator_pc_vault.mint == trader_pc_vault.mint,
+        constraint = liquidator_pc_vault.owner == *liquidator.key,
+        constraint = token::check_associated_address(&liquidator_pc_vault.mint, &liquidator, &liquidator_pc_vault),
+        constraint = *(*liquidator_pc_vault).as_ref().key != *(*swap_pc_vault).as_ref().key,
+        constraint = *(*liquidator_pc_vault).as_ref().key != *(*trader_pc_vault).as_ref().key,
+    )]
+    liquidator_pc_vault: Box<Account<'info, TokenAccount>>,
+
+    spl_token_program: Program<'info, SplToken>,
 
     dex_accounts: DexAccounts<'info>,
 }
 
 impl<'info> SwapPositionLiquidate<'info> {
-    pub fn handle(&mut self) -> WowswapResultEmpty {
+    // Unlike open and close this doesn't check `max_accrual_age`, a stale reserve must never keep
+    // an unhealthy position open. `remaining_accounts` holds the `LiquidatorRedeemableAccounts`
+    // when rewards are paid in redeemable tokens, followed by the trader coin vault when the
+    // position is below one coin lot. It may also hold the trader index, see
+    // `TraderIndex::remove_from_accounts`.
+    pub fn handle(
+        &mut self,
+        program_id: &Pubkey,
+        remaining_accounts: &[AccountInfo<'info>],
+    ) -> WowswapResultEmpty {
+        let timestamp = UnixTimestamp::now()?;
+
+        let mut accounts = remaining_accounts;
+        let redeemable = if self.governance.liquidation_reward_redeemable() {
+            let redeemable =
+                LiquidatorRedeemableAccounts::try_accounts(program_id, &mut accounts, &[])?;
+            redeemable.check(&self.reserve, self.liquidator.key)?;
+            Some(redeemable)
+        } else {
+            None
+        };
+
+        self.close_out(timestamp, false, redeemable.as_ref(), accounts.first())?;
+        TraderIndex::remove_from_accounts(
+            program_id,
+            remaining_accounts,
+            self.trader.key,
+            (*self.swap).as_ref().key,
+        )
+    }
+
+    // Keeper close of a position past its term, allowed regardless of its health. The keeper is
+    // paid `keeper_close_fee` instead of the liquidation reward. `remaining_accounts` holds the
+    // trader coin vault when the position is below one coin lot, and may hold the trader index.
+    pub fn handle_expired(
+        &mut self,
+        program_id: &Pubkey,
+        remaining_accounts: &[AccountInfo<'info>],
+    ) -> WowswapResultEmpty {
         let timestamp = UnixTimestamp::now()?;
+        require!(
+            self.position.state.is_expired(timestamp),
+            WowswapError::PositionNotExpired
+        );
+        self.close_out(timestamp, true, None, remaining_accounts.first())?;
+        TraderIndex::remove_from_accounts(
+            program_id,
+            remaining_accounts,
+            self.trader.key,
+            (*self.swap).as_ref().key,
+        )
+    }
+
+    // `redeemable` is only passed to liquidations, and only when rewards are paid in redeemable
+    // tokens. `trader_coin_vault` is only read when the position is below one coin lot.
+    fn close_out(
+        &mut self,
+        timestamp: UnixTimestamp,
+        expired: bool,
+        redeemable: Option<&LiquidatorRedeemableAccounts<'info>>,
+        trader_coin_vault: Option<&AccountInfo<'info>>,
+    ) -> WowswapResultEmpty {
+        self.governance.check_initialized()?;
 
+        // Lowest possible price, sells at whatever the book bids. A constant, never user input.
         let limit_price = DexLimitPrice::new(1).expect("Invalid DexLimitPrice");
-        let current_debt = self.position.state.get_debt(timestamp);
-        let liqudation_cost = current_debt
-            .checked_add(TokenAmount::from_u128(
-                self.governance
-                    .liquidation_margin()
-                    .percentage_mul(current_debt.into_inner() as u128),
-            ))
-            .expect("token amount overflow");
+        let current_debt = self.position.state.get_debt(
+            timestamp,
+            UnixTimestamp::ZERO,
+            self.governance.compound_terms(),
+        );
+        let liqudation_cost = SwapPosition::liquidation_threshold(current_debt, &self.governance);
 
-        let lot_sizes = dex::market_lot_sizes(&self.dex_accounts)?;
+        let lot_sizes = self.dex_accounts.validate_and_load(&self.swap.dex_market)?;
         let native_coin_qty = TokenAmount::new(self.proxy_token_account.amount);
-        let coin_qty = native_coin_qty
-            .checked_div(TokenAmount::new(lot_sizes.coin))
-            .and_then(DexNonZeroTokenQty::from_token_amount)
-            .expect("invalid position");
+        let coin_qty = match SwapPosition::sellable_coin_lots(native_coin_qty, lot_sizes.coin) {
+            Some(coin_qty) => coin_qty,
+            None => {
+                return self.liquidate_dust(
+                    timestamp,
+                    current_debt,
+                    native_coin_qty,
+                    trader_coin_vault,
+                )
+            }
+        };
         let native_pc_qty_including_fees = limit_price
             .checked_mul_lot_size(lot_sizes.pc)
             .and_then(|v| v.checked_mul_nonzero_token_qty(coin_qty))
@@ -821,10 +2558,14 @@ impl<'info> SwapPositionLiquidate<'info> {
         self.burn_proxy_token(native_coin_qty)?;
 
         self.make_swap(limit_price, coin_qty, native_pc_qty_including_fees)?;
+        let dust = dex::open_orders_pc_dust(&self.dex_accounts.open_orders, lot_sizes)?;
+        if !dust.is_zero() {
+            msg!("Pc dust is left in open orders: {:?}.", dust);
+        }
         self.swap_pc_vault.reload()?;
 
         let amount_output = TokenAmount::new(self.swap_pc_vault.amount);
-        if amount_output > liqudation_cost {
+        if !expired && amount_output > liqudation_cost {
             msg!(
                 "Trying to liquidate healthy position. Output amount: {:?}, liquidation cost: {:?}.",
                 amount_output,
@@ -833,14 +2574,88 @@ impl<'info> SwapPositionLiquidate<'info> {
             return Err(WowswapError::LiquidateHealthyPosition.into());
         }
 
-        let amount_left = self.pay_liquidation_reward(amount_output)?;
-        match amount_left.checked_sub(current_debt) {
-            Some(trader_amount) if !trader_amount.is_zero() => {
-                self.return_reserve_funds(current_debt)?;
-                self.return_trader_funds(trader_amount)?
-            }
-            Some(_) | None => self.return_reserve_funds(amount_left)?,
+        let reward = if expired {
+            SwapPosition::keeper_close_fee(&self.governance, amount_output, current_debt)
+        } else {
+            SwapPosition::liquidation_reward(&self.governance, amount_output, current_debt)
         };
+        let (reward_deposited, treasury_share, insurance_share) =
+            self.distribute_liquidation_proceeds(timestamp, reward, expired, redeemable)?;
+
+        let amount_left = amount_output
+            .checked_sub(reward)
+            .expect("liquidation amount overflow");
+        let (repaid, trader_amount, shortfall) =
+            SwapPosition::settle_liquidation(amount_left, current_debt);
+        self.return_reserve_funds(repaid)?;
+        if !trader_amount.is_zero() {
+            self.return_trader_funds(trader_amount)?;
+        }
+        // Shortfall is written off, lenders bear it until the bad debt is covered
+        self.reserve.add_bad_debt(shortfall);
+
+        self.swap.state.total_loan = self
+            .swap
+            .state
+            .total_loan
+            .checked_sub(self.position.state.loan)
+            .expect("total_loan overflow");
+        self.position.state.loan = TokenAmount::ZERO;
+
+        self.reserve_update_state(
+            timestamp,
+            current_debt,
+            current_debt
+                .checked_add(reward_deposited)
+                .expect("liquidity overflow"),
+        );
+        self.reserve.add_treasure(treasury_share);
+        self.reserve.add_insurance(insurance_share);
+
+        self.position.state.last_closed = timestamp;
+
+        Ok(())
+    }
+
+    // A position below one coin lot can't be sold on the dex. Its coins go back to the trader
+    // associated coin account, which the caller may have to create first, and the whole debt is
+    // written off as bad debt.
+    fn liquidate_dust(
+        &mut self,
+        timestamp: UnixTimestamp,
+        current_debt: TokenAmount,
+        native_coin_qty: TokenAmount,
+        trader_coin_vault: Option<&AccountInfo<'info>>,
+    ) -> WowswapResultEmpty {
+        require!(
+            !current_debt.is_zero(),
+            WowswapError::LiquidateHealthyPosition
+        );
+
+        if !native_coin_qty.is_zero() {
+            let trader_coin_vault = trader_coin_vault.ok_or(WowswapError::InvalidArgument)?;
+            require!(
+                *trader_coin_vault.key
+                    == spl_associated_token_account::get_associated_token_address(
+                        self.trader.key,
+                        &self.swap.coin_mint
+                    ),
+                WowswapError::InvalidArgument
+            );
+            self.burn_proxy_token(native_coin_qty)?;
+            token::transfer(
+                self.swap_coin_vault.to_account_info(),
+                trader_coin_vault.clone(),
+                self.swap_signer.clone(),
+                native_coin_qty,
+                &[&self.swap.signer_seeds((*self.swap).as_ref().key)],
+            )?;
+        }
+        msg!(
+            "Liquidating sub-lot position. Coin amount: {:?}, debt written off: {:?}.",
+            native_coin_qty,
+            current_debt
+        );
 
         self.swap.state.total_loan = self
             .swap
@@ -850,19 +2665,24 @@ impl<'info> SwapPositionLiquidate<'info> {
             .expect("total_loan overflow");
         self.position.state.loan = TokenAmount::ZERO;
 
-        self.reserve_update_state(timestamp, current_debt);
+        self.reserve_update_state(timestamp, current_debt, TokenAmount::ZERO);
+        self.reserve.add_bad_debt(current_debt);
+
+        self.position.state.last_closed = timestamp;
 
         Ok(())
     }
 
-    fn burn_proxy_token(&self, amount: TokenAmount) -> ProgramResult {
+    fn burn_proxy_token(&mut self, amount: TokenAmount) -> WowswapResultEmpty {
         token::burn(
             self.proxy_token_mint.to_account_info(),
             self.proxy_token_account.to_account_info(),
             self.swap_signer.clone(),
             amount,
-            &[&[(*self.swap).as_ref().key.as_ref(), &[self.swap.nonce]]],
-        )
+            &[&self.swap.signer_seeds((*self.swap).as_ref().key)],
+        )?;
+        self.swap.sub_coin_outstanding(amount);
+        Ok(())
     }
 
     fn make_swap(
@@ -870,7 +2690,7 @@ impl<'info> SwapPositionLiquidate<'info> {
         limit_price: DexLimitPrice,
         coin_qty: DexNonZeroTokenQty,
         max_native_pc_qty_including_fees: DexNonZeroTokenAmount,
-    ) -> ProgramResult {
+    ) -> WowswapResultEmpty {
         dex::sell(
             &self.dex_accounts,
             self.swap_coin_vault.to_account_info(),
@@ -879,73 +2699,1447 @@ impl<'info> SwapPositionLiquidate<'info> {
             limit_price,
             coin_qty,
             max_native_pc_qty_including_fees,
-            &[&[(*self.swap).as_ref().key.as_ref(), &[self.swap.nonce]]],
+            &[&self.swap.signer_seeds((*self.swap).as_ref().key)],
         )
     }
 
-    fn pay_liquidation_reward(&self, amount: TokenAmount) -> Result<TokenAmount, ProgramError> {
-        let max_reward = self.governance.max_liquidation_reward();
-        let mut reward = TokenAmount::from_u128(
-            self.governance
-                .liquidation_reward()
-                .percentage_mul(amount.into_inner() as u128),
-        );
-        if !max_reward.is_zero() && max_reward < reward {
-            reward = max_reward;
+    // Pays out a liquidation reward as split by `SwapPosition::split_liquidation_reward`. The
+    // treasury and insurance parts are paid into the reserve vault, the caller credits them once
+    // the reserve state is updated. The liquidator is paid in redeemable tokens when `redeemable`
+    // is passed.
+    // Returns what was paid into the reserve vault and the treasury and insurance parts.
+    fn distribute_liquidation_proceeds(
+        &self,
+        timestamp: UnixTimestamp,
+        reward: TokenAmount,
+        expired: bool,
+        redeemable: Option<&LiquidatorRedeemableAccounts<'info>>,
+    ) -> WowswapResult<(TokenAmount, TokenAmount, TokenAmount)> {
+        let (liquidator_share, treasury_share, insurance_share) =
+            SwapPosition::split_liquidation_reward(&self.governance, reward, expired);
+        let protocol_share = treasury_share
+            .checked_add(insurance_share)
+            .expect("liquidation reward overflow");
+
+        let mut deposited = TokenAmount::ZERO;
+        if !liquidator_share.is_zero() {
+            if let Some(redeemable) = redeemable {
+                self.mint_liquidation_reward(redeemable, timestamp, liquidator_share)?;
+                deposited = liquidator_share;
+            } else {
+                token::transfer(
+                    self.swap_pc_vault.to_account_info(),
+                    self.liquidator_pc_vault.to_account_info(),
+                    self.swap_signer.clone(),
+                    liquidator_share,
+                    &[&self.swap.signer_seeds((*self.swap).as_ref().key)],
+                )?;
+            }
+        }
+        // After the redeemable mint, which prices shares off the vault balance
+        if !protocol_share.is_zero() {
+            self.return_reserve_funds(protocol_share)?;
+            deposited = deposited
+                .checked_add(protocol_share)
+                .expect("liquidity overflow");
         }
 
-        token::transfer(
-            self.swap_pc_vault.to_account_info(),
-            self.liquidator_pc_vault.to_account_info(),
-            self.swap_signer.clone(),
-            reward,
-            &[&[(*self.swap).as_ref().key.as_ref(), &[self.swap.nonce]]],
-        )?;
+        Ok((deposited, treasury_share, insurance_share))
+    }
 
-        Ok(amount
-            .checked_sub(reward)
-            .expect("liquidation amount overflow"))
+    // The reward is deposited into the reserve on the liquidator's behalf, so they receive
+    // redeemable tokens worth it at the current exchange rate. Has to run before any other
+    // transfer into the reserve vault.
+    fn mint_liquidation_reward(
+        &self,
+        redeemable: &LiquidatorRedeemableAccounts<'info>,
+        timestamp: UnixTimestamp,
+        amount: TokenAmount,
+    ) -> WowswapResultEmpty {
+        let total_debt = self
+            .reserve
+            .debt
+            .get_total_debt(timestamp, self.governance.compound_terms());
+        let total_liquidity = self.reserve.get_total_liquidity(
+            total_debt,
+            TokenAmount::new(self.reserve_lendable_vault.amount),
+        );
+        let mint_amount = math::liquidity::mint_amount(
+            amount,
+            TokenAmount::new(redeemable.reserve_redeemable_mint.supply),
+            total_liquidity,
+            self.reserve.decimals_shift,
+        )
+        .ok_or(WowswapError::InvalidArgument)?;
+
+        self.return_reserve_funds(amount)?;
+        token::mint_to(
+            redeemable.reserve_redeemable_mint.to_account_info(),
+            redeemable.liquidator_redeemable_vault.to_account_info(),
+            self.reserve_signer.clone(),
+            mint_amount,
+            &[&self.reserve.signer_seeds((*self.reserve).as_ref().key)],
+        )
     }
 
-    fn return_reserve_funds(&self, amount: TokenAmount) -> ProgramResult {
+    fn return_reserve_funds(&self, amount: TokenAmount) -> WowswapResultEmpty {
         token::transfer(
             self.swap_pc_vault.to_account_info(),
             self.reserve_lendable_vault.to_account_info(),
             self.swap_signer.clone(),
             amount,
-            &[&[(*self.swap).as_ref().key.as_ref(), &[self.swap.nonce]]],
+            &[&self.swap.signer_seeds((*self.swap).as_ref().key)],
         )
     }
 
-    fn return_trader_funds(&self, amount: TokenAmount) -> ProgramResult {
+    fn return_trader_funds(&self, amount: TokenAmount) -> WowswapResultEmpty {
         token::transfer(
             self.swap_pc_vault.to_account_info(),
             self.trader_pc_vault.to_account_info(),
             self.swap_signer.clone(),
             amount,
-            &[&[(*self.swap).as_ref().key.as_ref(), &[self.swap.nonce]]],
+            &[&self.swap.signer_seeds((*self.swap).as_ref().key)],
         )
     }
 
-    fn reserve_update_state(&mut self, timestamp: UnixTimestamp, debt_change: TokenAmount) {
-        let reserve = &mut self.reserve;
-        let governance = &self.governance;
-
-        let total_debt = reserve.debt.get_total_debt(timestamp);
-        reserve.update_state(governance, total_debt, timestamp);
-
-        reserve.decrease_debt(&mut self.position.state, timestamp, total_debt, debt_change);
-
-        let total_debt = reserve.debt.get_total_debt(timestamp);
-        reserve.update_borrow_rate(
-            governance,
+    fn reserve_update_state(
+        &mut self,
+        timestamp: UnixTimestamp,
+        debt_change: TokenAmount,
+        liquidity_returned: TokenAmount,
+    ) {
+        sim::repay(
+            &mut self.reserve,
+            &self.governance,
+            &mut self.position.state,
             // We did not reload `reserve_lendable_vault` after transfers, so it's ok
             TokenAmount::new(self.reserve_lendable_vault.amount),
             debt_change,
+            liquidity_returned,
+            UnixTimestamp::ZERO,
+            timestamp,
+        );
+    }
+}
+
+#[event]
+pub struct PositionForceClosed {
+    pub swap: Pubkey,
+    pub trader: Pubkey,
+    pub coin_returned: TokenAmount,
+    pub debt_written_off: TokenAmount,
+}
+
+// Emergency backstop for positions that can't be sold on a blocked market. The position's coins
+// are handed to the trader as is and its whole debt is written off as bad debt.
+#[derive(Accounts)]
+pub struct SwapPositionForceClose<'info> {
+    #[account(
+        mut,
+        has_one = swap,
+        has_one = trader,
+        has_one = proxy_token_account,
+    )]
+    position: Box<Account<'info, SwapPosition>>,
+
+    #[account(
+        mut,
+        constraint = swap.signer == *swap_signer.key,
+        has_one = reserve,
+        constraint = swap.coin_vault == *(*swap_coin_vault).as_ref().key,
+        has_one = proxy_token_mint,
+    )]
+    swap: Box<Account<'info, Swap>>,
+    swap_signer: AccountInfo<'info>,
+    #[account(mut)]
+    swap_coin_vault: Box<Account<'info, TokenAccount>>,
+
+    #[account(mut)]
+    proxy_token_mint: Box<Account<'info, TokenMint>>,
+    #[account(mut, constraint = proxy_token_account.owner == swap.signer)]
+    proxy_token_account: Box<Account<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        constraint = reserve.lendable_vault == *(*reserve_lendable_vault).as_ref().key,
+    )]
+    reserve: Box<Account<'info, Reserve>>,
+    reserve_lendable_vault: Box<Account<'info, TokenAccount>>,
+
+    #[account(constraint = *(*governance).as_ref().key == governance::ID)]
+    governance: Box<Account<'info, Governance>>,
+
+    trader: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = trader_coin_vault.owner == *trader.key,
+        constraint = trader_coin_vault.mint == swap.coin_mint,
+    )]
+    trader_coin_vault: Box<Account<'info, TokenAccount>>,
+
+    #[account(constraint = *authority.as_ref().key == authority::ID)]
+    authority: Signer<'info>,
+
+    spl_token_program: Program<'info, SplToken>,
+}
+
+impl<'info> SwapPositionForceClose<'info> {
+    // `remaining_accounts` may hold the trader index, see `TraderIndex::remove_from_accounts`
+    pub fn handle(
+        &mut self,
+        program_id: &Pubkey,
+        remaining_accounts: &[AccountInfo<'info>],
+    ) -> WowswapResultEmpty {
+        require!(self.swap.blocked, WowswapError::MarketNotBlocked);
+        self.governance.check_initialized()?;
+
+        let timestamp = UnixTimestamp::now()?;
+
+        let coin_amount = TokenAmount::new(self.proxy_token_account.amount);
+        self.burn_proxy_token(coin_amount)?;
+        self.return_trader_coins(coin_amount)?;
+
+        let current_debt = self.position.state.get_debt(
+            timestamp,
+            UnixTimestamp::ZERO,
+            self.governance.compound_terms(),
+        );
+        self.swap.state.total_loan = self
+            .swap
+            .state
+            .total_loan
+            .checked_sub(self.position.state.loan)
+            .expect("total_loan overflow");
+        self.position.state.loan = TokenAmount::ZERO;
+
+        if !current_debt.is_zero() {
+            self.reserve_update_state(timestamp, current_debt);
+            self.reserve.add_bad_debt(current_debt);
+        }
+
+        self.position.state.last_closed = timestamp;
+        TraderIndex::remove_from_accounts(
+            program_id,
+            remaining_accounts,
+            self.trader.key,
+            (*self.swap).as_ref().key,
+        )?;
+
+        emit!(PositionForceClosed {
+            swap: *(*self.swap).as_ref().key,
+            trader: *self.trader.key,
+            coin_returned: coin_amount,
+            debt_written_off: current_debt,
+        });
+
+        Ok(())
+    }
+
+    fn burn_proxy_token(&mut self, amount: TokenAmount) -> WowswapResultEmpty {
+        token::burn(
+            self.proxy_token_mint.to_account_info(),
+            self.proxy_token_account.to_account_info(),
+            self.swap_signer.clone(),
+            amount,
+            &[&self.swap.signer_seeds((*self.swap).as_ref().key)],
+        )?;
+        self.swap.sub_coin_outstanding(amount);
+        Ok(())
+    }
+
+    fn return_trader_coins(&self, amount: TokenAmount) -> WowswapResultEmpty {
+        token::transfer(
+            self.swap_coin_vault.to_account_info(),
+            self.trader_coin_vault.to_account_info(),
+            self.swap_signer.clone(),
+            amount,
+            &[&self.swap.signer_seeds((*self.swap).as_ref().key)],
+        )
+    }
+
+    fn reserve_update_state(&mut self, timestamp: UnixTimestamp, debt_change: TokenAmount) {
+        sim::repay(
+            &mut self.reserve,
+            &self.governance,
+            &mut self.position.state,
+            TokenAmount::new(self.reserve_lendable_vault.amount),
+            debt_change,
             TokenAmount::ZERO,
-            total_debt,
-            TokenAmount::ZERO,
-            TokenAmount::ZERO,
+            UnixTimestamp::ZERO,
+            timestamp,
+        );
+    }
+}
+
+#[event]
+pub struct SwapPositionStatus {
+    pub swap: Pubkey,
+    pub trader: Pubkey,
+    pub exists: bool,
+    pub debt: TokenAmount,
+}
+
+// Read-only, reports through the `SwapPositionStatus` event because return data is not
+// available in the current runtime. Both `position` and `proxy_token_account` may be missing.
+#[derive(Accounts)]
+pub struct SwapPositionExists<'info> {
+    swap: Box<Account<'info, Swap>>,
+    trader: AccountInfo<'info>,
+
+    position: AccountInfo<'info>,
+    #[account(
+        constraint = *proxy_token_account.key == spl_associated_token_account::get_associated_token_address(
+            trader.key,
+            &swap.proxy_token_mint,
+        ),
+    )]
+    proxy_token_account: AccountInfo<'info>,
+
+    #[account(constraint = *(*governance).as_ref().key == governance::ID)]
+    governance: Box<Account<'info, Governance>>,
+}
+
+impl<'info> SwapPositionExists<'info> {
+    pub fn handle(&self) -> WowswapResultEmpty {
+        let timestamp = UnixTimestamp::now()?;
+
+        let (address, _) = SwapPosition::find_address((*self.swap).as_ref().key, self.trader.key);
+        require!(*self.position.key == address, WowswapError::InvalidArgument);
+
+        let state = if self.position.data_is_empty() {
+            SwapPositionState::default()
+        } else {
+            require!(
+                *self.position.owner == crate::ID,
+                WowswapError::InvalidArgument
+            );
+            SwapPosition::try_deserialize(&mut &self.position.try_borrow_data()?[..])?.state
+        };
+
+        let proxy_amount = if self.proxy_token_account.data_is_empty() {
+            0
+        } else {
+            TokenAccount::try_deserialize(&mut &self.proxy_token_account.try_borrow_data()?[..])?
+                .amount
+        };
+
+        emit!(SwapPositionStatus {
+            swap: *(*self.swap).as_ref().key,
+            trader: *self.trader.key,
+            exists: proxy_amount > 0 || !state.amount.is_zero(),
+            debt: state.get_debt(
+                timestamp,
+                self.governance.position_grace_period(),
+                self.governance.compound_terms(),
+            ),
+        });
+
+        Ok(())
+    }
+}
+
+#[event]
+pub struct SwapPositionOpenQuote {
+    pub swap: Pubkey,
+    pub native_coin_qty: TokenAmount,
+    pub native_pc_qty_loan: TokenAmount,
+    pub native_pc_qty_trader: TokenAmount,
+}
+
+#[derive(Accounts)]
+pub struct SwapPositionQuote<'info> {
+    #[account(
+        constraint = swap.dex_program == *dex_program.as_ref().key,
+        constraint = swap.dex_market == *dex_market.key,
+    )]
+    swap: Box<Account<'info, Swap>>,
+
+    #[account(constraint = *(*governance).as_ref().key == governance::ID)]
+    governance: Box<Account<'info, Governance>>,
+
+    dex_program: Program<'info, Dex>,
+    dex_market: AccountInfo<'info>,
+}
+
+impl<'info> SwapPositionQuote<'info> {
+    pub fn handle(
+        &self,
+        limit_price: DexLimitPrice,
+        coin_qty: DexNonZeroTokenQty,
+        leverage_factor: Factor,
+        max_pc_with_fees: Option<TokenAmount>,
+    ) -> WowswapResultEmpty {
+        let lot_sizes = dex::load_market_lot_sizes(&self.dex_market, self.dex_program.key)?;
+        let breakdown = SwapPositionOpen::breakdown(
+            coin_qty,
+            leverage_factor,
+            limit_price,
+            max_pc_with_fees,
+            lot_sizes,
+            self.swap.max_leverage_factor(&self.governance),
+        )?;
+
+        emit!(SwapPositionOpenQuote {
+            swap: *(*self.swap).as_ref().key,
+            native_coin_qty: breakdown.native_coin_qty,
+            native_pc_qty_loan: breakdown.native_pc_qty_loan,
+            native_pc_qty_trader: breakdown.native_pc_qty_trader,
+        });
+
+        Ok(())
+    }
+}
+
+#[event]
+pub struct SwapPositionMaxCoinQty {
+    pub swap: Pubkey,
+    pub trader_pc: TokenAmount,
+    pub coin_qty: u64,
+}
+
+// Read-only, reports through the `SwapPositionMaxCoinQty` event the largest `coin_qty` for
+// `swap_position_open` that `trader_pc` covers
+#[derive(Accounts)]
+pub struct SwapPositionMaxQuote<'info> {
+    #[account(
+        constraint = swap.dex_program == *dex_program.as_ref().key,
+        constraint = swap.dex_market == *dex_market.key,
+    )]
+    swap: Box<Account<'info, Swap>>,
+
+    #[account(constraint = *(*governance).as_ref().key == governance::ID)]
+    governance: Box<Account<'info, Governance>>,
+
+    dex_program: Program<'info, Dex>,
+    dex_market: AccountInfo<'info>,
+}
+
+impl<'info> SwapPositionMaxQuote<'info> {
+    pub fn handle(
+        &self,
+        trader_pc: TokenAmount,
+        leverage_factor: Factor,
+        limit_price: DexLimitPrice,
+    ) -> WowswapResultEmpty {
+        let lot_sizes = dex::load_market_lot_sizes(&self.dex_market, self.dex_program.key)?;
+        let coin_qty = SwapPositionOpen::max_coin_qty(
+            trader_pc,
+            leverage_factor,
+            limit_price,
+            lot_sizes,
+            self.swap.max_leverage_factor(&self.governance),
+        )?;
+
+        emit!(SwapPositionMaxCoinQty {
+            swap: *(*self.swap).as_ref().key,
+            trader_pc,
+            coin_qty: coin_qty.into_inner().get(),
+        });
+
+        Ok(())
+    }
+}
+
+#[event]
+pub struct SwapPositionLiquidationDistance {
+    pub swap: Pubkey,
+    pub trader: Pubkey,
+    pub collateral_value: TokenAmount,
+    pub liquidation_threshold: TokenAmount,
+    // Negative when the position is already liquidatable
+    pub distance_bps: i64,
+}
+
+// Read-only, reports through the `SwapPositionLiquidationDistance` event. Collateral is valued at
+// the best bid of the market.
+#[derive(Accounts)]
+pub struct SwapPositionLiquidationDistanceView<'info> {
+    #[account(has_one = swap, has_one = proxy_token_account)]
+    position: Box<Account<'info, SwapPosition>>,
+
+    #[account(
+        constraint = swap.dex_program == *dex_program.as_ref().key,
+        constraint = swap.dex_market == *dex_market.key,
+    )]
+    swap: Box<Account<'info, Swap>>,
+    proxy_token_account: Box<Account<'info, TokenAccount>>,
+
+    #[account(constraint = *(*governance).as_ref().key == governance::ID)]
+    governance: Box<Account<'info, Governance>>,
+
+    dex_program: Program<'info, Dex>,
+    dex_market: AccountInfo<'info>,
+    dex_bids: AccountInfo<'info>,
+}
+
+impl<'info> SwapPositionLiquidationDistanceView<'info> {
+    pub fn handle(&self) -> WowswapResultEmpty {
+        let timestamp = UnixTimestamp::now()?;
+
+        let lot_sizes = dex::load_market_lot_sizes(&self.dex_market, self.dex_program.key)?;
+        let best_bid = dex::best_bid_price(&self.dex_market, &self.dex_bids, self.dex_program.key)?
+            .ok_or(WowswapError::InsufficientLiquidity)?;
+
+        let collateral_value = Swap::proxy_to_pc_value(
+            TokenAmount::new(self.proxy_token_account.amount),
+            best_bid,
+            lot_sizes,
+        );
+        require!(!collateral_value.is_zero(), WowswapError::InvalidArgument);
+
+        let debt = self.position.state.get_debt(
+            timestamp,
+            UnixTimestamp::ZERO,
+            self.governance.compound_terms(),
+        );
+        let liquidation_threshold = SwapPosition::liquidation_threshold(debt, &self.governance);
+
+        let to_bps = |diff: TokenAmount| {
+            (diff.into_inner() as u128)
+                .checked_mul(Factor::ONE.into_inner() as u128)
+                .map(|v| v / collateral_value.into_inner() as u128)
+                .map(|v| v.min(i64::MAX as u128) as i64)
+                .expect("distance_bps overflow")
+        };
+        let distance_bps = match collateral_value.checked_sub(liquidation_threshold) {
+            Some(diff) => to_bps(diff),
+            None => -to_bps(liquidation_threshold.safe_sub(collateral_value)),
+        };
+
+        emit!(SwapPositionLiquidationDistance {
+            swap: *(*self.swap).as_ref().key,
+            trader: self.position.trader,
+            collateral_value,
+            liquidation_threshold,
+            distance_bps,
+        });
+
+        Ok(())
+    }
+}
+
+#[event]
+pub struct SwapOpenInterest {
+    pub swap: Pubkey,
+    pub total_coin_outstanding: TokenAmount,
+    // `total_coin_outstanding` valued at the best bid
+    pub notional: TokenAmount,
+}
+
+// Read-only, reports the open interest of the market through the `SwapOpenInterest` event
+#[derive(Accounts)]
+pub struct SwapOpenInterestView<'info> {
+    #[account(
+        constraint = swap.dex_program == *dex_program.as_ref().key,
+        constraint = swap.dex_market == *dex_market.key,
+    )]
+    swap: Box<Account<'info, Swap>>,
+
+    dex_program: Program<'info, Dex>,
+    dex_market: AccountInfo<'info>,
+    dex_bids: AccountInfo<'info>,
+}
+
+impl<'info> SwapOpenInterestView<'info> {
+    pub fn handle(&self) -> WowswapResultEmpty {
+        let lot_sizes = dex::load_market_lot_sizes(&self.dex_market, self.dex_program.key)?;
+        let best_bid = dex::best_bid_price(&self.dex_market, &self.dex_bids, self.dex_program.key)?
+            .ok_or(WowswapError::InsufficientLiquidity)?;
+
+        let total_coin_outstanding = self.swap.total_coin_outstanding;
+        emit!(SwapOpenInterest {
+            swap: *(*self.swap).as_ref().key,
+            total_coin_outstanding,
+            notional: Swap::proxy_to_pc_value(total_coin_outstanding, best_bid, lot_sizes),
+        });
+
+        Ok(())
+    }
+}
+
+#[event]
+pub struct SwapPositionValue {
+    pub swap: Pubkey,
+    pub trader: Pubkey,
+    pub gross_value: TokenAmount,
+    pub debt: TokenAmount,
+    // Zero when the debt exceeds the gross value
+    pub net_value: TokenAmount,
+    // Current leverage, zero when the position is underwater
+    pub leverage: Factor,
+}
+
+// Read-only, reports through the `SwapPositionValue` event what the position is worth at the best
+// bid of the market, before and after repaying its debt
+#[derive(Accounts)]
+pub struct SwapPositionValueView<'info> {
+    #[account(has_one = swap, has_one = proxy_token_account)]
+    position: Box<Account<'info, SwapPosition>>,
+
+    #[account(
+        constraint = swap.dex_program == *dex_program.as_ref().key,
+        constraint = swap.dex_market == *dex_market.key,
+    )]
+    swap: Box<Account<'info, Swap>>,
+    proxy_token_account: Box<Account<'info, TokenAccount>>,
+
+    #[account(constraint = *(*governance).as_ref().key == governance::ID)]
+    governance: Box<Account<'info, Governance>>,
+
+    dex_program: Program<'info, Dex>,
+    dex_market: AccountInfo<'info>,
+    dex_bids: AccountInfo<'info>,
+}
+
+impl<'info> SwapPositionValueView<'info> {
+    pub fn handle(&self) -> WowswapResultEmpty {
+        let timestamp = UnixTimestamp::now()?;
+
+        let lot_sizes = dex::load_market_lot_sizes(&self.dex_market, self.dex_program.key)?;
+        let best_bid = dex::best_bid_price(&self.dex_market, &self.dex_bids, self.dex_program.key)?
+            .ok_or(WowswapError::InsufficientLiquidity)?;
+
+        let proxy_amount = TokenAmount::new(self.proxy_token_account.amount);
+        let gross_value = Swap::proxy_to_pc_value(proxy_amount, best_bid, lot_sizes);
+        let debt = self.position.state.get_debt(
+            timestamp,
+            UnixTimestamp::ZERO,
+            self.governance.compound_terms(),
+        );
+        let leverage = self
+            .position
+            .current_leverage(
+                proxy_amount,
+                best_bid,
+                lot_sizes,
+                timestamp,
+                &self.governance,
+            )
+            .unwrap_or(Factor::new(0));
+
+        emit!(SwapPositionValue {
+            swap: *(*self.swap).as_ref().key,
+            trader: self.position.trader,
+            gross_value,
+            debt,
+            net_value: gross_value.checked_sub(debt).unwrap_or(TokenAmount::ZERO),
+            leverage,
+        });
+
+        Ok(())
+    }
+}
+
+#[event]
+pub struct SwapPositionRepayAmount {
+    pub swap: Pubkey,
+    pub trader: Pubkey,
+    pub debt: TokenAmount,
+    pub repay_amount: TokenAmount,
+}
+
+// Read-only, reports through the `SwapPositionRepayAmount` event the pc a full repay of the
+// position takes right now
+#[derive(Accounts)]
+pub struct SwapPositionRepayAmountView<'info> {
+    position: Box<Account<'info, SwapPosition>>,
+
+    #[account(constraint = *(*governance).as_ref().key == governance::ID)]
+    governance: Box<Account<'info, Governance>>,
+}
+
+impl<'info> SwapPositionRepayAmountView<'info> {
+    pub fn handle(&self) -> WowswapResultEmpty {
+        let timestamp = UnixTimestamp::now()?;
+
+        emit!(SwapPositionRepayAmount {
+            swap: self.position.swap,
+            trader: self.position.trader,
+            debt: self.position.state.get_debt(
+                timestamp,
+                self.governance.position_grace_period(),
+                self.governance.compound_terms(),
+            ),
+            repay_amount: self.position.repay_amount(timestamp, &self.governance),
+        });
+
+        Ok(())
+    }
+}
+
+#[event]
+pub struct ReserveBorrowCapacity {
+    pub reserve: Pubkey,
+    pub swap: Pubkey,
+    pub amount: TokenAmount,
+    pub allowed: bool,
+    // `WowswapError` variant `swap_position_open` would fail with, zero when allowed
+    pub reason: u32,
+    // Largest loan `swap` could take right now, regardless of `amount`
+    pub headroom: TokenAmount,
+}
+
+// Read-only, reports through the `ReserveBorrowCapacity` event whether a loan of `amount` for
+// `swap` passes the same checks as `swap_position_open`, and how much it could borrow at most
+#[derive(Accounts)]
+pub struct ReserveCanBorrow<'info> {
+    #[account(has_one = reserve)]
+    swap: Box<Account<'info, Swap>>,
+
+    #[account(constraint = reserve.lendable_vault == *(*reserve_lendable_vault).as_ref().key)]
+    reserve: Box<Account<'info, Reserve>>,
+    reserve_lendable_vault: Box<Account<'info, TokenAccount>>,
+
+    #[account(constraint = *(*governance).as_ref().key == governance::ID)]
+    governance: Box<Account<'info, Governance>>,
+}
+
+impl<'info> ReserveCanBorrow<'info> {
+    pub fn handle(&self, amount: TokenAmount) -> WowswapResultEmpty {
+        let timestamp = UnixTimestamp::now()?;
+
+        let liquidity = TokenAmount::new(self.reserve_lendable_vault.amount);
+        let total_debt = self
+            .reserve
+            .debt
+            .get_total_debt(timestamp, self.governance.compound_terms());
+        let result =
+            Swap::check_loan_liquidity((*self.swap).as_ref().key, &self.reserve, liquidity, amount)
+                .and_then(|_| {
+                    let mut swap = **self.swap;
+                    swap.state.total_loan = swap
+                        .state
+                        .total_loan
+                        .checked_add(amount)
+                        .ok_or(WowswapError::BorrowLimitExceeded)?;
+                    swap.check_borrow_limit(&self.reserve, &self.governance, total_debt, liquidity)
+                });
+
+        let headroom = if self.reserve.is_borrower_allowed((*self.swap).as_ref().key) {
+            self.reserve.borrow_headroom(
+                &self.governance,
+                total_debt,
+                liquidity,
+                self.swap.state.total_loan,
+            )
+        } else {
+            TokenAmount::ZERO
+        };
+
+        emit!(ReserveBorrowCapacity {
+            reserve: *(*self.reserve).as_ref().key,
+            swap: *(*self.swap).as_ref().key,
+            amount,
+            allowed: result.is_ok(),
+            reason: result.err().map_or(0, |err| err as u32),
+            headroom,
+        });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPENED: UnixTimestamp = UnixTimestamp::new(1_600_000_000);
+    const TERMS: u8 = math::interest::DEFAULT_COMPOUND_TERMS;
+
+    fn after(seconds: u64) -> UnixTimestamp {
+        OPENED.checked_add(UnixTimestamp::new(seconds)).unwrap()
+    }
+
+    fn open_position(amount: u64) -> SwapPositionState {
+        SwapPositionState {
+            loan: TokenAmount::new(amount),
+            rate: Rate::from_apr_bps(10_000),
+            amount: TokenAmount::new(amount),
+            timestamp: OPENED,
+            opened_at: OPENED,
+            ..SwapPositionState::default()
+        }
+    }
+
+    #[test]
+    fn current_leverage_follows_price_and_interest() {
+        // 200 coins bought at 10 with 1_000 of debt, 2x
+        let position = SwapPosition {
+            state: open_position(1_000),
+            ..SwapPosition::default()
+        };
+        let lot_sizes = dex::MarketLotSizes {
+            coin: 1,
+            pc: 1,
+            pc_dust_threshold: 0,
+        };
+        let governance = Governance::default();
+        let leverage = |coins: u64, price: u64, timestamp: UnixTimestamp| {
+            position
+                .current_leverage(
+                    TokenAmount::new(coins),
+                    DexLimitPrice::new(price).unwrap(),
+                    lot_sizes,
+                    timestamp,
+                    &governance,
+                )
+                .map(Factor::into_inner)
+        };
+
+        assert_eq!(leverage(200, 10, OPENED), Some(20_000));
+        // Price up, 3_000 over 2_000 of equity
+        assert_eq!(leverage(200, 15, OPENED), Some(15_000));
+        // Price down, 1_200 over 200 of equity
+        assert_eq!(leverage(200, 6, OPENED), Some(60_000));
+
+        // A day of interest at 100% APR adds about 3 to the debt
+        let accrued = leverage(200, 10, after(86_400)).unwrap();
+        assert!(
+            accrued > 20_000 && accrued < 20_200,
+            "leverage: {}",
+            accrued
+        );
+
+        // No equity left
+        assert_eq!(leverage(200, 5, OPENED), None);
+        assert_eq!(leverage(200, 4, OPENED), None);
+        assert_eq!(leverage(0, 10, OPENED), None);
+    }
+
+    #[test]
+    fn repay_amount_covers_the_debt_and_buffer() {
+        const ONE: u128 = 1_000_000_000_000_000_000;
+        let governance = Governance {
+            position_grace_period: 60,
+            repay_buffer: 10 * ONE,
+            ..Governance::default()
+        };
+        let position = SwapPosition {
+            state: open_position(1_000_000_000),
+            ..SwapPosition::default()
+        };
+        let repay = |timestamp| position.repay_amount(timestamp, &governance).into_inner();
+
+        // Within the grace period the debt is the loan, the buffer is 0.1% of it
+        assert_eq!(repay(OPENED), 1_001_000_000);
+        assert_eq!(repay(after(60)), 1_001_000_000);
+
+        // Past it the interest since opening is due
+        for elapsed in [61, 3_600, 86_400, 365 * 86_400] {
+            let debt = position
+                .state
+                .get_debt(after(elapsed), UnixTimestamp::ZERO, TERMS)
+                .into_inner();
+            assert!(debt > 1_000_000_000);
+            assert_eq!(repay(after(elapsed)), debt + (debt + 500) / 1_000);
+        }
+        assert!(repay(after(61)) > repay(after(60)));
+    }
+
+    #[test]
+    fn debt_increase_is_zero_without_elapsed_time() {
+        let position = open_position(1_000_000_000);
+        assert_eq!(
+            position.calculate_debt_increase(OPENED, UnixTimestamp::ZERO, TERMS),
+            (position.amount, TokenAmount::ZERO)
+        );
+
+        // Dust amounts may round below the stored amount, the increase is clamped to zero
+        for amount in 1..100 {
+            let position = open_position(amount);
+            let (debt, increase) =
+                position.calculate_debt_increase(after(1), UnixTimestamp::ZERO, TERMS);
+            assert!(debt >= position.amount);
+            assert_eq!(debt.checked_sub(position.amount), Some(increase));
+        }
+
+        let (debt, increase) =
+            position.calculate_debt_increase(after(3_600), UnixTimestamp::ZERO, TERMS);
+        assert!(!increase.is_zero());
+        assert_eq!(debt.checked_sub(position.amount), Some(increase));
+    }
+
+    #[test]
+    fn sub_grace_close_pays_no_interest() {
+        let position = open_position(1_000_000_000);
+        let grace = UnixTimestamp::new(60);
+
+        assert_eq!(position.get_debt(after(60), grace, TERMS), position.amount);
+        assert!(position.get_debt(after(60), UnixTimestamp::ZERO, TERMS) > position.amount);
+    }
+
+    #[test]
+    fn just_over_grace_close_pays_full_interest() {
+        let position = open_position(1_000_000_000);
+        let grace = UnixTimestamp::new(60);
+
+        let debt = position.get_debt(after(61), grace, TERMS);
+        assert!(debt > position.amount);
+        assert_eq!(
+            debt,
+            position.get_debt(after(61), UnixTimestamp::ZERO, TERMS)
+        );
+    }
+
+    #[test]
+    fn grace_counts_from_opening() {
+        // A partial close moves the accrual timestamp, the grace window stays where it was
+        let mut position = open_position(1_000_000_000);
+        position.timestamp = after(50);
+        let grace = UnixTimestamp::new(60);
+
+        assert!(position.get_debt(after(100), grace, TERMS) > position.amount);
+    }
+
+    #[test]
+    fn loans_are_limited_to_the_vault_while_liquidity_is_deployed() {
+        let swap = Pubkey::new_unique();
+        let reserve = Reserve {
+            deployed_liquidity: TokenAmount::new(9_000),
+            ..Reserve::default()
+        };
+        let vault = TokenAmount::new(1_000);
+
+        assert!(
+            Swap::check_loan_liquidity(&swap, &reserve, vault, TokenAmount::new(1_000)).is_ok()
+        );
+        assert!(matches!(
+            Swap::check_loan_liquidity(&swap, &reserve, vault, TokenAmount::new(1_001)),
+            Err(WowswapError::InsufficientLiquidity)
+        ));
+    }
+
+    #[test]
+    fn borrow_limit_is_exclusive() {
+        const ONE: u128 = 1_000_000_000_000_000_000;
+        let governance = Governance {
+            pool_utilization_allowance: 5_000 * ONE,
+            ..Governance::default()
+        };
+        let reserve = Reserve::default();
+        let total_debt = TokenAmount::new(2_000);
+        let liquidity = TokenAmount::new(8_000);
+
+        // Half of the vault plus the outstanding debt
+        let limit = reserve.borrow_limit(&governance, total_debt, liquidity);
+        assert_eq!(limit, TokenAmount::new(5_000));
+
+        let check = |total_loan: u64| {
+            let swap = Swap {
+                state: SwapState {
+                    total_loan: TokenAmount::new(total_loan),
+                },
+                ..Swap::default()
+            };
+            swap.check_borrow_limit(&reserve, &governance, total_debt, liquidity)
+        };
+        assert!(check(4_999).is_ok());
+        assert!(matches!(
+            check(5_000),
+            Err(WowswapError::BorrowLimitExceeded)
+        ));
+        assert!(matches!(
+            check(5_001),
+            Err(WowswapError::BorrowLimitExceeded)
+        ));
+
+        // The largest loan the headroom allows still passes the check
+        let headroom =
+            reserve.borrow_headroom(&governance, total_debt, liquidity, TokenAmount::new(1_000));
+        assert_eq!(headroom, TokenAmount::new(3_999));
+        assert!(check(1_000 + 3_999).is_ok());
+    }
+
+    #[test]
+    fn liquidation_reward_stays_within_its_bounds() {
+        const ONE: u128 = 1_000_000_000_000_000_000;
+        let governance = Governance {
+            liquidation_reward: 500 * ONE,
+            min_liquidation_reward: 10 * ONE,
+            max_liquidation_reward: 1_000 * ONE,
+            min_debt_recovery: 9_000 * ONE,
+            ..Governance::default()
+        };
+        let reward = |amount: u64, debt: u64| {
+            SwapPosition::liquidation_reward(
+                &governance,
+                TokenAmount::new(amount),
+                TokenAmount::new(debt),
+            )
+            .into_inner()
+        };
+
+        // 5% of the output, raised to the floor and cut to the cap
+        assert_eq!(reward(10_000, 9_000), 500);
+        assert_eq!(reward(100, 50), 10);
+        assert_eq!(reward(100_000, 90_000), 1_000);
+
+        // The reserve recovers 90% of the debt first
+        assert_eq!(reward(9_200, 10_000), 200);
+        assert_eq!(reward(9_000, 10_000), 0);
+        assert_eq!(reward(5_000, 10_000), 0);
+
+        // Repaying the reserve in full first
+        let reserve_first = Governance {
+            liquidation_reserve_first: true,
+            ..governance
+        };
+        assert_eq!(
+            SwapPosition::liquidation_reward(
+                &reserve_first,
+                TokenAmount::new(10_000),
+                TokenAmount::new(9_800),
+            ),
+            TokenAmount::new(200)
+        );
+
+        for amount in (0..20_000).step_by(997) {
+            let debt = 10_000;
+            let paid = reward(amount, debt);
+            assert!(paid <= 1_000);
+            assert!(amount - paid >= std::cmp::min(amount, 9_000));
+        }
+    }
+
+    #[test]
+    fn open_breakdown_rounds_the_loan_down_to_a_lot() {
+        let lot_sizes = dex::MarketLotSizes {
+            coin: 100,
+            pc: 10,
+            pc_dust_threshold: 0,
+        };
+        // 2.5x on 3 lots borrows 4.5 lots, only 4 of them are bought with the loan
+        let breakdown = SwapPositionOpen::breakdown(
+            DexNonZeroTokenQty::from_token_amount(TokenAmount::new(3)).unwrap(),
+            Factor::new(25_000),
+            DexLimitPrice::new(50).unwrap(),
+            None,
+            lot_sizes,
+            Factor::new(50_000),
+        )
+        .unwrap();
+        assert_eq!(breakdown.coin_qty.into_inner().get(), 7);
+        assert_eq!(breakdown.native_coin_qty, TokenAmount::new(700));
+        assert_eq!(breakdown.native_pc_qty_loan, TokenAmount::new(4 * 500));
+        assert_eq!(breakdown.native_pc_qty_trader, TokenAmount::new(3 * 500));
+        assert_eq!(
+            breakdown.native_pc_qty_including_fees.as_token_amount(),
+            TokenAmount::new(7 * 500)
+        );
+    }
+
+    #[test]
+    fn open_breakdown_rejects_overflowing_quantities() {
+        let breakdown = |coin_qty: u64, leverage_factor: u64, coin_lot_size: u64| {
+            SwapPositionOpen::breakdown(
+                DexNonZeroTokenQty::from_token_amount(TokenAmount::new(coin_qty)).unwrap(),
+                Factor::new(leverage_factor),
+                DexLimitPrice::new(1).unwrap(),
+                None,
+                dex::MarketLotSizes {
+                    coin: coin_lot_size,
+                    pc: 1,
+                    pc_dust_threshold: 0,
+                },
+                Factor::new(50_000),
+            )
+        };
+
+        // The borrowed lots alone exceed u64
+        assert!(breakdown(u64::MAX, 50_000, 1).is_err());
+        // The borrowed lots fit, adding them to `coin_qty` doesn't
+        assert!(breakdown(u64::MAX, 20_000, 1).is_err());
+        assert!(breakdown(u64::MAX / 2 + 1, 20_000, 1).is_err());
+        // The total fits in lots but not in native units
+        assert!(breakdown(u64::MAX / 4, 20_000, 100).is_err());
+
+        // Largest quantity that doubles without overflowing
+        let breakdown = breakdown(u64::MAX / 2, 20_000, 1).unwrap();
+        assert_eq!(breakdown.coin_qty.into_inner().get(), u64::MAX - 1);
+    }
+
+    #[test]
+    fn liquidation_reward_shares_add_up() {
+        const ONE: u128 = 1_000_000_000_000_000_000;
+        let governance = Governance {
+            liquidation_treasury_share: 2_000 * ONE,
+            liquidation_insurance_share: 1_000 * ONE,
+            ..Governance::default()
+        };
+        let split = |reward: u64, expired: bool| {
+            let (liquidator, treasury, insurance) = SwapPosition::split_liquidation_reward(
+                &governance,
+                TokenAmount::new(reward),
+                expired,
+            );
+            (
+                liquidator.into_inner(),
+                treasury.into_inner(),
+                insurance.into_inner(),
+            )
+        };
+
+        assert_eq!(split(1_000, false), (700, 200, 100));
+        // Protocol shares round down, the liquidator gets the remainder
+        assert_eq!(split(999, false), (701, 199, 99));
+        // A keeper close fee goes to the keeper in full
+        assert_eq!(split(1_000, true), (1_000, 0, 0));
+
+        for reward in (0..100_000).step_by(997) {
+            let (liquidator, treasury, insurance) = split(reward, false);
+            assert_eq!(liquidator + treasury + insurance, reward);
+        }
+    }
+
+    #[test]
+    fn underwater_liquidations_pay_no_reward() {
+        const ONE: u128 = 1_000_000_000_000_000_000;
+        let governance = Governance {
+            liquidation_reward: 500 * ONE,
+            min_liquidation_reward: 10 * ONE,
+            min_debt_recovery: 9_000 * ONE,
+            ..Governance::default()
+        };
+        let reward = |governance: &Governance, amount: u64, debt: u64| {
+            SwapPosition::liquidation_reward(
+                governance,
+                TokenAmount::new(amount),
+                TokenAmount::new(debt),
+            )
+            .into_inner()
+        };
+
+        // Deeply underwater, the floor isn't paid out of the reserve's recovery
+        assert_eq!(reward(&governance, 0, 10_000), 0);
+        assert_eq!(reward(&governance, 1, 10_000), 0);
+        assert_eq!(reward(&governance, 100, u64::MAX), 0);
+
+        // Right at the minimum recovery of 9_000, then one above it
+        assert_eq!(reward(&governance, 8_999, 10_000), 0);
+        assert_eq!(reward(&governance, 9_000, 10_000), 0);
+        assert_eq!(reward(&governance, 9_001, 10_000), 1);
+
+        let reserve_first = Governance {
+            liquidation_reserve_first: true,
+            ..governance
+        };
+        assert_eq!(reward(&reserve_first, 9_999, 10_000), 0);
+        assert_eq!(reward(&reserve_first, 10_000, 10_000), 0);
+        assert_eq!(reward(&reserve_first, 10_001, 10_000), 1);
+    }
+
+    #[test]
+    fn positions_expire_after_their_term() {
+        let mut state = open_position(1_000);
+        state.max_duration = UnixTimestamp::new(3_600);
+        assert!(!state.is_expired(OPENED));
+        assert!(!state.is_expired(after(3_600)));
+        assert!(state.is_expired(after(3_601)));
+
+        // No term
+        state.max_duration = UnixTimestamp::ZERO;
+        assert!(!state.is_expired(after(u32::MAX as u64)));
+    }
+
+    #[test]
+    fn keeper_close_fee_comes_out_of_the_trader_share() {
+        const ONE: u128 = 1_000_000_000_000_000_000;
+        let governance = Governance {
+            keeper_close_fee: 100 * ONE,
+            ..Governance::default()
+        };
+        let fee = |amount: u64, debt: u64| {
+            SwapPosition::keeper_close_fee(
+                &governance,
+                TokenAmount::new(amount),
+                TokenAmount::new(debt),
+            )
+            .into_inner()
+        };
+
+        // 1% of the output
+        assert_eq!(fee(10_000, 5_000), 100);
+        // Cut to what is left once the debt is repaid
+        assert_eq!(fee(10_000, 9_950), 50);
+        assert_eq!(fee(10_000, 10_000), 0);
+        // Underwater
+        assert_eq!(fee(10_000, 12_000), 0);
+    }
+
+    #[test]
+    fn trader_credential_is_only_required_by_gated_markets() {
+        let swap_key = Pubkey::new_unique();
+        let trader = Pubkey::new_unique();
+        let gate = Pubkey::new_unique();
+        let (credential_key, _) =
+            Pubkey::find_program_address(&[swap_key.as_ref(), trader.as_ref()], &gate);
+        let other_owner = Pubkey::new_unique();
+
+        let mut lamports = 0;
+        let mut data = [1u8];
+        let credential = AccountInfo::new(
+            &credential_key,
+            false,
+            false,
+            &mut lamports,
+            &mut data,
+            &gate,
+            false,
+            0,
+        );
+        let mut forged_lamports = 0;
+        let mut forged_data = [1u8];
+        let forged = AccountInfo::new(
+            &credential_key,
+            false,
+            false,
+            &mut forged_lamports,
+            &mut forged_data,
+            &other_owner,
+            false,
+            0,
+        );
+        let remaining = [credential.clone()];
+
+        // Permissionless, no extra account is taken
+        let open = Swap::default();
+        let mut accounts = &remaining[..];
+        assert!(open.take_trader_credential(&mut accounts).is_none());
+        assert_eq!(accounts.len(), 1);
+        assert!(open
+            .check_trader_permitted(&swap_key, &trader, None)
+            .is_ok());
+
+        let gated = Swap {
+            trader_gate: gate,
+            ..Swap::default()
+        };
+        let mut accounts = &remaining[..];
+        let taken = gated.take_trader_credential(&mut accounts);
+        assert!(accounts.is_empty());
+        assert!(gated
+            .check_trader_permitted(&swap_key, &trader, taken)
+            .is_ok());
+
+        // Missing, issued by another program or for another trader
+        assert!(gated
+            .check_trader_permitted(&swap_key, &trader, None)
+            .is_err());
+        assert!(gated
+            .check_trader_permitted(&swap_key, &trader, Some(&forged))
+            .is_err());
+        assert!(gated
+            .check_trader_permitted(&swap_key, &Pubkey::new_unique(), Some(&credential))
+            .is_err());
+    }
+
+    #[test]
+    fn sub_lot_positions_are_not_sold() {
+        let lots = |amount| {
+            SwapPosition::sellable_coin_lots(TokenAmount::new(amount), 1_000)
+                .map(|lots| lots.into_inner().get())
+        };
+        assert_eq!(lots(0), None);
+        assert_eq!(lots(999), None);
+        assert_eq!(lots(1_000), Some(1));
+        assert_eq!(lots(2_999), Some(2));
+    }
+
+    #[test]
+    fn roll_moves_only_the_difference() {
+        let net = |held: u64, amount: u64| {
+            let (transfer_in, left) =
+                SwapPositionRollFunds::net(TokenAmount::new(held), TokenAmount::new(amount));
+            // Whatever is held either funds the re-open or is paid back
+            assert_eq!(transfer_in.into_inner() + held, amount + left.into_inner());
+            (transfer_in.into_inner(), left.into_inner())
+        };
+
+        // A larger new loan only borrows the increase, a smaller one repays the rest
+        assert_eq!(net(1_000, 1_500), (500, 0));
+        assert_eq!(net(1_000, 400), (0, 600));
+        assert_eq!(net(1_000, 1_000), (0, 0));
+        // Nothing held outside of a roll
+        assert_eq!(net(0, 700), (700, 0));
+        assert_eq!(net(700, 0), (0, 700));
+    }
+
+    #[test]
+    fn signer_nonce_must_be_canonical() {
+        let swap = Pubkey::new_unique();
+        let (signer, canonical) = Swap::find_signer_address(&swap);
+        Swap::check_signer_nonce(&swap, canonical).unwrap();
+
+        // A lower bump that is off the curve derives another signer for the same swap
+        let (other, other_signer) = (0..canonical)
+            .rev()
+            .find_map(|nonce| {
+                Pubkey::create_program_address(&[swap.as_ref(), &[nonce]], &crate::ID)
+                    .ok()
+                    .map(|signer| (nonce, signer))
+            })
+            .unwrap();
+        assert_ne!(other_signer, signer);
+        assert!(Swap::check_signer_nonce(&swap, other).is_err());
+    }
+
+    #[test]
+    fn position_nonce_must_be_canonical() {
+        let swap = Pubkey::new_unique();
+        let trader = Pubkey::new_unique();
+        let (address, canonical) = SwapPosition::find_address(&swap, &trader);
+        SwapPosition::check_nonce(&swap, &trader, canonical).unwrap();
+
+        // A lower bump that is off the curve derives a second valid address for the same pair
+        let (other, other_address) = (0..canonical)
+            .rev()
+            .find_map(|nonce| {
+                Pubkey::create_program_address(
+                    &[swap.as_ref(), trader.as_ref(), &[nonce]],
+                    &crate::ID,
+                )
+                .ok()
+                .map(|address| (nonce, address))
+            })
+            .unwrap();
+        assert_ne!(other_address, address);
+        assert!(SwapPosition::check_nonce(&swap, &trader, other).is_err());
+    }
+
+    #[test]
+    fn client_nonces_are_strictly_increasing() {
+        let mut position = SwapPosition::default();
+        position.record_client_nonce(5).unwrap();
+        // Resubmitting the same open
+        assert!(position.record_client_nonce(5).is_err());
+        // Replaying an older one
+        assert!(position.record_client_nonce(4).is_err());
+        assert_eq!(position.last_client_nonce, 5);
+
+        position.record_client_nonce(7).unwrap();
+        assert_eq!(position.last_client_nonce, 7);
+
+        // Opens without a nonce are never rejected and don't reset it
+        position.record_client_nonce(0).unwrap();
+        position.record_client_nonce(0).unwrap();
+        assert_eq!(position.last_client_nonce, 7);
+    }
+
+    #[test]
+    fn proxy_supply_is_capped() {
+        let mut swap = Swap {
+            max_proxy_supply: TokenAmount::new(1_000),
+            ..Swap::default()
+        };
+        let supply = TokenAmount::new(600);
+        swap.check_proxy_supply(supply, TokenAmount::new(400))
+            .unwrap();
+        assert!(swap
+            .check_proxy_supply(supply, TokenAmount::new(401))
+            .is_err());
+        assert!(swap
+            .check_proxy_supply(TokenAmount::new(1), TokenAmount::new(u64::MAX))
+            .is_err());
+
+        // Zero means no cap
+        swap.max_proxy_supply = TokenAmount::ZERO;
+        swap.check_proxy_supply(supply, TokenAmount::new(u64::MAX - 600))
+            .unwrap();
+    }
+
+    #[test]
+    fn trader_index_is_bounded() {
+        let mut index = TraderIndex::default();
+        let swaps: Vec<Pubkey> = (0..TraderIndex::MAX_SWAPS)
+            .map(|_| Pubkey::new_unique())
+            .collect();
+        for swap in &swaps {
+            index.insert(swap).unwrap();
+        }
+        // Already listed swaps don't take another slot
+        index.insert(&swaps[0]).unwrap();
+
+        let extra = Pubkey::new_unique();
+        assert!(index.insert(&extra).is_err());
+
+        // Closing a position frees its slot
+        index.remove(&swaps[3]);
+        index.insert(&extra).unwrap();
+        assert_eq!(index.swaps[3], extra);
+        assert!(!index.swaps.contains(&swaps[3]));
+    }
+
+    #[test]
+    fn market_fees_accrue_across_closes() {
+        let governance = Governance {
+            // 25% of the close fee
+            market_fee_share: 2_500 * 1_000_000_000_000_000_000,
+            ..Governance::default()
+        };
+        let mut swap = Swap::default();
+
+        // No fee vault, the reserve keeps the whole fee
+        assert_eq!(
+            swap.market_fee(TokenAmount::new(1_000), &governance),
+            TokenAmount::ZERO
+        );
+
+        swap.fee_vault = Pubkey::new_unique();
+        for close_fee in [1_000, 2_000, 3] {
+            let market_fee = swap.market_fee(TokenAmount::new(close_fee), &governance);
+            swap.accrue_fees(market_fee);
+        }
+
+        // 250 + 500 + 0, the remainder of the last fee goes to the reserve
+        assert_eq!(swap.fees_accrued, TokenAmount::new(750));
+    }
+
+    #[test]
+    fn reclaim_waits_for_the_cooldown() {
+        let cooldown = UnixTimestamp::new(3_600);
+        let closed = SwapPositionState {
+            last_closed: after(100),
+            ..SwapPositionState::default()
+        };
+
+        // Close and reclaim in the same transaction, or right after a liquidation
+        assert!(closed.is_cooling_down(after(100), cooldown));
+        assert!(closed.is_cooling_down(after(3_699), cooldown));
+        assert!(!closed.is_cooling_down(after(3_700), cooldown));
+
+        // Never closed, or no cooldown configured
+        assert!(!SwapPositionState::default().is_cooling_down(after(0), cooldown));
+        assert!(!closed.is_cooling_down(after(100), UnixTimestamp::ZERO));
+    }
+
+    #[test]
+    fn dust_is_written_off_only_once_the_coins_are_sold() {
+        let threshold = TokenAmount::new(1_000);
+
+        // All coins sold, a remainder below the threshold is written off
+        assert_eq!(
+            SwapPosition::writable_dust(TokenAmount::new(999), TokenAmount::ZERO, threshold),
+            TokenAmount::new(999)
+        );
+        assert_eq!(
+            SwapPosition::writable_dust(TokenAmount::new(1_000), TokenAmount::ZERO, threshold),
+            TokenAmount::ZERO
+        );
+
+        // Partial close, the trader still holds coins so the debt stays on the position
+        assert_eq!(
+            SwapPosition::writable_dust(TokenAmount::new(999), TokenAmount::new(1), threshold),
+            TokenAmount::ZERO
+        );
+
+        // Disabled
+        assert_eq!(
+            SwapPosition::writable_dust(
+                TokenAmount::new(999),
+                TokenAmount::ZERO,
+                TokenAmount::ZERO
+            ),
+            TokenAmount::ZERO
         );
     }
 }
@@ -1,12 +1,15 @@
 use anchor_lang::{
     prelude::*,
-    solana_program::{entrypoint::ProgramResult, program::invoke_signed, program_pack::Pack},
+    solana_program::{program::invoke_signed, program_pack::Pack},
 };
 use spl_token::{instruction, state};
 pub use spl_token::{state::AccountState as TokenAccountState, ID};
 use std::{io::Write, ops::Deref};
 
-use super::math::TokenAmount;
+use super::{
+    error::{WowswapError, WowswapResultEmpty},
+    math::TokenAmount,
+};
 
 #[derive(Debug, Clone, Copy)]
 pub struct SplToken;
@@ -27,6 +30,25 @@ impl anchor_lang::Id for SplToken {
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct AssociatedToken;
+
+impl anchor_lang::AccountDeserialize for AssociatedToken {
+    fn try_deserialize(buf: &mut &[u8]) -> Result<Self, ProgramError> {
+        Self::try_deserialize_unchecked(buf)
+    }
+
+    fn try_deserialize_unchecked(_buf: &mut &[u8]) -> Result<Self, ProgramError> {
+        Ok(Self)
+    }
+}
+
+impl anchor_lang::Id for AssociatedToken {
+    fn id() -> Pubkey {
+        spl_associated_token_account::ID
+    }
+}
+
 #[derive(Clone)]
 pub struct TokenMint(state::Mint);
 
@@ -99,7 +121,7 @@ pub fn mint_to<'info>(
     authority: AccountInfo<'info>,
     amount: TokenAmount,
     seeds: &[&[&[u8]]],
-) -> ProgramResult {
+) -> WowswapResultEmpty {
     invoke_signed(
         &instruction::mint_to(
             &ID,
@@ -112,6 +134,8 @@ pub fn mint_to<'info>(
         &[account, mint, authority],
         seeds,
     )
+    .map_err(|err| token_error("mint_to", err))?;
+    Ok(())
 }
 
 pub fn transfer<'info>(
@@ -120,7 +144,7 @@ pub fn transfer<'info>(
     authority: AccountInfo<'info>,
     amount: TokenAmount,
     seeds: &[&[&[u8]]],
-) -> ProgramResult {
+) -> WowswapResultEmpty {
     invoke_signed(
         &instruction::transfer(
             &ID,
@@ -133,6 +157,8 @@ pub fn transfer<'info>(
         &[from, to, authority],
         seeds,
     )
+    .map_err(|err| token_error("transfer", err))?;
+    Ok(())
 }
 
 pub fn burn<'info>(
@@ -141,7 +167,7 @@ pub fn burn<'info>(
     authority: AccountInfo<'info>,
     amount: TokenAmount,
     seeds: &[&[&[u8]]],
-) -> ProgramResult {
+) -> WowswapResultEmpty {
     invoke_signed(
         &instruction::burn(
             &ID,
@@ -154,6 +180,85 @@ pub fn burn<'info>(
         &[account, mint, authority],
         seeds,
     )
+    .map_err(|err| token_error("burn", err))?;
+    Ok(())
+}
+
+pub fn set_mint_authority<'info>(
+    mint: AccountInfo<'info>,
+    authority: AccountInfo<'info>,
+    new_authority: &Pubkey,
+    seeds: &[&[&[u8]]],
+) -> WowswapResultEmpty {
+    invoke_signed(
+        &instruction::set_authority(
+            &ID,
+            mint.key,
+            Some(new_authority),
+            instruction::AuthorityType::MintTokens,
+            authority.key,
+            &[],
+        )?,
+        &[mint, authority],
+        seeds,
+    )
+    .map_err(|err| token_error("set_mint_authority", err))?;
+    Ok(())
+}
+
+pub fn set_account_owner<'info>(
+    account: AccountInfo<'info>,
+    owner: AccountInfo<'info>,
+    new_owner: &Pubkey,
+    seeds: &[&[&[u8]]],
+) -> WowswapResultEmpty {
+    invoke_signed(
+        &instruction::set_authority(
+            &ID,
+            account.key,
+            Some(new_owner),
+            instruction::AuthorityType::AccountOwner,
+            owner.key,
+            &[],
+        )?,
+        &[account, owner],
+        seeds,
+    )
+    .map_err(|err| token_error("set_account_owner", err))?;
+    Ok(())
+}
+
+pub fn create_associated_account<'info>(
+    payer: AccountInfo<'info>,
+    account: AccountInfo<'info>,
+    wallet: AccountInfo<'info>,
+    mint: AccountInfo<'info>,
+    system_program: AccountInfo<'info>,
+    spl_token_program: AccountInfo<'info>,
+    rent: AccountInfo<'info>,
+) -> WowswapResultEmpty {
+    invoke_signed(
+        &spl_associated_token_account::create_associated_token_account(
+            payer.key, wallet.key, mint.key,
+        ),
+        &[
+            payer,
+            account,
+            wallet,
+            mint,
+            system_program,
+            spl_token_program,
+            rent,
+        ],
+        &[],
+    )
+    .map_err(|err| token_error("create_associated_account", err))?;
+    Ok(())
+}
+
+fn token_error(operation: &str, err: ProgramError) -> WowswapError {
+    msg!("SPL token {} failed: {:?}", operation, err);
+    WowswapError::TokenOperationFailed
 }
 
 pub fn check_associated_address<'info>(